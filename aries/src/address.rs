@@ -0,0 +1,209 @@
+//! The host and port of a target, as written in a `CONNECT` request or a
+//! SOCKS command.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::Error;
+
+/// The longest domain name, RFC 1035 section 2.3.4.
+const MAX_DOMAIN_LENGTH: usize = 253;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Host {
+    Ip(IpAddr),
+    /// An ASCII domain name, lowercased and with internationalized labels
+    /// in punycode.
+    Domain(String),
+}
+
+impl Host {
+    /// Parses an IP address, with or without brackets for IPv6, or a domain
+    /// name, which is normalized with IDNA.
+    pub fn parse(host: &str) -> Result<Self, Error> {
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|v| v.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = literal.parse() {
+            return Ok(Host::Ip(ip));
+        }
+
+        let domain =
+            idna::domain_to_ascii(host).map_err(|_| Error::InvalidAddress("invalid domain"))?;
+        if domain.is_empty() || domain.len() > MAX_DOMAIN_LENGTH {
+            return Err(Error::InvalidAddress("invalid domain length"));
+        }
+        // IDNA lets through characters that are not valid in a host.
+        if domain.contains([':', '/', '?', '#', '@', '[', ']', ' ']) {
+            return Err(Error::InvalidAddress("invalid domain"));
+        }
+        Ok(Host::Domain(domain))
+    }
+}
+
+/// Formats an IPv6 address without brackets.
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(ip) => ip.fmt(f),
+            Host::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
+
+/// A host and port. It parses from and formats as `host:port`, with an IPv6
+/// host in brackets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    host: Host,
+    port: u16,
+}
+
+impl Address {
+    /// Parses `host` like [`Host::parse`].
+    pub fn new(host: &str, port: u16) -> Result<Self, Error> {
+        Ok(Self {
+            host: Host::parse(host)?,
+            port,
+        })
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the socket address of an IP host.
+    pub fn as_socket_addr(&self) -> Option<SocketAddr> {
+        match self.host {
+            Host::Ip(ip) => Some(SocketAddr::new(ip, self.port)),
+            Host::Domain(_) => None,
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or(Error::InvalidAddress("missing port"))?;
+        // An IPv6 address needs brackets to tell it from the port.
+        if host.contains(':') && !host.starts_with('[') {
+            return Err(Error::InvalidAddress("ipv6 address without brackets"));
+        }
+        if host.starts_with('[') && !matches!(Host::parse(host)?, Host::Ip(IpAddr::V6(_))) {
+            return Err(Error::InvalidAddress("invalid ipv6 address"));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| Error::InvalidAddress("invalid port"))?;
+        Address::new(host, port)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            host => write!(f, "{}:{}", host, self.port),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            host: Host::Ip(addr.ip()),
+            port: addr.port(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Address {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Address, Host};
+
+    #[test]
+    fn test_parse() {
+        let cases = [
+            ("example.com:443", "example.com:443"),
+            ("EXAMPLE.com:80", "example.com:80"),
+            ("bücher.example:443", "xn--bcher-kva.example:443"),
+            ("127.0.0.1:1080", "127.0.0.1:1080"),
+            ("[::1]:1080", "[::1]:1080"),
+            ("[2001:DB8::1]:443", "[2001:db8::1]:443"),
+        ];
+        for (input, expected) in cases {
+            let address: Address = input.parse().unwrap();
+            assert_eq!(address.to_string(), expected);
+            assert_eq!(address.to_string().parse::<Address>().unwrap(), address);
+        }
+
+        let address: Address = "[::1]:80".parse().unwrap();
+        assert_eq!(
+            address.host(),
+            &Host::Ip(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]))
+        );
+        assert_eq!(address.as_socket_addr(), Some("[::1]:80".parse().unwrap()));
+        assert_eq!(
+            Address::new("::1", 80).unwrap(),
+            Address::new("[::1]", 80).unwrap()
+        );
+
+        for input in [
+            "example.com",
+            "::1:80",
+            "[example.com]:80",
+            "example.com:65536",
+            ":80",
+            "exa mple.com:80",
+            "a/b:80",
+        ] {
+            assert!(input.parse::<Address>().is_err(), "{}", input);
+        }
+        let long = format!("{}.com:80", "a.".repeat(127));
+        assert!(long.parse::<Address>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let address: Address = "[::1]:1080".parse().unwrap();
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, "\"[::1]:1080\"");
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), address);
+        assert!(serde_json::from_str::<Address>("\"::1:1080\"").is_err());
+    }
+}
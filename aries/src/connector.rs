@@ -0,0 +1,117 @@
+//! A common interface over the proxy clients of the workspace, for callers
+//! that open tunnels without caring which protocol the proxy speaks.
+
+use std::{error, fmt, io};
+
+use async_trait::async_trait;
+use log::trace;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::address::Address;
+
+/// The protocol a [`ProxyConnector`] speaks to the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Socks4,
+    Socks5,
+    HttpConnect,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Socks4 => "socks4",
+            Protocol::Socks5 => "socks5",
+            Protocol::HttpConnect => "http connect",
+        })
+    }
+}
+
+/// A tunnel opened by a [`ProxyConnector`].
+pub trait ProxyStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> ProxyStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub type BoxStream = Box<dyn ProxyStream>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("proxy address required")]
+    MissingProxy,
+
+    #[error("connect to proxy: {0}")]
+    Connect(#[source] io::Error),
+
+    /// The proxy refused the tunnel or the exchange failed, the error of the
+    /// protocol client is kept as the source.
+    #[error("{protocol} handshake: {source}")]
+    Handshake {
+        protocol: Protocol,
+        #[source]
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+}
+
+impl ConnectError {
+    pub fn handshake<E>(protocol: Protocol, source: E) -> Self
+    where
+        E: error::Error + Send + Sync + 'static,
+    {
+        ConnectError::Handshake {
+            protocol,
+            source: Box::new(source),
+        }
+    }
+
+    /// Returns the error of the protocol client, such as a `libra::Error`
+    /// carrying the reply code of a SOCKS server.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: error::Error + 'static,
+    {
+        match self {
+            ConnectError::Handshake { source, .. } => source.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Opens a connection to a proxy and a tunnel through it to `target`.
+#[async_trait]
+pub trait ProxyConnector: Send + Sync {
+    fn protocol(&self) -> Protocol;
+
+    async fn connect(&self, target: &Address) -> Result<BoxStream, ConnectError>;
+}
+
+/// Opens the TCP connection to the proxy of a [`ProxyConnector`].
+pub async fn dial(proxy: Option<&Address>) -> Result<TcpStream, ConnectError> {
+    let proxy = proxy.ok_or(ConnectError::MissingProxy)?;
+    trace!("connect to proxy {}", proxy);
+    TcpStream::connect((proxy.host().to_string(), proxy.port()))
+        .await
+        .map_err(ConnectError::Connect)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{ConnectError, Protocol};
+
+    #[test]
+    fn test_error() {
+        let e = ConnectError::handshake(Protocol::Socks5, io::Error::other("refused"));
+        assert_eq!(e.to_string(), "socks5 handshake: refused");
+        assert_eq!(
+            e.downcast_ref::<io::Error>().unwrap().to_string(),
+            "refused"
+        );
+        assert!(ConnectError::MissingProxy
+            .downcast_ref::<io::Error>()
+            .is_none());
+    }
+}
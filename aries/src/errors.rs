@@ -0,0 +1,22 @@
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid address: {0}")]
+    InvalidAddress(&'static str),
+
+    #[error("invalid proxy url: {0}")]
+    InvalidProxyUrl(&'static str),
+
+    #[error("missing proxy protocol header")]
+    MissingProxyHeader,
+
+    #[error("malformed proxy protocol header")]
+    MalformedProxyHeader,
+
+    #[error("tls error: {0}")]
+    Tls(&'static str),
+}
@@ -0,0 +1,13 @@
+//! Building blocks shared by the proxies of the workspace.
+pub mod address;
+pub mod connector;
+pub mod proxy_protocol;
+pub mod proxy_url;
+#[cfg(feature = "rustls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+pub mod upstream;
+
+mod errors;
+pub use errors::Error;
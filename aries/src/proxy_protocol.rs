@@ -0,0 +1,437 @@
+//! The PROXY protocol of HAProxy, versions 1 and 2. A load balancer sends
+//! the header before any data so that the server behind it learns the
+//! addresses of the original connection, see
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, BytesMut};
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::Error;
+
+/// The signature starting a version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header, including the CRLF.
+const MAX_V1_LENGTH: usize = 107;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_INET: u8 = 0x10;
+const V2_FAMILY_INET6: u8 = 0x20;
+/// The length of the IPv4 and IPv6 address blocks.
+const V2_INET_LENGTH: usize = 12;
+const V2_INET6_LENGTH: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// The human-readable version.
+    V1,
+    /// The binary version.
+    V2,
+}
+
+/// The addresses of the connection a load balancer accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client.
+    pub source: SocketAddr,
+    /// The address the client connected to.
+    pub destination: SocketAddr,
+}
+
+impl ProxyHeader {
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            source,
+            destination,
+        }
+    }
+
+    /// Encodes the header for a TCP connection. Both addresses are sent as
+    /// IPv6 if only one of them is.
+    pub fn encode(&self, version: Version, buf: &mut BytesMut) {
+        let (source, destination) = match (self.source, self.destination) {
+            (SocketAddr::V4(_), SocketAddr::V6(_)) | (SocketAddr::V6(_), SocketAddr::V4(_)) => {
+                (to_ipv6(self.source), to_ipv6(self.destination))
+            }
+            _ => (self.source, self.destination),
+        };
+
+        match version {
+            Version::V1 => {
+                let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                let line = format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    protocol,
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                );
+                buf.put_slice(line.as_bytes());
+            }
+            Version::V2 => {
+                buf.put_slice(&SIGNATURE);
+                buf.put_u8(V2_VERSION | V2_COMMAND_PROXY);
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        buf.put_u8(V2_FAMILY_INET | 1);
+                        buf.put_u16(V2_INET_LENGTH as u16);
+                        buf.put_slice(&src.octets());
+                        buf.put_slice(&dst.octets());
+                    }
+                    (src, dst) => {
+                        buf.put_u8(V2_FAMILY_INET6 | 1);
+                        buf.put_u16(V2_INET6_LENGTH as u16);
+                        buf.put_slice(&to_ipv6_addr(src).octets());
+                        buf.put_slice(&to_ipv6_addr(dst).octets());
+                    }
+                }
+                buf.put_u16(source.port());
+                buf.put_u16(destination.port());
+            }
+        }
+    }
+
+    /// Writes the header to a connection to a backend that expects one.
+    pub async fn write<T>(&self, io: &mut T, version: Version) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::new();
+        self.encode(version, &mut buf);
+        io.write_all_buf(&mut buf).await
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(to_ipv6_addr(addr.ip())), addr.port())
+}
+
+fn to_ipv6_addr(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// Reads a version 1 or 2 header, without reading past it. Returns `None`
+/// for a header that carries no addresses, such as the health checks of a
+/// load balancer, in which case the connection's own addresses apply.
+pub async fn read_header<T>(io: &mut T) -> Result<Option<ProxyHeader>, Error>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut prefix = [0; 8];
+    io.read_exact(&mut prefix).await?;
+    if prefix.starts_with(V1_PREFIX) {
+        read_v1(io, &prefix).await
+    } else if prefix == SIGNATURE[..prefix.len()] {
+        read_v2(io, &prefix).await
+    } else {
+        Err(Error::MissingProxyHeader)
+    }
+}
+
+async fn read_v1<T>(io: &mut T, prefix: &[u8]) -> Result<Option<ProxyHeader>, Error>
+where
+    T: AsyncRead + Unpin,
+{
+    // The line is read byte by byte, what follows it belongs to the caller.
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_LENGTH {
+            return Err(Error::MalformedProxyHeader);
+        }
+        line.push(io.read_u8().await?);
+    }
+    trace!("proxy protocol v1 {:?}", String::from_utf8_lossy(&line));
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// Parses a version 1 line without its CRLF.
+fn parse_v1(line: &[u8]) -> Result<Option<ProxyHeader>, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::MalformedProxyHeader)?;
+    let mut fields = line.split(' ').skip(1);
+    let ipv4 = match fields.next() {
+        Some("TCP4") => true,
+        Some("TCP6") => false,
+        // The rest of the line is ignored.
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(Error::MalformedProxyHeader),
+    };
+
+    let fields: Vec<&str> = fields.collect();
+    let [source, destination, source_port, destination_port] = fields[..] else {
+        return Err(Error::MalformedProxyHeader);
+    };
+    let ip = |v: &str| match v.parse::<IpAddr>() {
+        Ok(ip) if ip.is_ipv4() == ipv4 => Ok(ip),
+        _ => Err(Error::MalformedProxyHeader),
+    };
+    let port = |v: &str| v.parse::<u16>().map_err(|_| Error::MalformedProxyHeader);
+    Ok(Some(ProxyHeader::new(
+        SocketAddr::new(ip(source)?, port(source_port)?),
+        SocketAddr::new(ip(destination)?, port(destination_port)?),
+    )))
+}
+
+async fn read_v2<T>(io: &mut T, prefix: &[u8]) -> Result<Option<ProxyHeader>, Error>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut header = [0; 16];
+    header[..prefix.len()].copy_from_slice(prefix);
+    io.read_exact(&mut header[prefix.len()..]).await?;
+    if header[..SIGNATURE.len()] != SIGNATURE || header[12] & 0xf0 != V2_VERSION {
+        return Err(Error::MalformedProxyHeader);
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0; length];
+    io.read_exact(&mut addresses).await?;
+    trace!("proxy protocol v2 {:02x?}", &header[12..]);
+    parse_v2(header[12] & 0x0f, header[13], &addresses)
+}
+
+/// Parses the address block of a version 2 header, the TLVs following the
+/// addresses are skipped.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> Result<Option<ProxyHeader>, Error> {
+    match command {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(Error::MalformedProxyHeader),
+    }
+
+    let port = |v: &[u8]| u16::from_be_bytes([v[0], v[1]]);
+    // The low nibble is the transport, stream or datagram.
+    match family & 0xf0 {
+        V2_FAMILY_INET => {
+            let v = addresses
+                .get(..V2_INET_LENGTH)
+                .ok_or(Error::MalformedProxyHeader)?;
+            let source = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
+            let destination = Ipv4Addr::new(v[4], v[5], v[6], v[7]);
+            Ok(Some(ProxyHeader::new(
+                SocketAddr::new(source.into(), port(&v[8..])),
+                SocketAddr::new(destination.into(), port(&v[10..])),
+            )))
+        }
+        V2_FAMILY_INET6 => {
+            let v = addresses
+                .get(..V2_INET6_LENGTH)
+                .ok_or(Error::MalformedProxyHeader)?;
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&v[..16]).unwrap());
+            let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&v[16..32]).unwrap());
+            Ok(Some(ProxyHeader::new(
+                SocketAddr::new(source.into(), port(&v[32..])),
+                SocketAddr::new(destination.into(), port(&v[34..])),
+            )))
+        }
+        // Unspecified and unix socket addresses.
+        _ => Ok(None),
+    }
+}
+
+/// A connection that started with a PROXY protocol header.
+#[derive(Debug)]
+pub struct ProxiedStream<T> {
+    io: T,
+    header: Option<ProxyHeader>,
+}
+
+impl<T> ProxiedStream<T> {
+    /// Reads the header of an accepted connection, a connection without one
+    /// is rejected.
+    pub async fn accept(mut io: T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let header = read_header(&mut io).await?;
+        Ok(Self { io, header })
+    }
+
+    /// Returns the addresses carried by the header, if any.
+    pub fn header(&self) -> Option<&ProxyHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T> AsyncRead for ProxiedStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for ProxiedStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io::AsyncReadExt;
+
+    use super::{read_header, ProxyHeader, Version};
+    use crate::Error;
+
+    #[tokio::test]
+    async fn test_v1() {
+        let mut data = &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /"[..];
+        let header = read_header(&mut data).await.unwrap().unwrap();
+        assert_eq!(header.source, "192.168.0.1:56324".parse().unwrap());
+        assert_eq!(header.destination, "192.168.0.11:443".parse().unwrap());
+        assert_eq!(data, b"GET /");
+
+        let mut data = &b"PROXY TCP6 ::1 2001:db8::1 1 2\r\n"[..];
+        let header = read_header(&mut data).await.unwrap().unwrap();
+        assert_eq!(header.source, "[::1]:1".parse().unwrap());
+        assert_eq!(header.destination, "[2001:db8::1]:2".parse().unwrap());
+
+        let mut data = &b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n"[..];
+        assert_eq!(read_header(&mut data).await.unwrap(), None);
+
+        for line in [
+            &b"PROXY TCP4 ::1 ::1 1 2\r\n"[..],
+            b"PROXY TCP4 127.0.0.1 127.0.0.1 1\r\n",
+            b"PROXY TCP4 127.0.0.1 127.0.0.1 1 65536\r\n",
+            b"PROXY UDP4 127.0.0.1 127.0.0.1 1 2\r\n",
+        ] {
+            let mut data = line;
+            assert!(matches!(
+                read_header(&mut data).await,
+                Err(Error::MalformedProxyHeader)
+            ));
+        }
+
+        let line = [b"PROXY UNKNOWN ".to_vec(), vec![b'x'; 100]].concat();
+        assert!(matches!(
+            read_header(&mut &line[..]).await,
+            Err(Error::MalformedProxyHeader)
+        ));
+        assert!(matches!(
+            read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await,
+            Err(Error::MissingProxyHeader)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        // A TCP over IPv4 header with a NOOP TLV.
+        let mut data: Vec<u8> = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x10".to_vec();
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0x01, 0xbb]);
+        data.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        data.extend_from_slice(b"data");
+        let mut reader = &data[..];
+        let header = read_header(&mut reader).await.unwrap().unwrap();
+        assert_eq!(header.source, "10.0.0.1:8080".parse().unwrap());
+        assert_eq!(header.destination, "10.0.0.2:443".parse().unwrap());
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"data");
+
+        // LOCAL, sent by health checks.
+        let mut reader = &b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00"[..];
+        assert_eq!(read_header(&mut reader).await.unwrap(), None);
+
+        // Too short for the IPv6 addresses.
+        let mut data = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x0c".to_vec();
+        data.extend_from_slice(&[0; 12]);
+        assert!(matches!(
+            read_header(&mut &data[..]).await,
+            Err(Error::MalformedProxyHeader)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let headers = [
+            ("127.0.0.1:1", "127.0.0.2:2"),
+            ("[::1]:1", "[2001:db8::1]:2"),
+            ("127.0.0.1:1", "[::1]:2"),
+        ];
+        for (source, destination) in headers {
+            let header = ProxyHeader::new(source.parse().unwrap(), destination.parse().unwrap());
+            for version in [Version::V1, Version::V2] {
+                let mut buf = BytesMut::new();
+                header.encode(version, &mut buf);
+                let decoded = read_header(&mut &buf[..]).await.unwrap().unwrap();
+                if header.source.is_ipv4() == header.destination.is_ipv4() {
+                    assert_eq!(decoded, header);
+                } else {
+                    assert_eq!(decoded.source, "[::ffff:127.0.0.1]:1".parse().unwrap());
+                    assert_eq!(decoded.destination, header.destination);
+                }
+            }
+        }
+
+        let header = ProxyHeader::new(
+            "192.168.0.1:56324".parse().unwrap(),
+            "192.168.0.11:443".parse().unwrap(),
+        );
+        let mut buf = BytesMut::new();
+        header.encode(Version::V1, &mut buf);
+        assert_eq!(
+            &buf[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+        );
+    }
+}
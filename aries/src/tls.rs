@@ -0,0 +1,154 @@
+//! The rustls helpers shared by the TLS backends of the proxies.
+
+use std::sync::Arc;
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use rustls_pki_types::pem::PemObject;
+use tokio_rustls::server::TlsStream;
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+use crate::Error;
+
+/// Returns the name of a client that presented a verified certificate, see
+/// [`certificate_identity`].
+pub fn peer_identity<T>(stream: &TlsStream<T>) -> Option<String> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    certificate_identity(cert)
+}
+
+/// Maps a DER encoded certificate to the name of its owner: the first
+/// e-mail address, DNS name or URI of the subject alternative names, or the
+/// common name of the subject if it has none, as RFC 6125 section 6.4.4
+/// prefers the alternative names.
+pub fn certificate_identity(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(der).ok()?;
+    let alt_name = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|names| {
+            names
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::RFC822Name(name)
+                    | GeneralName::DNSName(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+        });
+    if alt_name.is_some() {
+        return alt_name;
+    }
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(str::to_string)
+}
+
+/// Parses a PEM encoded certificate chain and private key.
+pub fn parse_pem(
+    cert_chain: &[u8],
+    key: &[u8],
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    let cert_chain = CertificateDer::pem_slice_iter(cert_chain)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::Tls("invalid certificate"))?;
+    let key = PrivateKeyDer::from_pem_slice(key).map_err(|_| Error::Tls("invalid key"))?;
+    Ok((cert_chain, key))
+}
+
+/// Accepts any certificate, only the handshake signatures are checked with
+/// the algorithms of the provider. For testing only.
+#[derive(Debug)]
+pub struct NoVerifier(Arc<CryptoProvider>);
+
+impl NoVerifier {
+    pub fn new(provider: Arc<CryptoProvider>) -> Self {
+        Self(provider)
+    }
+}
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DnType, KeyPair};
+
+    use super::{certificate_identity, parse_pem};
+
+    #[test]
+    fn test_certificate_identity() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(certificate_identity(cert.der()).unwrap(), "alice");
+
+        // A subject alternative name is preferred to the common name.
+        let mut params = CertificateParams::new(vec!["client.example".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(certificate_identity(cert.der()).unwrap(), "client.example");
+    }
+
+    #[test]
+    fn test_parse_pem() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let (cert_chain, _) =
+            parse_pem(cert.pem().as_bytes(), key.serialize_pem().as_bytes()).unwrap();
+        assert_eq!(cert_chain.len(), 1);
+        assert!(parse_pem(cert.pem().as_bytes(), b"").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Unix domain socket listeners, for clients on the same host such as
+//! sidecars sharing a volume with the proxy.
+
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, trace};
+use tokio::net::UnixStream;
+
+/// A listener on a socket file, which is removed when the listener is
+/// dropped.
+#[derive(Debug)]
+pub struct UnixListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+impl UnixListener {
+    /// Binds to `path`. A socket file left by a previous run is removed, one
+    /// that a live server still accepts on is an error, and so is any other
+    /// kind of file.
+    pub fn bind<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ));
+                }
+                debug!("remove stale socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Sets the permission bits of the socket file, such as `0o660` to admit
+    /// the owning group only. The file is created with the permissions of the
+    /// process umask, bind in a private directory to close that window.
+    pub fn set_mode(&self, mode: u32) -> io::Result<()> {
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = self.listener.accept().await?;
+        trace!("accept connection on {}", self.path.display());
+        Ok(stream)
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::UnixListener;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = std::env::temp_dir().join(format!("aries-unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.sock");
+
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_mode(0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixListener::bind(&path).is_err());
+        drop(listener);
+        assert!(!path.exists());
+
+        // A socket file nobody listens on is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = UnixListener::bind(&path).unwrap();
+        drop(listener);
+
+        fs::write(&path, b"").unwrap();
+        assert!(UnixListener::bind(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Connections to upstream servers over TCP, or over Unix sockets for the
+//! hosts mapped to a socket path.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use log::trace;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// Opens upstream connections, over a Unix socket for the hosts added with
+/// [`Connector::add_unix_socket`] and over TCP otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    unix_sockets: HashMap<String, PathBuf>,
+}
+
+impl Connector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes connections to `host`, on any port, to the socket at `path`.
+    /// The host is matched without regard to case, such as a reserved name
+    /// like `backend.internal` that DNS would never resolve.
+    pub fn add_unix_socket<P>(mut self, host: &str, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.unix_sockets
+            .insert(host.to_ascii_lowercase(), path.into());
+        self
+    }
+
+    /// Returns the socket path `host` is routed to.
+    pub fn unix_socket(&self, host: &str) -> Option<&Path> {
+        self.unix_sockets
+            .get(&host.to_ascii_lowercase())
+            .map(PathBuf::as_path)
+    }
+
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<Stream> {
+        if let Some(path) = self.unix_socket(host) {
+            trace!("connect to {} at {}", host, path.display());
+            #[cfg(unix)]
+            return Ok(Stream::Unix(UnixStream::connect(path).await?));
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets not supported",
+            ));
+        }
+        Ok(Stream::Tcp(TcpStream::connect((host, port)).await?))
+    }
+}
+
+/// A connection opened by a [`Connector`].
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Returns the local address of a TCP connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Err(no_ip_address()),
+        }
+    }
+
+    /// Returns the remote address of a TCP connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Err(no_ip_address()),
+        }
+    }
+
+    /// Reads without waiting, like [`TcpStream::try_read`].
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.try_read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_read(buf),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn no_ip_address() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "unix socket has no ip address")
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{Connector, Stream};
+    use crate::unix::UnixListener;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let path = std::env::temp_dir().join(format!("aries-upstream-{}.sock", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let connector = Connector::new().add_unix_socket("Backend.Internal", &path);
+        assert_eq!(
+            connector.unix_socket("backend.internal"),
+            Some(path.as_path())
+        );
+
+        let mut stream = connector.connect("backend.internal", 80).await.unwrap();
+        assert!(matches!(stream, Stream::Unix(_)));
+        assert!(stream.peer_addr().is_err());
+        stream.write_all(b"hello").await.unwrap();
+        let mut accepted = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let stream = connector.connect("127.0.0.1", port).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+}
@@ -0,0 +1,416 @@
+use std::{collections::HashMap, fmt};
+
+use async_trait::async_trait;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// The credentials carried by a `Proxy-Authorization` header.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// The `Basic` scheme with the decoded user-id and password.
+    Basic { username: String, password: String },
+
+    /// Any other scheme, the scheme name is lowercased and the token is
+    /// passed through untouched.
+    Other { scheme: String, token: String },
+}
+
+impl Credentials {
+    /// Parses the value of a `Proxy-Authorization` header. The scheme is
+    /// matched case-insensitively and surrounding whitespace is ignored.
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?.trim();
+        let (scheme, token) = match value.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some((scheme, token)) => (scheme, token.trim()),
+            None => (value, ""),
+        };
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(token)
+                .ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            Some(Credentials::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        } else if scheme.is_empty() {
+            None
+        } else {
+            Some(Credentials::Other {
+                scheme: scheme.to_ascii_lowercase(),
+                token: token.to_string(),
+            })
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Credentials::Other { scheme, .. } => f
+                .debug_struct("Other")
+                .field("scheme", scheme)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// The identity of an authenticated proxy user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    name: String,
+}
+
+impl Identity {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name.fmt(f)
+    }
+}
+
+/// The request being authenticated.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthContext<'a> {
+    /// The request method.
+    pub method: &'a http::Method,
+
+    /// The request-target as sent by the client.
+    pub target: &'a str,
+
+    /// The protection space configured on the server.
+    pub realm: &'a str,
+}
+
+/// Verifies the credentials presented by a proxy client.
+#[async_trait]
+pub trait ProxyAuthenticator: Send + Sync {
+    /// Returns the identity of the user, or `None` if the credentials are
+    /// rejected and the client must be challenged again.
+    async fn authenticate(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: &Credentials,
+    ) -> Result<Option<Identity>, Error>;
+
+    /// Returns the challenges sent in `Proxy-Authenticate` when the request
+    /// carries no or rejected `credentials`. Defaults to a `Basic` challenge.
+    fn challenges(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: Option<&Credentials>,
+    ) -> Vec<String> {
+        let _ = credentials;
+        vec![format!(
+            "Basic realm={}, charset=\"UTF-8\"",
+            quote(context.realm)
+        )]
+    }
+}
+
+/// Authenticates `Basic` credentials against a fixed set of users.
+#[derive(Clone, Default)]
+pub struct BasicUsers {
+    users: HashMap<String, String>,
+}
+
+impl BasicUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(mut self, username: &str, password: &str) -> Self {
+        self.users
+            .insert(username.to_string(), password.to_string());
+        self
+    }
+}
+
+impl fmt::Debug for BasicUsers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicUsers")
+            .field("users", &self.users.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl ProxyAuthenticator for BasicUsers {
+    async fn authenticate(
+        &self,
+        _context: &AuthContext<'_>,
+        credentials: &Credentials,
+    ) -> Result<Option<Identity>, Error> {
+        if let Credentials::Basic { username, password } = credentials {
+            // An unknown user is compared against a dummy password, so the
+            // time taken does not tell whether the user exists.
+            let expected = self.users.get(username);
+            let matched = constant_time_eq(
+                expected
+                    .map_or(UNKNOWN_USER_PASSWORD, String::as_str)
+                    .as_bytes(),
+                password.as_bytes(),
+            );
+            if matched && expected.is_some() {
+                return Ok(Some(Identity::new(username.as_str())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The password an unknown user is checked against.
+pub(crate) const UNKNOWN_USER_PASSWORD: &str = "unknown user";
+
+/// Compares two byte strings in constant time. Both are hashed first, so
+/// that the time taken does not depend on their lengths either.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Quotes a string as an HTTP `quoted-string`.
+pub(crate) fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A challenge received in a `Proxy-Authenticate` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Challenge {
+    /// The lowercased scheme name.
+    pub(crate) scheme: String,
+    pub(crate) token68: Option<String>,
+    pub(crate) params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    pub(crate) fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses the challenges of a `Proxy-Authenticate` header value, several
+/// comma separated challenges may share a single header.
+pub(crate) fn parse_challenges(value: &str) -> Vec<Challenge> {
+    let mut parser = Parser {
+        s: value.as_bytes(),
+        pos: 0,
+    };
+    let mut challenges: Vec<Challenge> = Vec::new();
+
+    loop {
+        parser.skip(b" \t,");
+        let name = parser.token();
+        if name.is_empty() {
+            break;
+        }
+
+        parser.skip(b" \t");
+        if let Some(challenge) = challenges.last_mut() {
+            if parser.peek() == Some(b'=') && challenge.token68.is_none() {
+                parser.pos += 1;
+                parser.skip(b" \t");
+                let value = if parser.peek() == Some(b'"') {
+                    parser.quoted()
+                } else {
+                    parser.token()
+                };
+                challenge.params.push((name.to_ascii_lowercase(), value));
+                continue;
+            }
+        }
+
+        let mut challenge = Challenge {
+            scheme: name.to_ascii_lowercase(),
+            token68: None,
+            params: Vec::new(),
+        };
+        let mark = parser.pos;
+        let token68 = parser.token68();
+        parser.skip(b" \t");
+        if !token68.is_empty() && matches!(parser.peek(), None | Some(b',')) {
+            challenge.token68 = Some(token68);
+        } else {
+            parser.pos = mark;
+        }
+        challenges.push(challenge);
+    }
+
+    challenges
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn skip(&mut self, set: &[u8]) {
+        while matches!(self.peek(), Some(c) if set.contains(&c)) {
+            self.pos += 1;
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if f(c)) {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.s[start..self.pos]).into_owned()
+    }
+
+    fn token(&mut self) -> String {
+        self.take_while(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+    }
+
+    fn token68(&mut self) -> String {
+        let mut token = self.take_while(|c| c.is_ascii_alphanumeric() || b"-._~+/".contains(&c));
+        token.push_str(&self.take_while(|c| c == b'='));
+        token
+    }
+
+    fn quoted(&mut self) -> String {
+        let mut value = Vec::new();
+        self.pos += 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    if let Some(c) = self.peek() {
+                        self.pos += 1;
+                        value.push(c);
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        String::from_utf8_lossy(&value).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        constant_time_eq, parse_challenges, AuthContext, BasicUsers, Credentials,
+        ProxyAuthenticator,
+    };
+
+    #[test]
+    fn test_parse_basic() {
+        let expected = Credentials::Basic {
+            username: "Aladdin".to_string(),
+            password: "open sesame".to_string(),
+        };
+        assert_eq!(
+            Credentials::parse(b"Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            Credentials::parse(b"  bAsIc   QWxhZGRpbjpvcGVuIHNlc2FtZQ==  "),
+            Some(expected)
+        );
+        assert_eq!(Credentials::parse(b"Basic !!!"), None);
+        assert_eq!(
+            Credentials::parse(b"Bearer abc"),
+            Some(Credentials::Other {
+                scheme: "bearer".to_string(),
+                token: "abc".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_basic_users() {
+        let users = BasicUsers::new()
+            .add_user("alice", "secret")
+            .add_user("bob", "hunter2");
+        let credentials = |u: &str, p: &str| Credentials::Basic {
+            username: u.to_string(),
+            password: p.to_string(),
+        };
+        let context = AuthContext {
+            method: &http::Method::CONNECT,
+            target: "example.com:443",
+            realm: "zodiac",
+        };
+        let identity = users
+            .authenticate(&context, &credentials("bob", "hunter2"))
+            .await
+            .unwrap();
+        assert_eq!(identity.unwrap().name(), "bob");
+        assert!(users
+            .authenticate(&context, &credentials("alice", "hunter2"))
+            .await
+            .unwrap()
+            .is_none());
+        // The dummy password of unknown users does not let anyone in.
+        assert!(users
+            .authenticate(&context, &credentials("carol", "unknown user"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn test_parse_challenges() {
+        let challenges = parse_challenges(
+            r#"Basic realm="a \"quoted\" realm", Digest realm="zodiac", qop="auth, auth-int", stale=TRUE, NTLM TlRMTVNTUAACAAAA=="#,
+        );
+        assert_eq!(challenges.len(), 3);
+        assert_eq!(challenges[0].scheme, "basic");
+        assert_eq!(challenges[0].param("realm"), Some(r#"a "quoted" realm"#));
+        assert_eq!(challenges[1].scheme, "digest");
+        assert_eq!(challenges[1].param("qop"), Some("auth, auth-int"));
+        assert_eq!(challenges[1].param("Stale"), Some("TRUE"));
+        assert_eq!(challenges[2].scheme, "ntlm");
+        assert_eq!(challenges[2].token68.as_deref(), Some("TlRMTVNTUAACAAAA=="));
+
+        let challenges = parse_challenges("NTLM");
+        assert_eq!(challenges[0].scheme, "ntlm");
+        assert!(challenges[0].token68.is_none());
+    }
+}
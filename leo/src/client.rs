@@ -1,18 +1,45 @@
+use std::sync::{Arc, Mutex};
+
+use aries::{
+    address::Address,
+    connector::{self, BoxStream, ConnectError, Protocol, ProxyConnector},
+    proxy_url::{ProxyUrl, Scheme},
+};
+use async_trait::async_trait;
 use base64::Engine;
-use bytes::{Buf, BytesMut};
-use http::{header, HeaderMap};
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue};
 use log::trace;
-use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufStream};
 
 use crate::{
-    codec::{encode_request, parse_response},
+    auth::{parse_challenges, Challenge},
+    codec::{encode_request, is_keep_alive, parse_response, skip_body},
+    digest::DigestChallenge,
+    http2::Tunnel,
+    ntlm::{self, ChallengeMessage, NtlmCredentials},
     Error,
 };
 
+/// The maximum number of `CONNECT` requests sent on one connection, a
+/// Digest or NTLM exchange needs a second request and a stale nonce a
+/// third one.
+const MAX_ATTEMPTS: usize = 3;
+
 #[derive(Debug, Clone, Default)]
 pub struct Builder {
-    authorization: Option<String>,
-    destination: Option<(String, u16)>,
+    authorization: Option<(String, String)>,
+    ntlm: Option<NtlmCredentials>,
+    /// The target, or the reason the host given was rejected.
+    destination: Option<Result<Address, &'static str>>,
+    extended_connect: Option<(String, String, String)>,
+    preemptive_basic: bool,
+    /// The last Digest challenge answered and the number of requests sent
+    /// with its nonce, shared by the clones of the builder.
+    digest: Arc<Mutex<Option<(DigestChallenge, u32)>>>,
+    proxy: Option<Address>,
+    #[cfg(feature = "rustls")]
+    tls: Option<crate::tls::rustls::TlsConnector>,
 }
 
 impl Builder {
@@ -21,44 +48,414 @@ impl Builder {
         T: AsyncWrite + AsyncBufRead + Unpin,
     {
         let mut buf = BytesMut::new();
-        let (host, port) = self
-            .destination
-            .as_ref()
-            .ok_or_else(|| Error::Http("host and port required"))?;
-        let mut headers = HeaderMap::new();
-        if let Some(auth) = &self.authorization {
-            headers.append(header::PROXY_AUTHORIZATION, auth.parse().unwrap());
+        let destination = self.destination()?;
+        let uri = destination.to_string();
+        let mut authorization = match self.ntlm {
+            Some(_) => Some(ntlm_authorization(&ntlm::negotiate_message())?),
+            None => self.initial_authorization(&uri)?,
+        };
+        let mut answered = false;
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut headers = HeaderMap::new();
+            if let Some(auth) = authorization.take() {
+                headers.append(header::PROXY_AUTHORIZATION, auth);
+            }
+            trace!("encode request");
+            encode_request(destination, &headers, &mut buf);
+            trace!("write {} bytes", buf.remaining(),);
+            io.write_all_buf(&mut buf).await?;
+            io.flush().await?;
+            trace!("parse response");
+            let resp = parse_response(&mut io)
+                .await?
+                .ok_or(Error::HttpStatus("non status code"))?;
+            if resp.status.is_success() {
+                return Ok(io);
+            }
+
+            let reason = resp
+                .status
+                .canonical_reason()
+                .unwrap_or("non canonical reason");
+            if resp.status != http::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+                return Err(Error::HttpStatus(reason));
+            }
+
+            let challenges: Vec<_> = resp
+                .headers
+                .get_all(header::PROXY_AUTHENTICATE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(parse_challenges)
+                .collect();
+            let value = if let Some(credentials) = &self.ntlm {
+                let challenge = challenges
+                    .iter()
+                    .find(|c| c.scheme == "ntlm")
+                    .and_then(|c| c.token68.as_deref())
+                    .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok());
+                match challenge {
+                    // A second challenge means the credentials were rejected.
+                    Some(challenge) if !answered => {
+                        trace!("answer ntlm challenge");
+                        let challenge = ChallengeMessage::parse(&challenge)
+                            .ok_or(Error::Http("malformed ntlm challenge"))?;
+                        ntlm_authorization(&ntlm::authenticate_message(credentials, &challenge))?
+                    }
+                    _ => return Err(Error::HttpStatus(reason)),
+                }
+            } else {
+                self.answer_challenges(&challenges, &uri, answered)?
+                    .ok_or(Error::HttpStatus(reason))?
+            };
+
+            if !is_keep_alive(resp.version, &resp.headers) {
+                trace!("proxy closes the connection after 407");
+                return Err(Error::HttpStatus(reason));
+            }
+            skip_body(&mut io, &resp.headers).await?;
+            authorization = Some(value);
+            answered = true;
+        }
+
+        Err(Error::HttpStatus(
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                .canonical_reason()
+                .unwrap(),
+        ))
+    }
+
+    /// Opens a tunnel on a new stream of an HTTP/2 connection to the proxy,
+    /// such as the one returned by [`crate::http2::connect`]. A challenge is
+    /// answered on another stream, NTLM is bound to HTTP/1.1 connections and
+    /// not supported.
+    pub async fn handshake_h2(
+        &self,
+        send_request: &h2::client::SendRequest<Bytes>,
+    ) -> Result<Tunnel, Error> {
+        if self.ntlm.is_some() {
+            return Err(Error::Http("ntlm requires http/1.1"));
         }
-        trace!("encode request");
-        encode_request(host.as_str(), *port, &headers, &mut buf);
-        trace!("write {} bytes", buf.remaining(),);
-        io.write_all_buf(&mut buf).await?;
-        io.flush().await?;
-        trace!("parse response");
-        let resp = parse_response(&mut io).await?;
-        if let Some(status) = resp {
+        let authority = self.destination()?.to_string();
+        let uri = match &self.extended_connect {
+            Some((_, scheme, path)) => format!("{}://{}{}", scheme, authority, path),
+            None => authority.clone(),
+        };
+        let mut authorization = self.initial_authorization(&authority)?;
+        let mut answered = false;
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut request = http::Request::builder()
+                .method(http::Method::CONNECT)
+                .uri(uri.as_str())
+                .body(())
+                .map_err(|_| Error::Http("invalid host"))?;
+            if let Some(auth) = authorization.take() {
+                request
+                    .headers_mut()
+                    .insert(header::PROXY_AUTHORIZATION, auth);
+            }
+            if let Some((protocol, _, _)) = &self.extended_connect {
+                if !send_request.is_extended_connect_protocol_enabled() {
+                    return Err(Error::Http("extended connect not enabled by proxy"));
+                }
+                request
+                    .extensions_mut()
+                    .insert(h2::ext::Protocol::from(protocol.as_str()));
+            }
+
+            trace!("send request");
+            let mut send_request = send_request.clone().ready().await?;
+            let (response, send) = send_request.send_request(request, false)?;
+            let response = response.await?;
+            let status = response.status();
             if status.is_success() {
-                Ok(io)
-            } else {
-                Err(Error::HttpStatus(
-                    status.canonical_reason().unwrap_or("non canonical reason"),
-                ))
+                let protocol = self.extended_connect.as_ref().map(|v| v.0.clone());
+                let path = self.extended_connect.as_ref().map(|v| v.2.clone());
+                return Ok(Tunnel::new(send, response.into_body(), protocol, path));
+            }
+
+            let reason = status.canonical_reason().unwrap_or("non canonical reason");
+            if status != http::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+                return Err(Error::HttpStatus(reason));
+            }
+            let challenges: Vec<_> = response
+                .headers()
+                .get_all(header::PROXY_AUTHENTICATE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(parse_challenges)
+                .collect();
+            authorization = Some(
+                self.answer_challenges(&challenges, &authority, answered)?
+                    .ok_or(Error::HttpStatus(reason))?,
+            );
+            answered = true;
+        }
+
+        Err(Error::HttpStatus(
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                .canonical_reason()
+                .unwrap(),
+        ))
+    }
+
+    /// Opens a UDP tunnel to the destination with a `CONNECT-UDP` request of
+    /// RFC 9298 on an HTTP/3 connection to the proxy. A challenge is answered
+    /// with another request.
+    #[cfg(feature = "http3")]
+    pub async fn handshake_udp(
+        &self,
+        client: &crate::masque::Client,
+    ) -> Result<crate::masque::UdpTunnel, Error> {
+        if self.ntlm.is_some() {
+            return Err(Error::Http("ntlm requires http/1.1"));
+        }
+        let destination = self.destination()?;
+        let target = destination.to_string();
+        let host = destination.host().to_string();
+        let port = destination.port();
+        let mut authorization = self.initial_authorization(&target)?;
+        let mut answered = false;
+
+        for _ in 0..MAX_ATTEMPTS {
+            trace!("send request");
+            let (response, tunnel) = client.request(&host, port, authorization.take()).await?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(tunnel);
+            }
+
+            let reason = status.canonical_reason().unwrap_or("non canonical reason");
+            if status != http::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+                return Err(Error::HttpStatus(reason));
+            }
+            let challenges: Vec<_> = response
+                .headers()
+                .get_all(header::PROXY_AUTHENTICATE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(parse_challenges)
+                .collect();
+            authorization = Some(
+                self.answer_challenges(&challenges, &target, answered)?
+                    .ok_or(Error::HttpStatus(reason))?,
+            );
+            answered = true;
+        }
+
+        Err(Error::HttpStatus(
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                .canonical_reason()
+                .unwrap(),
+        ))
+    }
+
+    /// The credentials sent with the first request: a `Digest` answer
+    /// reusing the nonce of an earlier challenge, or `Basic` if enabled with
+    /// [`Builder::set_preemptive_basic`].
+    fn initial_authorization(&self, uri: &str) -> Result<Option<HeaderValue>, Error> {
+        let (username, password) = match &self.authorization {
+            Some(authorization) => authorization,
+            None => return Ok(None),
+        };
+        if let Some((challenge, nc)) = self.digest.lock().unwrap().as_mut() {
+            *nc += 1;
+            trace!("reuse digest nonce, nc {}", nc);
+            let value = challenge.authorization("CONNECT", uri, username, password, *nc);
+            return Ok(Some(HeaderValue::try_from(value)?));
+        }
+        if self.preemptive_basic {
+            return self.basic_authorization();
+        }
+        Ok(None)
+    }
+
+    /// Answers a `Digest` challenge, or a `Basic` one if the proxy offers no
+    /// Digest. `answered` is set if the previous request already carried an
+    /// answer.
+    fn answer_challenges(
+        &self,
+        challenges: &[Challenge],
+        uri: &str,
+        answered: bool,
+    ) -> Result<Option<HeaderValue>, Error> {
+        let (username, password) = match &self.authorization {
+            Some(authorization) => authorization,
+            None => return Ok(None),
+        };
+        match DigestChallenge::select(challenges) {
+            // A fresh challenge after a Digest answer means the credentials
+            // were rejected, unless the nonce went stale.
+            Some(challenge) if !answered || challenge.stale => {
+                trace!("answer digest challenge");
+                let value = challenge.authorization("CONNECT", uri, username, password, 1);
+                *self.digest.lock().unwrap() = Some((challenge, 1));
+                Ok(Some(HeaderValue::try_from(value)?))
             }
-        } else {
-            Err(Error::HttpStatus("non status code"))
+            Some(_) => Ok(None),
+            None if !answered && challenges.iter().any(|c| c.scheme == "basic") => {
+                trace!("answer basic challenge");
+                self.basic_authorization()
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn destination(&self) -> Result<&Address, Error> {
+        match &self.destination {
+            Some(destination) => destination.as_ref().map_err(|e| Error::Http(e)),
+            None => Err(Error::Http("host and port required")),
         }
     }
 
+    fn basic_authorization(&self) -> Result<Option<HeaderValue>, Error> {
+        match &self.authorization {
+            Some((username, password)) => {
+                let raw_auth = format!("{}:{}", username, password);
+                let mut encoded = String::from("Basic ");
+                base64::engine::general_purpose::STANDARD
+                    .encode_string(raw_auth.as_bytes(), &mut encoded);
+                Ok(Some(HeaderValue::try_from(encoded)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the credentials used to answer a `Digest` or `Basic` challenge
+    /// of the proxy.
     pub fn set_authorization(mut self, username: &str, password: &str) -> Self {
-        let raw_auth = format!("{}:{}", username, password);
-        let mut encoded = String::from("Basic ");
-        base64::engine::general_purpose::STANDARD.encode_string(raw_auth.as_bytes(), &mut encoded);
-        self.authorization = Some(encoded);
+        self.authorization = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Sends the credentials with `Basic` on the first request, saving a
+    /// round trip to a proxy known to accept `Basic`. Off by default, since
+    /// it hands the password in the clear to a proxy that only asks for
+    /// `Digest`.
+    pub fn set_preemptive_basic(mut self, preemptive_basic: bool) -> Self {
+        self.preemptive_basic = preemptive_basic;
         self
     }
 
+    /// Authenticates with NTLMv2 instead of `Basic` or `Digest`, the whole
+    /// exchange runs on the connection passed to [`Builder::handshake`].
+    pub fn set_ntlm_credentials(
+        mut self,
+        domain: &str,
+        username: &str,
+        password: &str,
+        workstation: &str,
+    ) -> Self {
+        self.ntlm = Some(NtlmCredentials {
+            domain: domain.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            workstation: workstation.to_string(),
+        });
+        self
+    }
+
+    /// Sets the target host and port, an invalid host fails the handshake.
     pub fn set_host_port(mut self, host: String, port: u16) -> Self {
-        self.destination = Some((host, port));
+        self.destination = Some(Address::new(&host, port).map_err(|_| "invalid host"));
+        self
+    }
+
+    pub fn set_address(mut self, address: Address) -> Self {
+        self.destination = Some(Ok(address));
         self
     }
+
+    /// Sends an extended `CONNECT` with `:protocol`, `:scheme` and `:path`
+    /// from [`Builder::handshake_h2`], such as `websocket` to bootstrap a
+    /// WebSocket as in RFC 8441.
+    pub fn set_extended_connect(mut self, protocol: &str, scheme: &str, path: &str) -> Self {
+        self.extended_connect = Some((protocol.to_string(), scheme.to_string(), path.to_string()));
+        self
+    }
+
+    /// Sets the proxy dialed by [`ProxyConnector::connect`], the tunnel is
+    /// opened with an HTTP/1.1 `CONNECT`.
+    pub fn set_proxy(mut self, proxy: Address) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Connects to the proxy of [`Builder::set_proxy`] over TLS, verifying
+    /// its certificate against the proxy host.
+    #[cfg(feature = "rustls")]
+    pub fn set_tls_connector(mut self, tls: crate::tls::rustls::TlsConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+/// Configures the proxy and credentials of an `http://` or `https://` URL,
+/// the latter needs the `rustls` feature and trusts the built-in roots.
+impl TryFrom<&ProxyUrl> for Builder {
+    type Error = aries::Error;
+
+    fn try_from(url: &ProxyUrl) -> Result<Self, Self::Error> {
+        let mut builder = Builder::default().set_proxy(url.proxy().clone());
+        match url.scheme() {
+            Scheme::Http => {}
+            #[cfg(feature = "rustls")]
+            Scheme::Https => {
+                let tls = crate::tls::rustls::ClientBuilder::new()
+                    .build()
+                    .map_err(|_| aries::Error::InvalidProxyUrl("tls configuration failed"))?;
+                builder = builder.set_tls_connector(tls);
+            }
+            #[cfg(not(feature = "rustls"))]
+            Scheme::Https => {
+                return Err(aries::Error::InvalidProxyUrl(
+                    "https proxy requires the rustls feature",
+                ))
+            }
+            _ => return Err(aries::Error::InvalidProxyUrl("not an http url")),
+        }
+        if let Some((username, password)) = url.credentials() {
+            builder = builder.set_authorization(username, password);
+        }
+        Ok(builder)
+    }
+}
+
+#[async_trait]
+impl ProxyConnector for Builder {
+    fn protocol(&self) -> Protocol {
+        Protocol::HttpConnect
+    }
+
+    async fn connect(&self, target: &Address) -> Result<BoxStream, ConnectError> {
+        let stream = connector::dial(self.proxy.as_ref()).await?;
+        let builder = self.clone().set_address(target.clone());
+        let map_err = |e| ConnectError::handshake(Protocol::HttpConnect, e);
+        #[cfg(feature = "rustls")]
+        if let (Some(tls), Some(proxy)) = (&self.tls, &self.proxy) {
+            let stream = tls
+                .connect(&proxy.host().to_string(), stream)
+                .await
+                .map_err(map_err)?;
+            let stream = builder
+                .handshake(BufStream::new(stream))
+                .await
+                .map_err(map_err)?;
+            return Ok(Box::new(stream));
+        }
+        // The buffer keeps any bytes the proxy sent after the response.
+        let stream = builder
+            .handshake(BufStream::new(stream))
+            .await
+            .map_err(map_err)?;
+        Ok(Box::new(stream))
+    }
+}
+
+fn ntlm_authorization(message: &[u8]) -> Result<HeaderValue, Error> {
+    let mut value = String::from("NTLM ");
+    base64::engine::general_purpose::STANDARD.encode_string(message, &mut value);
+    Ok(HeaderValue::try_from(value)?)
 }
@@ -1,152 +1,464 @@
+use aries::address::Address;
 use bytes::{BufMut, BytesMut};
-use http::HeaderMap;
+use http::{header, HeaderMap, HeaderName, HeaderValue};
 use httparse::{Request, Response};
 use log::{debug, trace};
-use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::errors::Error;
 
 const LF: u8 = b'\n';
 
 /// The maximum amount of headers parsed on the server.
-const MAX_HEADERS: usize = 128;
+pub(crate) const MAX_HEADERS: usize = 128;
 
 /// The maximum length of the head section we'll try to parse.
-const MAX_HEAD_LENGTH: usize = 8 * 1024;
+pub(crate) const MAX_HEAD_LENGTH: usize = 8 * 1024;
 
-/// The number returned from httparse when the request is HTTP 1.1
+/// The number returned from httparse when the message is HTTP 1.0
+const HTTP_1_0_VERSION: u8 = 0;
+
+/// The number returned from httparse when the message is HTTP 1.1
 const HTTP_1_1_VERSION: u8 = 1;
 
-pub(crate) async fn parse_request<R>(
-    reader: &mut R,
-    auth: Option<&str>,
-) -> Result<Option<(http::StatusCode, Option<String>)>, Error>
+fn http_version(version: Option<u8>) -> Option<http::Version> {
+    match version {
+        Some(HTTP_1_0_VERSION) => Some(http::Version::HTTP_10),
+        Some(HTTP_1_1_VERSION) => Some(http::Version::HTTP_11),
+        _ => None,
+    }
+}
+
+/// Reads the head section line by line, never buffering more than
+/// `max_head_length` bytes. Returns `None` if the peer closed the connection
+/// before a complete head was received.
+async fn read_head<R>(reader: &mut R, max_head_length: usize) -> Result<Option<Vec<u8>>, Error>
 where
     R: AsyncBufRead + Unpin,
 {
     let mut buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
-    let mut httparse_req = Request::new(&mut headers);
 
     loop {
-        let bytes_read = reader.read_until(LF, &mut buf).await?;
-        if bytes_read == 0 {
-            return Ok(None);
-        }
+        let (done, used) = {
+            let available = reader.fill_buf().await?;
+            if available.is_empty() {
+                return Ok(None);
+            }
 
-        trace!("read {} bytes, total {} bytes", bytes_read, buf.len());
-        assert!(
-            buf.len() < MAX_HEAD_LENGTH,
-            "Head byte length should be less than 8kb"
-        );
+            let (done, used) = match available.iter().position(|b| *b == LF) {
+                Some(idx) => (true, idx + 1),
+                None => (false, available.len()),
+            };
+            if buf.len() + used > max_head_length {
+                return Err(Error::HeadTooLarge);
+            }
+
+            buf.extend_from_slice(&available[..used]);
+            (done, used)
+        };
+
+        reader.consume(used);
+        trace!("read {} bytes, total {} bytes", used, buf.len());
+        if !done {
+            continue;
+        }
 
         let idx = buf.len() - 1;
         if idx >= 3 && &buf[idx - 3..] == b"\r\n\r\n" {
-            break;
+            return Ok(Some(buf));
+        }
+
+        if idx >= 1 && buf[idx - 1..=idx] == [LF, LF] {
+            return Ok(Some(buf));
         }
     }
+}
+
+/// The head of a request received by the proxy.
+#[derive(Debug)]
+pub(crate) struct RequestHead {
+    pub(crate) version: http::Version,
+    pub(crate) method: http::Method,
+    pub(crate) target: String,
+    pub(crate) headers: HeaderMap,
+}
+
+pub(crate) async fn parse_request<R>(
+    reader: &mut R,
+    max_head_length: usize,
+    max_headers: usize,
+) -> Result<Option<RequestHead>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let buf = match read_head(reader, max_head_length).await? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+    let mut httparse_req = Request::new(&mut headers);
 
     trace!("check parse status");
-    let status = httparse_req.parse(&buf)?;
-    assert!(!status.is_partial(), "Malformed HTTP head");
+    if httparse_req.parse(&buf)?.is_partial() {
+        return Err(Error::MalformedHead);
+    }
+
+    let version = http_version(httparse_req.version).ok_or(httparse::Error::Version)?;
+    let method = httparse_req
+        .method
+        .and_then(|v| http::Method::from_bytes(v.as_bytes()).ok())
+        .ok_or(Error::MalformedHead)?;
+    let target = httparse_req
+        .path
+        .map(|v| v.to_string())
+        .ok_or(Error::MalformedHead)?;
+
+    Ok(Some(RequestHead {
+        version,
+        method,
+        target,
+        headers: header_map(httparse_req.headers)?,
+    }))
+}
 
-    if Some("CONNECT") != httparse_req.method {
-        trace!("method is not connect");
-        return Ok(Some((http::StatusCode::METHOD_NOT_ALLOWED, None)));
+fn header_map(headers: &[httparse::Header<'_>]) -> Result<HeaderMap, Error> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for header in headers {
+        let name =
+            HeaderName::from_bytes(header.name.as_bytes()).map_err(|_| Error::MalformedHead)?;
+        let value = HeaderValue::from_bytes(header.value).map_err(|_| Error::MalformedHead)?;
+        map.append(name, value);
     }
+    Ok(map)
+}
+
+/// The head of a response received from an upstream proxy.
+#[derive(Debug)]
+pub(crate) struct ResponseHead {
+    pub(crate) version: http::Version,
+    pub(crate) status: http::StatusCode,
+    pub(crate) headers: HeaderMap,
+}
 
-    if Some(HTTP_1_1_VERSION) != httparse_req.version {
-        trace!("http version is not 1.1");
-        return Ok(Some((http::StatusCode::HTTP_VERSION_NOT_SUPPORTED, None)));
+pub(crate) async fn parse_response<R>(reader: &mut R) -> Result<Option<ResponseHead>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let buf = match read_head(reader, MAX_HEAD_LENGTH).await? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut httparse_res = Response::new(&mut headers);
+
+    debug!("check parse status");
+    if httparse_res.parse(&buf)?.is_partial() {
+        return Err(Error::MalformedHead);
+    }
+
+    let version = http_version(httparse_res.version).ok_or(httparse::Error::Version)?;
+    let status = httparse_res
+        .code
+        .and_then(|v| http::StatusCode::from_u16(v).ok())
+        .ok_or(httparse::Error::Status)?;
+
+    Ok(Some(ResponseHead {
+        version,
+        status,
+        headers: header_map(httparse_res.headers)?,
+    }))
+}
+
+/// Returns whether the connection stays open after the current message.
+pub(crate) fn is_keep_alive(version: http::Version, headers: &HeaderMap) -> bool {
+    // Proxy-Connection is the pre-standard spelling some clients still send.
+    let has_token = |token: &str| {
+        headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .chain(headers.get_all("proxy-connection").iter())
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+
+    if version == http::Version::HTTP_10 {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
     }
+}
 
-    let proxy_auth = httparse_req
-        .headers
+/// The maximum length of a chunk-size or trailer line.
+const MAX_CHUNK_LINE_LENGTH: usize = 4 * 1024;
+
+/// How the end of a message body is found, see RFC 9112 section 6.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyLength {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// The body ends when the sender closes the connection.
+    CloseDelimited,
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TRANSFER_ENCODING)
         .iter()
-        .find(|x| x.name.eq_ignore_ascii_case("proxy-authorization"))
-        .and_then(|x| std::str::from_utf8(x.value).ok());
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .last()
+        .map(|v| v.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
 
-    match (proxy_auth, auth) {
-        (Some(a), Some(b)) => {
-            if a != b {
-                return Ok(Some((http::StatusCode::UNAUTHORIZED, None)));
-            }
-        }
-        (None, Some(_)) => {
-            return Ok(Some((
-                http::StatusCode::PROXY_AUTHENTICATION_REQUIRED,
-                None,
-            )));
+fn content_length(headers: &HeaderMap) -> Result<Option<u64>, Error> {
+    let mut length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        let value = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .ok_or(Error::MalformedBody)?;
+        if length.is_some_and(|v| v != value) {
+            return Err(Error::MalformedBody);
         }
-        (_, None) => {}
+        length = Some(value);
     }
+    Ok(length)
+}
 
-    let host = httparse_req
-        .headers
-        .iter()
-        .find(|x| x.name.eq_ignore_ascii_case("host"))
-        .and_then(|x| std::str::from_utf8(x.value).ok())
-        .map(|v| v.to_string());
+/// Returns the body length of a request, a request without `Content-Length`
+/// or `Transfer-Encoding` has no body. A request with both is rejected, as
+/// allowed by RFC 9112 section 6.3, since it may be an attempt at request
+/// smuggling.
+pub(crate) fn request_body_length(headers: &HeaderMap) -> Result<BodyLength, Error> {
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return if headers.contains_key(header::CONTENT_LENGTH) {
+            Err(Error::MalformedBody)
+        } else if is_chunked(headers) {
+            Ok(BodyLength::Chunked)
+        } else {
+            Err(Error::MalformedBody)
+        };
+    }
 
-    // TODO: Skip body
+    match content_length(headers)? {
+        Some(0) | None => Ok(BodyLength::Empty),
+        Some(length) => Ok(BodyLength::Length(length)),
+    }
+}
+
+/// Returns the body length of a response to a `method` request.
+pub(crate) fn response_body_length(
+    method: &http::Method,
+    status: http::StatusCode,
+    headers: &HeaderMap,
+) -> Result<BodyLength, Error> {
+    if method == http::Method::HEAD
+        || status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED
+    {
+        return Ok(BodyLength::Empty);
+    }
 
-    Ok(Some((http::StatusCode::OK, host)))
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return if is_chunked(headers) {
+            Ok(BodyLength::Chunked)
+        } else {
+            Ok(BodyLength::CloseDelimited)
+        };
+    }
+
+    match content_length(headers)? {
+        Some(0) => Ok(BodyLength::Empty),
+        Some(length) => Ok(BodyLength::Length(length)),
+        None => Ok(BodyLength::CloseDelimited),
+    }
 }
 
-pub(crate) async fn parse_response<R>(reader: &mut R) -> Result<Option<http::StatusCode>, Error>
+/// Reads a line of at most `MAX_CHUNK_LINE_LENGTH` bytes, including the LF.
+async fn read_line<R>(reader: &mut R) -> Result<Vec<u8>, Error>
 where
     R: AsyncBufRead + Unpin,
 {
-    let mut buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
-    let mut httparse_res = Response::new(&mut headers);
-
+    let mut line = Vec::new();
     loop {
-        let bytes_read = reader.read_until(LF, &mut buf).await?;
-        if bytes_read == 0 {
-            return Ok(None);
-        }
-
-        debug!("read {} bytes, total {} bytes", bytes_read, buf.len());
-        assert!(
-            buf.len() < MAX_HEAD_LENGTH,
-            "Head byte length should be less than 8kb"
-        );
-
-        let idx = buf.len() - 1;
-        if idx >= 3 && &buf[idx - 3..] == b"\r\n\r\n" {
-            break;
+        let (done, used) = {
+            let available = reader.fill_buf().await?;
+            if available.is_empty() {
+                return Err(Error::MalformedBody);
+            }
+            let (done, used) = match available.iter().position(|b| *b == LF) {
+                Some(idx) => (true, idx + 1),
+                None => (false, available.len()),
+            };
+            if line.len() + used > MAX_CHUNK_LINE_LENGTH {
+                return Err(Error::MalformedBody);
+            }
+            line.extend_from_slice(&available[..used]);
+            (done, used)
+        };
+        reader.consume(used);
+        if done {
+            return Ok(line);
         }
+    }
+}
 
-        if idx >= 1 && buf[idx - 1..=idx] == [LF, LF] {
-            break;
+/// Copies `length` bytes, or everything until EOF if `length` is `None`.
+async fn copy_bytes<R, W>(reader: &mut R, writer: &mut W, length: Option<u64>) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut remaining = length.unwrap_or(u64::MAX);
+    while remaining > 0 {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return match length {
+                Some(_) => Err(Error::MalformedBody),
+                None => Ok(()),
+            };
         }
+        let used = available
+            .len()
+            .min(remaining.min(usize::MAX as u64) as usize);
+        writer.write_all(&available[..used]).await?;
+        reader.consume(used);
+        remaining -= used as u64;
     }
+    Ok(())
+}
 
-    debug!("check parse status");
-    let status = httparse_res.parse(&buf)?;
-    assert!(!status.is_partial(), "Malformed HTTP head");
+/// Streams a message body from `reader` to `writer`. A chunked body is
+/// relayed with its framing unless `dechunk` is set, in which case only the
+/// chunk data is written and the trailer section is dropped.
+pub(crate) async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+    dechunk: bool,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Empty => Ok(()),
+        BodyLength::Length(length) => copy_bytes(reader, writer, Some(length)).await,
+        BodyLength::CloseDelimited => copy_bytes(reader, writer, None).await,
+        BodyLength::Chunked => {
+            loop {
+                let line = read_line(reader).await?;
+                let size = std::str::from_utf8(&line)
+                    .ok()
+                    .and_then(|v| v.split(';').next())
+                    .and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+                    .ok_or(Error::MalformedBody)?;
+                if !dechunk {
+                    writer.write_all(&line).await?;
+                }
+                if size == 0 {
+                    break;
+                }
 
-    if Some(HTTP_1_1_VERSION) != httparse_res.version {
-        debug!("http version is not 1.1");
-        return Ok(None);
-    }
+                copy_bytes(reader, writer, Some(size)).await?;
+                let line = read_line(reader).await?;
+                if line != b"\r\n" && line != b"\n" {
+                    return Err(Error::MalformedBody);
+                }
+                if !dechunk {
+                    writer.write_all(&line).await?;
+                }
+            }
 
-    // TODO: Skip body
+            // Trailer section ends with an empty line.
+            loop {
+                let line = read_line(reader).await?;
+                if !dechunk {
+                    writer.write_all(&line).await?;
+                }
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
 
-    let status_code = httparse_res
-        .code
-        .and_then(|v| http::StatusCode::from_u16(v).ok());
-    Ok(status_code)
+/// Reads and discards the body framed by `headers`, a message without
+/// `Content-Length` or `Transfer-Encoding` is treated as having no body.
+pub(crate) async fn skip_body<R>(reader: &mut R, headers: &HeaderMap) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let length = request_body_length(headers)?;
+    copy_body(reader, &mut tokio::io::sink(), length, true).await
 }
 
-pub(crate) fn encode_request(host: &str, port: u16, headers: &HeaderMap, buf: &mut BytesMut) {
-    let request_line = format!("CONNECT {}:{} HTTP/1.1\r\n", host, port);
+pub(crate) fn encode_request(target: &Address, headers: &HeaderMap, buf: &mut BytesMut) {
+    let request_line = format!("CONNECT {} HTTP/1.1\r\n", target);
     buf.reserve(request_line.len());
     buf.put_slice(request_line.as_bytes());
-    let host = format!("Host: {}:{}\r\n", host, port);
+    let host = format!("Host: {}\r\n", target);
     buf.reserve(host.len());
     buf.put_slice(host.as_bytes());
+    encode_headers(headers, buf);
+}
+
+/// Encodes the head of a request forwarded to an origin server, `target` is
+/// the request-target in origin-form.
+pub(crate) fn encode_request_head(
+    method: &http::Method,
+    target: &str,
+    headers: &HeaderMap,
+    buf: &mut BytesMut,
+) {
+    let request_line = format!("{} {} HTTP/1.1\r\n", method, target);
+    buf.reserve(request_line.len());
+    buf.put_slice(request_line.as_bytes());
+    encode_headers(headers, buf);
+}
+
+pub(crate) fn encode_response(
+    version: http::Version,
+    status: http::StatusCode,
+    headers: &HeaderMap,
+    buf: &mut BytesMut,
+) {
+    let status_line = format!(
+        "{:?} {} {}\r\n",
+        version,
+        status.as_str(),
+        status.canonical_reason().unwrap_or("")
+    );
+    buf.reserve(status_line.len());
+    buf.put_slice(status_line.as_bytes());
+    encode_headers(headers, buf);
+}
+
+/// Writes a response head without a body and flushes it.
+pub(crate) async fn respond<T>(
+    io: &mut T,
+    version: http::Version,
+    status: http::StatusCode,
+    headers: &HeaderMap,
+) -> Result<(), Error>
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    encode_response(version, status, headers, &mut buf);
+    io.write_all_buf(&mut buf).await?;
+    io.flush().await?;
+    Ok(())
+}
+
+/// Writes the header fields and the empty line ending the head section.
+fn encode_headers(headers: &HeaderMap, buf: &mut BytesMut) {
     for (k, v) in headers.iter() {
         let name = k.as_str();
         let value = v.as_bytes();
@@ -160,20 +472,111 @@ pub(crate) fn encode_request(host: &str, port: u16, headers: &HeaderMap, buf: &m
     buf.put_slice(b"\r\n");
 }
 
-pub(crate) fn encode_response(status: http::StatusCode, buf: &mut BytesMut) {
-    let status_line = format!(
-        "HTTP/1.1 {} {}\r\n",
-        status.as_str(),
-        status.canonical_reason().unwrap()
-    );
-    buf.reserve(status_line.len());
-    buf.put_slice(status_line.as_bytes());
-    if status == http::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
-        const STR: &str = "Proxy-Authenticate: Basic realm=Proxy Server\r\n";
-        buf.reserve(STR.len());
-        buf.put_slice(STR.as_bytes());
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use http::{header, HeaderMap, HeaderValue};
+
+    use super::{
+        encode_response, parse_request, parse_response, request_body_length, skip_body, BodyLength,
+        MAX_HEADERS, MAX_HEAD_LENGTH,
+    };
+    use crate::Error;
+
+    #[tokio::test]
+    async fn test_parse_request_http_1_0() {
+        let mut reader: &[u8] = b"CONNECT example.com:443 HTTP/1.0\r\n\r\n";
+        let head = parse_request(&mut reader, MAX_HEAD_LENGTH, MAX_HEADERS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.version, http::Version::HTTP_10);
+        assert_eq!(head.method, http::Method::CONNECT);
+        assert_eq!(head.target, "example.com:443");
+
+        let mut buf = BytesMut::new();
+        encode_response(
+            head.version,
+            http::StatusCode::OK,
+            &HeaderMap::new(),
+            &mut buf,
+        );
+        assert_eq!(&buf[..], b"HTTP/1.0 200 OK\r\n\r\n");
     }
 
-    buf.reserve(2);
-    buf.put_slice(b"\r\n");
+    #[tokio::test]
+    async fn test_parse_response_http_1_0() {
+        let mut reader: &[u8] = b"HTTP/1.0 200 Connection established\r\n\r\n";
+        let head = parse_response(&mut reader).await.unwrap().unwrap();
+        assert_eq!(head.version, http::Version::HTTP_10);
+        assert_eq!(head.status, http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_parse_request_too_large() {
+        let head = format!(
+            "CONNECT example.com:443 HTTP/1.1\r\nX-Pad: {}\r\n\r\n",
+            "a".repeat(64)
+        );
+        let mut reader = head.as_bytes();
+        let err = parse_request(&mut reader, 32, MAX_HEADERS)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HeadTooLarge));
+
+        let mut reader = head.as_bytes();
+        let err = parse_request(&mut reader, MAX_HEAD_LENGTH, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Httparse(httparse::Error::TooManyHeaders)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_parse_request_malformed() {
+        let mut reader: &[u8] = b"CONNECT\r\n\r\n";
+        let err = parse_request(&mut reader, MAX_HEAD_LENGTH, MAX_HEADERS)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Httparse(_)));
+    }
+
+    #[tokio::test]
+    async fn test_skip_body() {
+        let mut reader: &[u8] =
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 200 OK\r\n\r\n";
+        let head = parse_response(&mut reader).await.unwrap().unwrap();
+        skip_body(&mut reader, &head.headers).await.unwrap();
+        let head = parse_response(&mut reader).await.unwrap().unwrap();
+        assert_eq!(head.status, http::StatusCode::OK);
+
+        let mut reader: &[u8] =
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\nHTTP/1.1 200 OK\r\n\r\n";
+        let head = parse_response(&mut reader).await.unwrap().unwrap();
+        skip_body(&mut reader, &head.headers).await.unwrap();
+        let head = parse_response(&mut reader).await.unwrap().unwrap();
+        assert_eq!(head.status, http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_request_body_length() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(5));
+        assert_eq!(
+            request_body_length(&headers).unwrap(),
+            BodyLength::Length(5)
+        );
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        assert!(matches!(
+            request_body_length(&headers),
+            Err(Error::MalformedBody)
+        ));
+        headers.remove(header::CONTENT_LENGTH);
+        assert_eq!(request_body_length(&headers).unwrap(), BodyLength::Chunked);
+    }
 }
@@ -0,0 +1,586 @@
+//! HTTP Digest access authentication as described in RFC 7616.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::{
+        constant_time_eq, parse_challenges, quote, AuthContext, Challenge, Credentials, Identity,
+        ProxyAuthenticator, UNKNOWN_USER_PASSWORD,
+    },
+    Error,
+};
+
+/// How long an issued nonce is accepted unless configured otherwise.
+const DEFAULT_NONCE_TTL: Duration = Duration::from_secs(300);
+
+/// The length of the timestamp prefix of a server nonce.
+const NONCE_TIMESTAMP_LENGTH: usize = 8;
+
+/// The length of the random part that follows the timestamp, so that two
+/// nonces issued in the same second differ.
+const NONCE_RANDOM_LENGTH: usize = 8;
+
+/// The length of the truncated MAC suffix of a server nonce.
+const NONCE_MAC_LENGTH: usize = 16;
+
+/// The hash algorithm of a Digest challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    Md5,
+    Md5Sess,
+    #[default]
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        [
+            Algorithm::Md5,
+            Algorithm::Md5Sess,
+            Algorithm::Sha256,
+            Algorithm::Sha256Sess,
+        ]
+        .into_iter()
+        .find(|v| v.as_str().eq_ignore_ascii_case(name))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Md5Sess => "MD5-sess",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn is_session(&self) -> bool {
+        matches!(self, Algorithm::Md5Sess | Algorithm::Sha256Sess)
+    }
+
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Algorithm::Md5 | Algorithm::Md5Sess => hex(&Md5::digest(data.as_bytes())),
+            Algorithm::Sha256 | Algorithm::Sha256Sess => hex(&Sha256::digest(data.as_bytes())),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The parameters that go into the `response` of a Digest authorization.
+struct Request<'a> {
+    algorithm: Algorithm,
+    username: &'a str,
+    realm: &'a str,
+    password: &'a str,
+    nonce: &'a str,
+    nc: &'a str,
+    cnonce: &'a str,
+    qop: Option<&'a str>,
+    method: &'a str,
+    uri: &'a str,
+}
+
+impl Request<'_> {
+    fn response(&self) -> String {
+        let algorithm = self.algorithm;
+        let mut ha1 = algorithm.hash(&format!(
+            "{}:{}:{}",
+            self.username, self.realm, self.password
+        ));
+        if algorithm.is_session() {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, self.cnonce));
+        }
+        let ha2 = algorithm.hash(&format!("{}:{}", self.method, self.uri));
+        match self.qop {
+            Some(qop) => algorithm.hash(&format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, self.nonce, self.nc, self.cnonce, qop, ha2
+            )),
+            None => algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        }
+    }
+}
+
+/// A Digest challenge the client knows how to answer.
+#[derive(Debug, Clone)]
+pub(crate) struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    qop: bool,
+    pub(crate) stale: bool,
+}
+
+impl DigestChallenge {
+    /// Picks the strongest supported Digest challenge.
+    pub(crate) fn select(challenges: &[Challenge]) -> Option<Self> {
+        challenges
+            .iter()
+            .filter(|c| c.scheme == "digest")
+            .filter_map(Self::from_challenge)
+            .max_by_key(|c| matches!(c.algorithm, Algorithm::Sha256 | Algorithm::Sha256Sess))
+    }
+
+    fn from_challenge(challenge: &Challenge) -> Option<Self> {
+        let algorithm = match challenge.param("algorithm") {
+            Some(name) => Algorithm::parse(name)?,
+            None => Algorithm::Md5,
+        };
+        let qop = match challenge.param("qop") {
+            Some(qop) => {
+                // Only `auth` is supported, `auth-int` would need the body.
+                if !qop
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case("auth"))
+                {
+                    return None;
+                }
+                true
+            }
+            None => false,
+        };
+
+        Some(Self {
+            realm: challenge.param("realm")?.to_string(),
+            nonce: challenge.param("nonce")?.to_string(),
+            opaque: challenge.param("opaque").map(|v| v.to_string()),
+            algorithm,
+            qop,
+            stale: challenge
+                .param("stale")
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
+    }
+
+    /// Builds the `Proxy-Authorization` value answering this challenge,
+    /// `nc` counts the requests sent with the same nonce.
+    pub(crate) fn authorization(
+        &self,
+        method: &str,
+        uri: &str,
+        username: &str,
+        password: &str,
+        nc: u32,
+    ) -> String {
+        let cnonce = hex(&rand::random::<[u8; 16]>());
+        self.authorization_with_cnonce(method, uri, username, password, nc, &cnonce)
+    }
+
+    fn authorization_with_cnonce(
+        &self,
+        method: &str,
+        uri: &str,
+        username: &str,
+        password: &str,
+        nc: u32,
+        cnonce: &str,
+    ) -> String {
+        let nc = format!("{:08x}", nc);
+        let qop = if self.qop { Some("auth") } else { None };
+        let response = Request {
+            algorithm: self.algorithm,
+            username,
+            realm: &self.realm,
+            password,
+            nonce: &self.nonce,
+            nc: &nc,
+            cnonce,
+            qop,
+            method,
+            uri,
+        }
+        .response();
+
+        let mut value = format!(
+            "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response={}",
+            quote(username),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            self.algorithm.as_str(),
+            quote(&response),
+        );
+        if let Some(qop) = qop {
+            value.push_str(&format!(
+                ", qop={}, nc={}, cnonce={}",
+                qop,
+                nc,
+                quote(cnonce)
+            ));
+        }
+        if let Some(opaque) = &self.opaque {
+            value.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        value
+    }
+}
+
+enum Nonce {
+    Valid(u64),
+    Expired,
+    Invalid,
+}
+
+/// Issues Digest challenges and verifies the answers against a fixed set of
+/// users. Nonces are stateless and expire after a configurable time, the
+/// nonce count of each live nonce is tracked to reject replays.
+pub struct DigestUsers {
+    users: HashMap<String, String>,
+    algorithm: Algorithm,
+    nonce_ttl: Duration,
+    secret: [u8; 32],
+    counts: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl Default for DigestUsers {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            algorithm: Algorithm::default(),
+            nonce_ttl: DEFAULT_NONCE_TTL,
+            secret: rand::random(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl DigestUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(mut self, username: &str, password: &str) -> Self {
+        self.users
+            .insert(username.to_string(), password.to_string());
+        self
+    }
+
+    /// Sets the hash algorithm offered in challenges, defaults to SHA-256.
+    pub fn set_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Sets how long an issued nonce is accepted.
+    pub fn set_nonce_ttl(mut self, nonce_ttl: Duration) -> Self {
+        self.nonce_ttl = nonce_ttl;
+        self
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key length");
+        mac.update(data);
+        mac.finalize().into_bytes()[..NONCE_MAC_LENGTH].to_vec()
+    }
+
+    fn issue_nonce(&self) -> String {
+        let mut nonce = Self::now().to_be_bytes().to_vec();
+        nonce.extend_from_slice(&rand::random::<[u8; NONCE_RANDOM_LENGTH]>());
+        nonce.extend_from_slice(&self.mac(&nonce));
+        base64::engine::general_purpose::STANDARD.encode(nonce)
+    }
+
+    fn check_nonce(&self, nonce: &str) -> Nonce {
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(nonce) {
+            Ok(decoded)
+                if decoded.len()
+                    == NONCE_TIMESTAMP_LENGTH + NONCE_RANDOM_LENGTH + NONCE_MAC_LENGTH =>
+            {
+                decoded
+            }
+            _ => return Nonce::Invalid,
+        };
+        let (data, mac) = decoded.split_at(NONCE_TIMESTAMP_LENGTH + NONCE_RANDOM_LENGTH);
+        if !constant_time_eq(&self.mac(data), mac) {
+            return Nonce::Invalid;
+        }
+
+        let timestamp = &data[..NONCE_TIMESTAMP_LENGTH];
+        let issued = u64::from_be_bytes(timestamp.try_into().expect("timestamp length"));
+        if Self::now().saturating_sub(issued) > self.nonce_ttl.as_secs() {
+            Nonce::Expired
+        } else {
+            Nonce::Valid(issued)
+        }
+    }
+
+    /// Records `nc` for `nonce`, returning false if it was already used.
+    fn count(&self, nonce: &str, issued: u64, nc: u32) -> bool {
+        let now = Self::now();
+        let ttl = self.nonce_ttl.as_secs();
+        let mut counts = self.counts.lock().unwrap();
+        counts.retain(|_, (issued, _)| now.saturating_sub(*issued) <= ttl);
+        let last = counts.entry(nonce.to_string()).or_insert((issued, 0));
+        if nc <= last.1 {
+            return false;
+        }
+        last.1 = nc;
+        true
+    }
+}
+
+impl fmt::Debug for DigestUsers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DigestUsers")
+            .field("users", &self.users.keys().collect::<Vec<_>>())
+            .field("algorithm", &self.algorithm)
+            .field("nonce_ttl", &self.nonce_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses the auth-params of Digest credentials.
+fn digest_params(credentials: &Credentials) -> Option<Challenge> {
+    match credentials {
+        Credentials::Other { scheme, token } if scheme == "digest" => {
+            // Credentials share the auth-param syntax of challenges.
+            parse_challenges(&format!("Digest {}", token))
+                .into_iter()
+                .next()
+        }
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl ProxyAuthenticator for DigestUsers {
+    async fn authenticate(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: &Credentials,
+    ) -> Result<Option<Identity>, Error> {
+        let params = match digest_params(credentials) {
+            Some(params) => params,
+            None => return Ok(None),
+        };
+
+        let param = |name| params.param(name).unwrap_or_default();
+        let (username, nonce, nc, cnonce) = (
+            param("username"),
+            param("nonce"),
+            param("nc"),
+            param("cnonce"),
+        );
+        let algorithm = params.param("algorithm").unwrap_or("MD5");
+        if param("realm") != context.realm
+            || param("uri") != context.target
+            || Algorithm::parse(algorithm) != Some(self.algorithm)
+            || !param("qop").eq_ignore_ascii_case("auth")
+        {
+            return Ok(None);
+        }
+
+        let issued = match self.check_nonce(nonce) {
+            Nonce::Valid(issued) => issued,
+            Nonce::Expired | Nonce::Invalid => return Ok(None),
+        };
+        let count = match u32::from_str_radix(nc, 16) {
+            Ok(count) if nc.len() == 8 => count,
+            _ => return Ok(None),
+        };
+
+        // An unknown user is checked against a dummy password, so that the
+        // time taken does not tell whether the user exists.
+        let password = self.users.get(username);
+        let expected = Request {
+            algorithm: self.algorithm,
+            username,
+            realm: context.realm,
+            password: password.map_or(UNKNOWN_USER_PASSWORD, String::as_str),
+            nonce,
+            nc,
+            cnonce,
+            qop: Some("auth"),
+            method: context.method.as_str(),
+            uri: context.target,
+        }
+        .response();
+        let matched = constant_time_eq(expected.as_bytes(), param("response").as_bytes());
+        if !matched || password.is_none() {
+            return Ok(None);
+        }
+
+        if !self.count(nonce, issued, count) {
+            return Ok(None);
+        }
+
+        Ok(Some(Identity::new(username)))
+    }
+
+    fn challenges(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: Option<&Credentials>,
+    ) -> Vec<String> {
+        let stale = credentials
+            .and_then(digest_params)
+            .and_then(|params| params.param("nonce").map(|nonce| self.check_nonce(nonce)))
+            .map(|nonce| matches!(nonce, Nonce::Expired))
+            .unwrap_or(false);
+
+        let mut challenge = format!(
+            "Digest realm={}, qop=\"auth\", algorithm={}, nonce={}",
+            quote(context.realm),
+            self.algorithm.as_str(),
+            quote(&self.issue_nonce()),
+        );
+        if stale {
+            challenge.push_str(", stale=true");
+        }
+        vec![challenge]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Algorithm, DigestChallenge, DigestUsers};
+    use crate::auth::{
+        parse_challenges, AuthContext, Credentials, ProxyAuthenticator, UNKNOWN_USER_PASSWORD,
+    };
+
+    /// The example of RFC 7616 section 3.9.1.
+    fn rfc_challenge(algorithm: &str) -> DigestChallenge {
+        let challenges = parse_challenges(&format!(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm={}, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+            algorithm
+        ));
+        DigestChallenge::select(&challenges).unwrap()
+    }
+
+    #[test]
+    fn test_rfc_7616_example() {
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let md5 = rfc_challenge("MD5").authorization_with_cnonce(
+            "GET",
+            "/dir/index.html",
+            "Mufasa",
+            "Circle of Life",
+            1,
+            cnonce,
+        );
+        assert!(md5.contains(r#"response="8ca523f5e9506fed4657c9700eebdbec""#));
+        assert!(md5.contains("nc=00000001"));
+        assert!(md5.contains(r#"opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#));
+
+        let sha256 = rfc_challenge("SHA-256").authorization_with_cnonce(
+            "GET",
+            "/dir/index.html",
+            "Mufasa",
+            "Circle of Life",
+            1,
+            cnonce,
+        );
+        assert!(sha256.contains(
+            r#"response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1""#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_digest_users() {
+        let users = DigestUsers::new()
+            .add_user("Mufasa", "Circle of Life")
+            .set_algorithm(Algorithm::Md5);
+        let context = AuthContext {
+            method: &http::Method::CONNECT,
+            target: "example.com:443",
+            realm: "zodiac",
+        };
+
+        let challenges = parse_challenges(&users.challenges(&context, None)[0]);
+        let challenge = DigestChallenge::select(&challenges).unwrap();
+        let authorize = |nc, password| {
+            let value =
+                challenge.authorization("CONNECT", "example.com:443", "Mufasa", password, nc);
+            Credentials::parse(value.as_bytes()).unwrap()
+        };
+
+        let identity = users
+            .authenticate(&context, &authorize(1, "Circle of Life"))
+            .await
+            .unwrap();
+        assert_eq!(identity.unwrap().name(), "Mufasa");
+
+        // Replayed nonce count.
+        assert!(users
+            .authenticate(&context, &authorize(1, "Circle of Life"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(users
+            .authenticate(&context, &authorize(2, "Circle of Life"))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(users
+            .authenticate(&context, &authorize(3, "wrong"))
+            .await
+            .unwrap()
+            .is_none());
+
+        // An unknown user is rejected even with the dummy password.
+        let value = challenge.authorization(
+            "CONNECT",
+            "example.com:443",
+            "nobody",
+            UNKNOWN_USER_PASSWORD,
+            4,
+        );
+        let credentials = Credentials::parse(value.as_bytes()).unwrap();
+        assert!(users
+            .authenticate(&context, &credentials)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_digest_nonce_expiry() {
+        let users = DigestUsers::new()
+            .add_user("Mufasa", "Circle of Life")
+            .set_nonce_ttl(Duration::ZERO);
+        let context = AuthContext {
+            method: &http::Method::CONNECT,
+            target: "example.com:443",
+            realm: "zodiac",
+        };
+
+        let challenges = parse_challenges(&users.challenges(&context, None)[0]);
+        let challenge = DigestChallenge::select(&challenges).unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let value =
+            challenge.authorization("CONNECT", "example.com:443", "Mufasa", "Circle of Life", 1);
+        let credentials = Credentials::parse(value.as_bytes()).unwrap();
+        assert!(users
+            .authenticate(&context, &credentials)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(users.challenges(&context, Some(&credentials))[0].ends_with("stale=true"));
+    }
+}
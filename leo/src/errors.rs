@@ -8,9 +8,67 @@ pub enum Error {
     #[error("http parse error: {0}")]
     Httparse(#[from] httparse::Error),
 
+    #[error("invalid header name: {0}")]
+    InvalidHeaderName(#[from] http::header::InvalidHeaderName),
+
+    #[error("invalid header value: {0}")]
+    InvalidHeaderValue(#[from] http::header::InvalidHeaderValue),
+
+    #[error("http2 error: {0}")]
+    H2(#[from] h2::Error),
+
+    #[cfg(feature = "http3")]
+    #[error("http3 connection error: {0}")]
+    H3Connection(#[from] h3::error::ConnectionError),
+
+    #[cfg(feature = "http3")]
+    #[error("http3 stream error: {0}")]
+    H3Stream(#[from] h3::error::StreamError),
+
+    #[cfg(feature = "http3")]
+    #[error("send datagram error: {0}")]
+    SendDatagram(#[from] quinn::SendDatagramError),
+
+    #[cfg(feature = "rustls")]
+    #[error("rustls error: {0}")]
+    Rustls(#[from] rustls::Error),
+
+    #[cfg(feature = "native-tls")]
+    #[error("native tls error: {0}")]
+    NativeTls(#[from] tokio_native_tls::native_tls::Error),
+
+    #[cfg(feature = "mitm")]
+    #[error("certificate error: {0}")]
+    Certificate(#[from] rcgen::Error),
+
+    #[error("proxy protocol error: {0}")]
+    ProxyProtocol(aries::Error),
+
+    #[error("tls error: {0}")]
+    Tls(&'static str),
+
+    #[error("http head too large")]
+    HeadTooLarge,
+
+    #[error("malformed http head")]
+    MalformedHead,
+
+    #[error("malformed http body")]
+    MalformedBody,
+
     #[error("http status: {0}")]
     HttpStatus(&'static str),
 
     #[error("http error: {0}")]
     Http(&'static str),
 }
+
+/// The TLS errors of the shared rustls helpers keep their own variant.
+impl From<aries::Error> for Error {
+    fn from(e: aries::Error) -> Self {
+        match e {
+            aries::Error::Tls(reason) => Error::Tls(reason),
+            e => Error::ProxyProtocol(e),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Forwarding of absolute-form requests to origin servers.
+
+use std::net::SocketAddr;
+
+use aries::upstream::Stream;
+use bytes::BytesMut;
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use log::{debug, trace};
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::{
+    codec::{
+        copy_body, encode_request_head, encode_response, is_keep_alive, parse_response,
+        request_body_length, respond, response_body_length, BodyLength, RequestHead, ResponseHead,
+    },
+    pool::Pool,
+    rewrite::Rewrite,
+    Error,
+};
+
+/// The port of an `http` URI without an explicit port.
+const DEFAULT_HTTP_PORT: u16 = 80;
+
+/// Header fields that only apply to a single connection, see RFC 9110
+/// section 7.6.1. `Transfer-Encoding` is kept because bodies are relayed
+/// with their framing.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Removes the hop-by-hop fields, including those listed in `Connection`.
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| HeaderName::from_bytes(v.trim().as_bytes()).ok())
+        .collect();
+
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(*name);
+    }
+    for name in listed {
+        headers.remove(name);
+    }
+}
+
+/// The origin server addressed by an absolute-form request-target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Origin {
+    /// The host to dial, without the brackets of an IPv6 literal.
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// The value of the `Host` header sent to the origin.
+    pub(crate) authority: String,
+    /// The request-target in origin-form.
+    pub(crate) path: String,
+}
+
+impl Origin {
+    /// Parses an absolute-form `http` request-target.
+    pub(crate) fn parse(target: &str) -> Option<Self> {
+        let uri: http::Uri = target.parse().ok()?;
+        if uri.scheme() != Some(&http::uri::Scheme::HTTP) {
+            return None;
+        }
+
+        let authority = uri.authority()?;
+        let host = authority.host();
+        if host.is_empty() {
+            return None;
+        }
+        let authority = match authority.port_u16() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let path = uri
+            .path_and_query()
+            .map(|v| v.as_str())
+            .filter(|v| v.starts_with('/'))
+            .unwrap_or("/")
+            .to_string();
+
+        Some(Self {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: uri.port_u16().unwrap_or(DEFAULT_HTTP_PORT),
+            authority,
+            path,
+        })
+    }
+}
+
+/// A connection to an origin server, the read buffer must be empty before
+/// the connection goes back to the pool.
+type Upstream = BufReader<BufWriter<Stream>>;
+
+/// Forwards one request to its origin server and relays the response.
+/// Returns whether the client connection can be used for another request.
+///
+/// The origin connection is taken from `pool` and returned to it once the
+/// response has been relayed, if the origin keeps it alive. Both messages
+/// are rewritten by `rewrite`, `remote_addr` is the address of the client.
+pub(crate) async fn forward<T>(
+    io: &mut T,
+    head: RequestHead,
+    pool: &Pool,
+    rewrite: &Rewrite,
+    remote_addr: Option<SocketAddr>,
+) -> Result<bool, Error>
+where
+    T: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let close = || {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+        headers
+    };
+
+    let origin = Origin::parse(&head.target);
+    let request_length = request_body_length(&head.headers);
+    let (origin, request_length) = match (origin, request_length) {
+        (Some(origin), Ok(length)) => (origin, length),
+        _ => {
+            trace!("reject request to {}", head.target);
+            respond(io, head.version, http::StatusCode::BAD_REQUEST, &close()).await?;
+            return Ok(false);
+        }
+    };
+
+    let client_keep_alive = is_keep_alive(head.version, &head.headers);
+    let expect_continue = head
+        .headers
+        .get(header::EXPECT)
+        .map(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+        .unwrap_or(false);
+
+    let mut headers = head.headers;
+    strip_hop_by_hop(&mut headers);
+    headers.remove(header::EXPECT);
+    headers.insert(
+        header::HOST,
+        HeaderValue::try_from(origin.authority.as_str())?,
+    );
+    rewrite.request(head.version, remote_addr, &mut headers)?;
+
+    let mut buf = BytesMut::new();
+    encode_request_head(&head.method, &origin.path, &headers, &mut buf);
+    let request = buf.split().freeze();
+
+    let mut continued = false;
+    let mut fresh = false;
+    let (mut upstream, resp) = loop {
+        trace!("connect to {}:{}", origin.host, origin.port);
+        let connected = if fresh {
+            pool.dial(&origin.host, origin.port, remote_addr)
+                .await
+                .map(|v| (v, false))
+        } else {
+            pool.connect(&origin.host, origin.port, remote_addr).await
+        };
+        let (stream, reused) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                debug!("failed to connect to {}: {}", origin.authority, e);
+                respond(io, head.version, http::StatusCode::BAD_GATEWAY, &close()).await?;
+                return Ok(false);
+            }
+        };
+        let mut upstream = BufReader::new(BufWriter::new(stream));
+
+        // The origin never sees `Expect`, the proxy invites the body itself.
+        if expect_continue && !continued {
+            respond(
+                io,
+                head.version,
+                http::StatusCode::CONTINUE,
+                &HeaderMap::new(),
+            )
+            .await?;
+            continued = true;
+        }
+
+        let sent = async {
+            upstream.write_all(&request).await?;
+            copy_body(io, &mut upstream, request_length, false).await?;
+            upstream.flush().await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        let resp = match sent {
+            Ok(()) => read_response(&mut upstream).await,
+            Err(e) => Err(e),
+        };
+
+        match resp {
+            Ok(Some(resp)) => break (upstream, resp),
+            // The origin may have closed a pooled connection just as it was
+            // reused, an idempotent request without a body is safe to send
+            // again, see RFC 9110 section 9.2.2.
+            _ if reused && request_length == BodyLength::Empty && is_idempotent(&head.method) => {
+                debug!("pooled connection to {} was closed", origin.authority);
+                fresh = true;
+            }
+            _ => {
+                debug!("invalid response from {}", origin.authority);
+                respond(io, head.version, http::StatusCode::BAD_GATEWAY, &close()).await?;
+                return Ok(false);
+            }
+        }
+    };
+
+    let length = response_body_length(&head.method, resp.status, &resp.headers)?;
+    // An HTTP/1.0 client does not understand chunked framing.
+    let dechunk = length == BodyLength::Chunked && head.version == http::Version::HTTP_10;
+    let keep_alive = client_keep_alive && length != BodyLength::CloseDelimited && !dechunk;
+    let reusable =
+        is_keep_alive(resp.version, &resp.headers) && length != BodyLength::CloseDelimited;
+
+    let mut headers = resp.headers;
+    strip_hop_by_hop(&mut headers);
+    rewrite.response(resp.version, &mut headers)?;
+    // `Transfer-Encoding` overrides `Content-Length`, which must not reach
+    // the client where it could frame the body differently.
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        headers.remove(header::CONTENT_LENGTH);
+    }
+    if dechunk {
+        headers.remove(header::TRANSFER_ENCODING);
+    }
+    if !keep_alive {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+    } else if head.version == http::Version::HTTP_10 {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+    }
+
+    encode_response(head.version, resp.status, &headers, &mut buf);
+    io.write_all_buf(&mut buf).await?;
+    copy_body(&mut upstream, io, length, dechunk).await?;
+    io.flush().await?;
+
+    // Bytes past the response mean the origin does not frame its messages
+    // as expected, such a connection is not reused.
+    if reusable && upstream.buffer().is_empty() {
+        pool.checkin(
+            &origin.host,
+            origin.port,
+            upstream.into_inner().into_inner(),
+        );
+    }
+    Ok(keep_alive)
+}
+
+/// Whether a request may be sent again after the connection failed, the
+/// response to the first attempt being unknown.
+fn is_idempotent(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET
+            | http::Method::HEAD
+            | http::Method::OPTIONS
+            | http::Method::TRACE
+            | http::Method::PUT
+            | http::Method::DELETE
+    )
+}
+
+/// Reads the final response, skipping interim `1xx` responses. Returns
+/// `None` if the origin closed the connection or answered with
+/// `101 Switching Protocols`.
+async fn read_response(upstream: &mut Upstream) -> Result<Option<ResponseHead>, Error> {
+    loop {
+        match parse_response(&mut *upstream).await? {
+            Some(resp) if resp.status == http::StatusCode::SWITCHING_PROTOCOLS => return Ok(None),
+            Some(resp) if resp.status.is_informational() => continue,
+            resp => return Ok(resp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use super::{is_idempotent, strip_hop_by_hop, Origin};
+
+    #[test]
+    fn test_origin() {
+        let origin = Origin::parse("http://example.com/a/b?c=d").unwrap();
+        assert_eq!(origin.host, "example.com");
+        assert_eq!(origin.port, 80);
+        assert_eq!(origin.authority, "example.com");
+        assert_eq!(origin.path, "/a/b?c=d");
+
+        let origin = Origin::parse("http://[::1]:8080").unwrap();
+        assert_eq!(origin.host, "::1");
+        assert_eq!(origin.port, 8080);
+        assert_eq!(origin.authority, "[::1]:8080");
+        assert_eq!(origin.path, "/");
+
+        assert!(Origin::parse("https://example.com/").is_none());
+        assert!(Origin::parse("/index.html").is_none());
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("close, x-hop"));
+        headers.insert("proxy-connection", HeaderValue::from_static("keep-alive"));
+        headers.insert("proxy-authorization", HeaderValue::from_static("Basic x"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("x-end", HeaderValue::from_static("1"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        strip_hop_by_hop(&mut headers);
+        let mut names: Vec<_> = headers.keys().map(|v| v.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["transfer-encoding", "x-end"]);
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&http::Method::GET));
+        assert!(is_idempotent(&http::Method::DELETE));
+        assert!(!is_idempotent(&http::Method::POST));
+        assert!(!is_idempotent(&http::Method::PATCH));
+    }
+}
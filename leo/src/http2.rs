@@ -0,0 +1,260 @@
+//! `CONNECT` tunnels carried by the streams of an HTTP/2 connection, see
+//! RFC 9113 section 8.5, and the extended `CONNECT` of RFC 8441.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use h2::{server::SendResponse, RecvStream, SendStream};
+use http::{header, HeaderMap, HeaderValue};
+use log::{debug, trace};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    auth::Identity,
+    codec::RequestHead,
+    server::{Authorization, Builder},
+    Error,
+};
+
+/// Opens an HTTP/2 connection to a proxy over `io`. The connection is driven
+/// by a spawned task, tunnels are opened on it with
+/// [`crate::client::Builder::handshake_h2`].
+pub async fn connect<T>(io: T) -> Result<h2::client::SendRequest<Bytes>, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, connection) = h2::client::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("http2 connection error: {}", e);
+        }
+    });
+    Ok(send_request)
+}
+
+/// A tunnel established on one HTTP/2 stream.
+pub struct Tunnel {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    buf: Bytes,
+    protocol: Option<String>,
+    path: Option<String>,
+}
+
+impl Tunnel {
+    pub(crate) fn new(
+        send: SendStream<Bytes>,
+        recv: RecvStream,
+        protocol: Option<String>,
+        path: Option<String>,
+    ) -> Self {
+        Self {
+            send,
+            recv,
+            buf: Bytes::new(),
+            protocol,
+            path,
+        }
+    }
+
+    /// The `:protocol` of an extended `CONNECT`, such as `websocket`.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// The `:path` of an extended `CONNECT`.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.buf.is_empty() {
+            match ready!(self.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    self.recv
+                        .flow_control()
+                        .release_capacity(data.len())
+                        .map_err(into_io)?;
+                    self.buf = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = self.buf.len().min(buf.remaining());
+        buf.put_slice(&self.buf[..n]);
+        self.buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        self.send.reserve_capacity(buf.len());
+        let n = match ready!(self.send.poll_capacity(cx)) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        self.send
+            .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+            .map_err(into_io)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the stream, the peer may keep sending.
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.send.send_data(Bytes::new(), true).map_err(into_io)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Accepts the tunnels requested on an HTTP/2 connection, see
+/// [`Builder::handshake_h2`].
+pub struct Acceptor<T> {
+    connection: h2::server::Connection<T, Bytes>,
+    builder: Builder,
+}
+
+impl<T> Acceptor<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) async fn new(io: T, builder: Builder) -> Result<Self, Error> {
+        let connection = h2::server::Builder::new()
+            .enable_connect_protocol()
+            .handshake(io)
+            .await?;
+        Ok(Self {
+            connection,
+            builder,
+        })
+    }
+
+    /// Accepts the next authorized `CONNECT` request, returning the tunnel,
+    /// the requested target and the identity of the user. Returns `None`
+    /// once the client closes the connection.
+    ///
+    /// The connection, including every tunnel already accepted, only makes
+    /// progress while this method is polled, so it should be called in a
+    /// loop on its own task.
+    pub async fn accept(&mut self) -> Result<Option<(Tunnel, String, Option<Identity>)>, Error> {
+        loop {
+            let (request, respond) = match self.connection.accept().await {
+                Some(accepted) => accepted?,
+                None => return Ok(None),
+            };
+            // An error on one stream leaves the connection and the other
+            // streams usable.
+            match self.answer(request, respond).await {
+                Ok(Some(accepted)) => return Ok(Some(accepted)),
+                Ok(None) => {}
+                Err(e) => debug!("failed to answer stream: {}", e),
+            }
+        }
+    }
+
+    /// Answers a request, returning the tunnel if it was accepted.
+    async fn answer(
+        &self,
+        request: http::Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> Result<Option<(Tunnel, String, Option<Identity>)>, Error> {
+        let (parts, recv) = request.into_parts();
+        if parts.method != http::Method::CONNECT {
+            trace!("method is not connect");
+            send_status(&mut respond, http::StatusCode::METHOD_NOT_ALLOWED, None)?;
+            return Ok(None);
+        }
+        let target = match parts.uri.authority() {
+            Some(authority) => authority.to_string(),
+            None => {
+                send_status(&mut respond, http::StatusCode::BAD_REQUEST, None)?;
+                return Ok(None);
+            }
+        };
+        let protocol = parts
+            .extensions
+            .get::<h2::ext::Protocol>()
+            .map(|v| v.as_str().to_string());
+        let path = protocol
+            .as_ref()
+            .and(parts.uri.path_and_query())
+            .map(|v| v.to_string());
+
+        let head = RequestHead {
+            version: http::Version::HTTP_2,
+            method: parts.method,
+            target,
+            headers: parts.headers,
+        };
+        match self.builder.authorize(&head).await? {
+            Authorization::Granted(identity) => {
+                trace!("accept tunnel to {}", head.target);
+                let response = http::Response::new(());
+                let send = respond.send_response(response, false)?;
+                let tunnel = Tunnel::new(send, recv, protocol, path);
+                Ok(Some((tunnel, head.target, identity)))
+            }
+            Authorization::Challenge(challenges) => {
+                trace!("proxy authentication required");
+                let mut headers = HeaderMap::new();
+                for challenge in challenges {
+                    headers.append(
+                        header::PROXY_AUTHENTICATE,
+                        HeaderValue::try_from(challenge)?,
+                    );
+                }
+                let status = http::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+                send_status(&mut respond, status, Some(headers))?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Answers a request with a response without a body.
+fn send_status(
+    respond: &mut SendResponse<Bytes>,
+    status: http::StatusCode,
+    headers: Option<HeaderMap>,
+) -> Result<(), Error> {
+    let mut response = http::Response::new(());
+    *response.status_mut() = status;
+    if let Some(headers) = headers {
+        *response.headers_mut() = headers;
+    }
+    respond.send_response(response, true)?;
+    Ok(())
+}
+
+fn into_io(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        e.into_io().unwrap()
+    } else {
+        io::Error::other(e)
+    }
+}
@@ -1,6 +1,19 @@
+pub mod auth;
 pub mod client;
 mod codec;
+pub mod digest;
+mod forward;
+pub mod http2;
+#[cfg(feature = "http3")]
+pub mod masque;
+#[cfg(feature = "mitm")]
+pub mod mitm;
+mod ntlm;
+pub mod pool;
+pub mod rewrite;
 pub mod server;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub mod tls;
 
 mod errors;
 pub use errors::Error;
@@ -0,0 +1,570 @@
+//! Proxying UDP in HTTP/3 with `CONNECT-UDP`, see RFC 9298. The UDP
+//! payloads travel as HTTP Datagrams (RFC 9297) on the QUIC connection,
+//! tagged with the request stream they belong to.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::ConnectionState;
+use http::{header, HeaderMap, HeaderValue};
+use log::{debug, trace};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::Identity,
+    codec::RequestHead,
+    server::{Authorization, Builder},
+    Error,
+};
+
+/// The path of the default URI template, followed by the target host and
+/// port.
+pub(crate) const WELL_KNOWN_PATH: &str = "/.well-known/masque/udp/";
+
+/// The header announcing the Capsule Protocol of RFC 9297 section 3.
+pub(crate) const CAPSULE_PROTOCOL: &str = "capsule-protocol";
+
+/// The context ID of a datagram carrying a whole UDP payload.
+const UDP_PAYLOAD_CONTEXT: u64 = 0;
+
+/// The type of a capsule carrying an HTTP Datagram, see RFC 9297 section
+/// 3.5.
+const DATAGRAM_CAPSULE: u64 = 0x00;
+
+/// The largest capsule buffered, a UDP payload with its context ID fits.
+const MAX_CAPSULE_LENGTH: usize = 1 << 17;
+
+/// The number of received payloads queued for a tunnel, more are dropped
+/// until it reads them as with plain UDP.
+const MAX_QUEUED_PAYLOADS: usize = 1024;
+
+/// The request stream of a tunnel.
+enum Stream {
+    Client(h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>),
+    Server(h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>),
+}
+
+/// The sending half of a request stream, only held so that the tunnel is
+/// closed with it.
+#[allow(dead_code)]
+enum SendHalf {
+    Client(h3::client::RequestStream<h3_quinn::SendStream<Bytes>, Bytes>),
+    Server(h3::server::RequestStream<h3_quinn::SendStream<Bytes>, Bytes>),
+}
+
+enum RecvHalf {
+    Client(h3::client::RequestStream<h3_quinn::RecvStream, Bytes>),
+    Server(h3::server::RequestStream<h3_quinn::RecvStream, Bytes>),
+}
+
+impl Stream {
+    fn split(self) -> (SendHalf, RecvHalf) {
+        match self {
+            Stream::Client(stream) => {
+                let (send, recv) = stream.split();
+                (SendHalf::Client(send), RecvHalf::Client(recv))
+            }
+            Stream::Server(stream) => {
+                let (send, recv) = stream.split();
+                (SendHalf::Server(send), RecvHalf::Server(recv))
+            }
+        }
+    }
+}
+
+impl RecvHalf {
+    async fn recv_data(&mut self) -> Result<Option<Bytes>, Error> {
+        let data = match self {
+            RecvHalf::Client(stream) => stream
+                .recv_data()
+                .await?
+                .map(|mut v| v.copy_to_bytes(v.remaining())),
+            RecvHalf::Server(stream) => stream
+                .recv_data()
+                .await?
+                .map(|mut v| v.copy_to_bytes(v.remaining())),
+        };
+        Ok(data)
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<u64, mpsc::Sender<Bytes>>>>;
+
+/// Dispatches the datagrams received on a QUIC connection to the tunnels,
+/// by the quarter stream ID that prefixes each of them.
+#[derive(Clone)]
+struct Datagrams {
+    connection: quinn::Connection,
+    sessions: Sessions,
+}
+
+impl Datagrams {
+    fn new(connection: quinn::Connection) -> Self {
+        let sessions: Sessions = Arc::default();
+        let reader = connection.clone();
+        let dispatch = sessions.clone();
+        tokio::spawn(async move {
+            while let Ok(mut datagram) = reader.read_datagram().await {
+                let (quarter, context) =
+                    match (decode_varint(&mut datagram), decode_varint(&mut datagram)) {
+                        (Some(quarter), Some(context)) => (quarter, context),
+                        _ => {
+                            debug!("malformed http datagram");
+                            continue;
+                        }
+                    };
+                if context != UDP_PAYLOAD_CONTEXT {
+                    trace!("drop datagram with context {}", context);
+                    continue;
+                }
+                if let Some(session) = dispatch.lock().unwrap().get(&quarter) {
+                    if session.try_send(datagram).is_err() {
+                        trace!("drop datagram of a full tunnel");
+                    }
+                }
+            }
+            dispatch.lock().unwrap().clear();
+        });
+        Self {
+            connection,
+            sessions,
+        }
+    }
+
+    /// Starts collecting the datagrams of a request stream, before its
+    /// response is sent or received so that none is lost.
+    fn register(&self, stream_id: u64) -> (u64, mpsc::Receiver<Bytes>) {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED_PAYLOADS);
+        let quarter = stream_id / 4;
+        self.sessions.lock().unwrap().insert(quarter, tx);
+        (quarter, rx)
+    }
+
+    /// Creates the tunnel of a request stream whose response was sent or
+    /// received, the capsules of the stream are read by a spawned task.
+    fn tunnel(&self, (quarter, rx): (u64, mpsc::Receiver<Bytes>), stream: Stream) -> UdpTunnel {
+        let (send, recv) = stream.split();
+        let session = self.sessions.lock().unwrap().get(&quarter).cloned();
+        let reader = tokio::spawn(async move {
+            if let Some(session) = session {
+                read_capsules(recv, session).await;
+            }
+        });
+        UdpTunnel {
+            datagrams: self.clone(),
+            quarter,
+            rx,
+            reader,
+            _stream: send,
+        }
+    }
+}
+
+/// A UDP tunnel established by a `CONNECT-UDP` request, each datagram
+/// carries one UDP payload.
+pub struct UdpTunnel {
+    datagrams: Datagrams,
+    quarter: u64,
+    rx: mpsc::Receiver<Bytes>,
+    reader: tokio::task::JoinHandle<()>,
+    _stream: SendHalf,
+}
+
+impl UdpTunnel {
+    /// Sends one UDP payload, it is dropped if the peer is out of buffer
+    /// space as with plain UDP.
+    pub fn send(&self, payload: &[u8]) -> Result<(), Error> {
+        let mut datagram = BytesMut::with_capacity(payload.len() + 9);
+        encode_varint(self.quarter, &mut datagram);
+        encode_varint(UDP_PAYLOAD_CONTEXT, &mut datagram);
+        datagram.put_slice(payload);
+        self.datagrams.connection.send_datagram(datagram.freeze())?;
+        Ok(())
+    }
+
+    /// Receives the next UDP payload, `None` once the connection is closed.
+    /// Up to 1024 payloads are queued, those received meanwhile are dropped.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for UdpTunnel {
+    fn drop(&mut self) {
+        self.datagrams
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.quarter);
+        self.reader.abort();
+    }
+}
+
+/// Reads the capsules of a request stream until it ends, see RFC 9297
+/// section 3.2. The UDP payloads of `DATAGRAM` capsules are sent to
+/// `session` like those of HTTP Datagrams, other capsules are skipped.
+async fn read_capsules(mut recv: RecvHalf, session: mpsc::Sender<Bytes>) {
+    let mut buf = BytesMut::new();
+    loop {
+        while let Some((capsule_type, mut value)) = decode_capsule(&mut buf) {
+            if capsule_type != DATAGRAM_CAPSULE {
+                trace!("skip capsule of type {}", capsule_type);
+                continue;
+            }
+            match decode_varint(&mut value) {
+                Some(UDP_PAYLOAD_CONTEXT) => {
+                    if session.try_send(value).is_err() {
+                        trace!("drop capsule of a full tunnel");
+                    }
+                }
+                context => trace!("drop capsule with context {:?}", context),
+            }
+        }
+        if buf.len() > MAX_CAPSULE_LENGTH {
+            debug!("capsule too large");
+            return;
+        }
+        match recv.recv_data().await {
+            Ok(Some(data)) => buf.extend_from_slice(&data),
+            Ok(None) => return,
+            Err(e) => {
+                debug!("failed to read capsules: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Takes the type and value of the first capsule of `buf` once it is
+/// complete.
+fn decode_capsule(buf: &mut BytesMut) -> Option<(u64, Bytes)> {
+    let mut peek = &buf[..];
+    let capsule_type = decode_varint(&mut peek)?;
+    let length = usize::try_from(decode_varint(&mut peek)?).ok()?;
+    if peek.len() < length {
+        return None;
+    }
+    let header = buf.len() - peek.len();
+    buf.advance(header);
+    Some((capsule_type, buf.split_to(length).freeze()))
+}
+
+/// An HTTP/3 connection to a proxy, tunnels are opened on it with
+/// [`crate::client::Builder::handshake_udp`].
+///
+/// The QUIC connection must have negotiated the `h3` ALPN protocol and
+/// enabled datagrams.
+#[derive(Clone)]
+pub struct Client {
+    send_request: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+    datagrams: Datagrams,
+    authority: String,
+}
+
+impl Client {
+    /// Starts HTTP/3 on `connection` to the proxy at `authority`, the
+    /// connection is driven by a spawned task.
+    pub async fn new(connection: quinn::Connection, authority: &str) -> Result<Self, Error> {
+        let datagrams = Datagrams::new(connection.clone());
+        let (mut driver, send_request) = h3::client::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build(h3_quinn::Connection::new(connection))
+            .await?;
+        tokio::spawn(async move {
+            let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+            debug!("http3 connection closed: {}", e);
+        });
+        Ok(Self {
+            send_request,
+            datagrams,
+            authority: authority.to_string(),
+        })
+    }
+
+    /// Sends a `CONNECT-UDP` request for `host` and `port`, returning the
+    /// response and the tunnel the datagrams are read into.
+    pub(crate) async fn request(
+        &self,
+        host: &str,
+        port: u16,
+        authorization: Option<HeaderValue>,
+    ) -> Result<(http::Response<()>, UdpTunnel), Error> {
+        let uri = format!(
+            "https://{}{}{}/{}/",
+            self.authority,
+            WELL_KNOWN_PATH,
+            host.replace(':', "%3A"),
+            port
+        );
+        let mut request = http::Request::builder()
+            .method(http::Method::CONNECT)
+            .uri(uri)
+            .header(CAPSULE_PROTOCOL, "?1")
+            .extension(h3::ext::Protocol::CONNECT_UDP)
+            .body(())
+            .map_err(|_| Error::Http("invalid host"))?;
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(header::PROXY_AUTHORIZATION, authorization);
+        }
+
+        let mut send_request = self.send_request.clone();
+        let mut stream = send_request.send_request(request).await?;
+        let session = self.datagrams.register(stream.id().into_inner());
+        // The tunnel unregisters the session once dropped, even if the
+        // response never arrives.
+        let response = stream.recv_response().await;
+        let tunnel = self.datagrams.tunnel(session, Stream::Client(stream));
+        let response = response?;
+        // HTTP Datagrams are only sent to a peer that announced them, whose
+        // settings arrive before the response it sends.
+        if !self.send_request.settings().enable_datagram() {
+            return Err(Error::Http("proxy does not support http datagrams"));
+        }
+        Ok((response, tunnel))
+    }
+}
+
+/// Accepts the `CONNECT-UDP` requests of an HTTP/3 connection, see
+/// [`Builder::handshake_udp`].
+pub struct Acceptor {
+    connection: h3::server::Connection<h3_quinn::Connection, Bytes>,
+    datagrams: Datagrams,
+    builder: Builder,
+}
+
+impl Acceptor {
+    pub(crate) async fn new(
+        connection: quinn::Connection,
+        builder: Builder,
+    ) -> Result<Self, Error> {
+        let datagrams = Datagrams::new(connection.clone());
+        let connection = h3::server::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build(h3_quinn::Connection::new(connection))
+            .await?;
+        Ok(Self {
+            connection,
+            datagrams,
+            builder,
+        })
+    }
+
+    /// Accepts the next authorized `CONNECT-UDP` request, returning the
+    /// tunnel, the requested target as `host:port` and the identity of the
+    /// user. Returns `None` once the client closes the connection.
+    pub async fn accept(&mut self) -> Result<Option<(UdpTunnel, String, Option<Identity>)>, Error> {
+        loop {
+            let resolver = match self.connection.accept().await? {
+                Some(resolver) => resolver,
+                None => return Ok(None),
+            };
+            let (request, stream) = match resolver.resolve_request().await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    debug!("failed to read request: {}", e);
+                    continue;
+                }
+            };
+
+            // An error on one request stream leaves the connection and the
+            // other streams usable.
+            match self.answer(request, stream).await {
+                Ok(Some(accepted)) => return Ok(Some(accepted)),
+                Ok(None) => {}
+                Err(e) => debug!("failed to answer request: {}", e),
+            }
+        }
+    }
+
+    /// Answers a request, returning the tunnel if it was accepted.
+    async fn answer(
+        &self,
+        request: http::Request<()>,
+        mut stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    ) -> Result<Option<(UdpTunnel, String, Option<Identity>)>, Error> {
+        let (parts, _) = request.into_parts();
+        let protocol = parts.extensions.get::<h3::ext::Protocol>();
+        if parts.method != http::Method::CONNECT {
+            trace!("method is not connect");
+            send_status(&mut stream, http::StatusCode::METHOD_NOT_ALLOWED, None).await?;
+            return Ok(None);
+        }
+        let target = match (protocol, parse_path(parts.uri.path())) {
+            (Some(&h3::ext::Protocol::CONNECT_UDP), Some(target)) => target,
+            _ => {
+                trace!("reject request for {}", parts.uri);
+                send_status(&mut stream, http::StatusCode::BAD_REQUEST, None).await?;
+                return Ok(None);
+            }
+        };
+
+        let head = RequestHead {
+            version: http::Version::HTTP_3,
+            method: parts.method,
+            target,
+            headers: parts.headers,
+        };
+        match self.builder.authorize(&head).await? {
+            Authorization::Granted(identity) => {
+                trace!("accept udp tunnel to {}", head.target);
+                let session = self.datagrams.register(stream.id().into_inner());
+                let mut response = http::Response::new(());
+                response
+                    .headers_mut()
+                    .insert(CAPSULE_PROTOCOL, HeaderValue::from_static("?1"));
+                let sent = stream.send_response(response).await;
+                let tunnel = self.datagrams.tunnel(session, Stream::Server(stream));
+                sent?;
+                Ok(Some((tunnel, head.target, identity)))
+            }
+            Authorization::Challenge(challenges) => {
+                trace!("proxy authentication required");
+                let mut headers = HeaderMap::new();
+                for challenge in challenges {
+                    headers.append(
+                        header::PROXY_AUTHENTICATE,
+                        HeaderValue::try_from(challenge)?,
+                    );
+                }
+                let status = http::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+                send_status(&mut stream, status, Some(headers)).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Answers a request with a response without a body.
+async fn send_status(
+    stream: &mut h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    status: http::StatusCode,
+    headers: Option<HeaderMap>,
+) -> Result<(), Error> {
+    let mut response = http::Response::new(());
+    *response.status_mut() = status;
+    if let Some(headers) = headers {
+        *response.headers_mut() = headers;
+    }
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Formats the target of a `CONNECT-UDP` request like the target of a
+/// `CONNECT` request.
+pub(crate) fn target(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Parses the path of the default URI template,
+/// `/.well-known/masque/udp/{target_host}/{target_port}/`.
+fn parse_path(path: &str) -> Option<String> {
+    let mut segments = path.strip_prefix(WELL_KNOWN_PATH)?.split('/');
+    let host = segments.next()?.replace("%3A", ":").replace("%3a", ":");
+    let port = segments.next()?.parse().ok()?;
+    if host.is_empty() || segments.any(|v| !v.is_empty()) {
+        return None;
+    }
+    Some(target(&host, port))
+}
+
+/// Appends a QUIC variable-length integer, see RFC 9000 section 16.
+fn encode_varint(value: u64, buf: &mut BytesMut) {
+    if value < 1 << 6 {
+        buf.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        buf.put_u16(0x4000 | value as u16);
+    } else if value < 1 << 30 {
+        buf.put_u32(0x8000_0000 | value as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
+
+fn decode_varint<B>(buf: &mut B) -> Option<u64>
+where
+    B: Buf,
+{
+    if !buf.has_remaining() {
+        return None;
+    }
+    let length = 1 << (buf.chunk()[0] >> 6);
+    if buf.remaining() < length {
+        return None;
+    }
+    let mut value = (buf.get_u8() & 0x3f) as u64;
+    for _ in 1..length {
+        value = value << 8 | buf.get_u8() as u64;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{decode_capsule, decode_varint, encode_varint, parse_path};
+
+    #[test]
+    fn test_varint() {
+        // The examples of RFC 9000 appendix A.1.
+        for (value, encoded) in [
+            (
+                151_288_809_941_952_652,
+                &b"\xc2\x19\x7c\x5e\xff\x14\xe8\x8c"[..],
+            ),
+            (494_878_333, b"\x9d\x7f\x3e\x7d"),
+            (15_293, b"\x7b\xbd"),
+            (37, b"\x25"),
+        ] {
+            let mut buf = BytesMut::new();
+            encode_varint(value, &mut buf);
+            assert_eq!(&buf[..], encoded);
+            assert_eq!(decode_varint(&mut buf.freeze()), Some(value));
+        }
+        assert_eq!(decode_varint(&mut Bytes::from_static(b"\x7b")), None);
+    }
+
+    #[test]
+    fn test_decode_capsule() {
+        let mut buf = BytesMut::from(&b"\x00\x04\x00abc\x40\x41\x01"[..]);
+        let (capsule_type, value) = decode_capsule(&mut buf).unwrap();
+        assert_eq!(capsule_type, 0);
+        assert_eq!(&value[..], b"\x00abc");
+        // The second capsule is incomplete and left in the buffer.
+        assert_eq!(decode_capsule(&mut buf), None);
+        assert_eq!(&buf[..], b"\x40\x41\x01");
+        buf.extend_from_slice(b"x");
+        assert_eq!(
+            decode_capsule(&mut buf),
+            Some((0x41, Bytes::from_static(b"x")))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("/.well-known/masque/udp/192.0.2.6/443/").as_deref(),
+            Some("192.0.2.6:443")
+        );
+        assert_eq!(
+            parse_path("/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/").as_deref(),
+            Some("[2001:db8::42]:53")
+        );
+        assert_eq!(parse_path("/.well-known/masque/udp/example.com/x/"), None);
+        assert_eq!(parse_path("/.well-known/masque/udp//443/"), None);
+        assert_eq!(parse_path("/masque/example.com/443/"), None);
+    }
+}
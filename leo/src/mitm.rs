@@ -0,0 +1,552 @@
+//! TLS interception for debugging clients in staging. After a `CONNECT`,
+//! [`Interceptor::intercept`] terminates TLS with a certificate generated
+//! for the requested host and signed by a local CA, opens its own TLS
+//! connection to the origin and relays the HTTP/1.1 exchanges, which are
+//! given to an observer callback and recorded in a [`Har`] log.
+//!
+//! Clients must trust the CA. Bodies up to a size limit are buffered whole
+//! so that the observer sees them before they are relayed, larger ones are
+//! relayed as they arrive and recorded truncated. Protocol upgrades are not
+//! relayed.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue};
+use log::trace;
+use rcgen::{CertificateParams, DnType, Issuer, KeyPair};
+use rustls::{
+    crypto::ring,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+use crate::{
+    codec::{
+        copy_body, encode_request_head, encode_response, is_keep_alive, parse_request,
+        parse_response, request_body_length, respond, response_body_length, BodyLength,
+        MAX_HEADERS, MAX_HEAD_LENGTH,
+    },
+    forward::strip_hop_by_hop,
+    tls::rustls::TlsConnector,
+    Error,
+};
+
+mod har;
+
+pub use har::Har;
+
+/// How long a generated certificate is valid, clients refuse server
+/// certificates valid for more than 398 days.
+const LEAF_VALIDITY: time::Duration = time::Duration::days(365);
+
+/// The number of generated certificates kept unless configured otherwise.
+const DEFAULT_MAX_CERTIFICATES: usize = 1024;
+
+/// The largest body buffered unless configured otherwise.
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The local CA signing the certificates presented to clients.
+pub struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    /// The key of every generated certificate.
+    leaf_key: KeyPair,
+    max_certificates: usize,
+    configs: Mutex<Configs>,
+}
+
+/// The server configurations by host, the least recently used one is
+/// dropped once `max_certificates` are kept.
+#[derive(Default)]
+struct Configs {
+    /// The configuration and the time it was last used.
+    entries: HashMap<String, (Arc<rustls::ServerConfig>, u64)>,
+    clock: u64,
+}
+
+impl CertificateAuthority {
+    /// Uses the PEM encoded CA certificate and private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let cert = std::str::from_utf8(cert).map_err(|_| Error::Tls("invalid certificate"))?;
+        let key = std::str::from_utf8(key).map_err(|_| Error::Tls("invalid key"))?;
+        let issuer = Issuer::from_ca_cert_pem(cert, KeyPair::from_pem(key)?)?;
+        Ok(Self {
+            issuer,
+            leaf_key: KeyPair::generate()?,
+            max_certificates: DEFAULT_MAX_CERTIFICATES,
+            configs: Mutex::default(),
+        })
+    }
+
+    /// Sets how many generated certificates are kept for reuse, the least
+    /// recently used one is generated again when needed.
+    pub fn set_max_certificates(mut self, max_certificates: usize) -> Self {
+        self.max_certificates = max_certificates.max(1);
+        self
+    }
+
+    /// Loads the PEM encoded CA certificate and private key from files.
+    pub fn from_pem_files<P>(cert: P, key: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_pem(&std::fs::read(cert)?, &std::fs::read(key)?)
+    }
+
+    /// Returns the server configuration presenting a certificate for `host`.
+    fn server_config(&self, host: &str) -> Result<Arc<rustls::ServerConfig>, Error> {
+        let mut configs = self.configs.lock().unwrap();
+        configs.clock += 1;
+        let clock = configs.clock;
+        if let Some((config, used)) = configs.entries.get_mut(host) {
+            *used = clock;
+            return Ok(config.clone());
+        }
+
+        trace!("generate certificate for {}", host);
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + LEAF_VALIDITY;
+        let cert = params.signed_by(&self.leaf_key, &self.issuer)?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.leaf_key.serialize_der()));
+
+        let mut config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.der().clone()], key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+        if configs.entries.len() >= self.max_certificates {
+            let oldest = configs
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                configs.entries.remove(&oldest);
+            }
+        }
+        configs
+            .entries
+            .insert(host.to_string(), (config.clone(), clock));
+        Ok(config)
+    }
+}
+
+/// A request and its response as seen by the interceptor. The headers are
+/// those received, the bodies are without their chunked framing and cut at
+/// the limit set by [`Interceptor::set_max_body_size`].
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    /// When the request head was received.
+    pub started: SystemTime,
+
+    /// The time from the request head until the end of the response.
+    pub time: Duration,
+
+    /// The request, with an absolute `https` URI.
+    pub request: http::Request<Bytes>,
+
+    pub response: http::Response<Bytes>,
+
+    /// Whether the request body was larger than the limit.
+    pub request_truncated: bool,
+
+    /// Whether the response body was larger than the limit.
+    pub response_truncated: bool,
+}
+
+type Observer = Arc<dyn Fn(&Exchange) + Send + Sync>;
+
+/// Relays the decrypted exchanges of intercepted tunnels.
+#[derive(Clone)]
+pub struct Interceptor {
+    ca: Arc<CertificateAuthority>,
+    connector: TlsConnector,
+    observer: Option<Observer>,
+    har: Option<Har>,
+    max_body_size: usize,
+}
+
+impl Interceptor {
+    /// Signs certificates with `ca`, connections to origin servers are made
+    /// with `connector`.
+    pub fn new(ca: CertificateAuthority, connector: TlsConnector) -> Self {
+        Self {
+            ca: Arc::new(ca),
+            connector,
+            observer: None,
+            har: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Sets the largest body buffered before it is relayed, 1 MiB by
+    /// default. A larger body is relayed as it arrives, with chunked framing
+    /// or closing the connection to an HTTP/1.0 client, and only its first
+    /// `max_body_size` bytes are recorded. Its exchange is observed once
+    /// relayed.
+    pub fn set_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Calls `observer` with every exchange, before its response is relayed
+    /// to the client.
+    pub fn set_observer<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&Exchange) + Send + Sync + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Records every exchange in `har`, which is written to its file by
+    /// [`Har::flush`].
+    pub fn set_har(mut self, har: Har) -> Self {
+        self.har = Some(har);
+        self
+    }
+
+    /// Intercepts the tunnel `io` opened for `target`, in authority-form as
+    /// returned by [`crate::server::Builder::handshake`], until either side
+    /// closes the connection.
+    pub async fn intercept<T>(&self, io: T, target: &str) -> Result<(), Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority: http::uri::Authority =
+            target.parse().map_err(|_| Error::Http("invalid target"))?;
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = authority.port_u16().ok_or(Error::Http("invalid target"))?;
+
+        trace!("terminate tls for {}", host);
+        let config = self.ca.server_config(host)?;
+        let client = tokio_rustls::TlsAcceptor::from(config).accept(io).await?;
+        let mut client = BufStream::new(client);
+
+        trace!("connect to {}", authority);
+        let upstream = TcpStream::connect((host, port)).await?;
+        let upstream = self.connector.connect(host, upstream).await?;
+        let mut upstream = BufStream::new(upstream);
+
+        while self
+            .exchange(&mut client, &mut upstream, &authority)
+            .await?
+        {}
+        Ok(())
+    }
+
+    /// Relays one exchange, returns whether both connections can be used
+    /// for another one.
+    async fn exchange<C, U>(
+        &self,
+        client: &mut C,
+        upstream: &mut U,
+        authority: &http::uri::Authority,
+    ) -> Result<bool, Error>
+    where
+        C: AsyncBufRead + AsyncWrite + Unpin,
+        U: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        let head = match parse_request(&mut *client, MAX_HEAD_LENGTH, MAX_HEADERS).await? {
+            Some(head) => head,
+            None => return Ok(false),
+        };
+        let started = SystemTime::now();
+        let start = Instant::now();
+
+        let length = request_body_length(&head.headers)?;
+        if head
+            .headers
+            .get(header::EXPECT)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+        {
+            let status = http::StatusCode::CONTINUE;
+            respond(&mut *client, head.version, status, &HeaderMap::new()).await?;
+        }
+        let mut headers = head.headers.clone();
+        strip_hop_by_hop(&mut headers);
+        headers.remove(header::EXPECT);
+        let mut buf = BytesMut::new();
+        let mut streamed = headers.clone();
+        set_chunked(&mut streamed);
+        encode_request_head(&head.method, &head.target, &streamed, &mut buf);
+        let mut spill = Spill::new(
+            &mut *upstream,
+            buf.split().freeze(),
+            true,
+            self.max_body_size,
+        );
+        copy_body(&mut *client, &mut spill, length, true).await?;
+        let (body, request_truncated) = spill.finish().await?;
+        if !request_truncated {
+            if length != BodyLength::Empty {
+                set_content_length(&mut headers, body.len());
+            }
+            encode_request_head(&head.method, &head.target, &headers, &mut buf);
+            buf.extend_from_slice(&body);
+            upstream.write_all_buf(&mut buf).await?;
+            upstream.flush().await?;
+        }
+
+        let resp = loop {
+            match parse_response(&mut *upstream).await? {
+                Some(resp) if resp.status == http::StatusCode::SWITCHING_PROTOCOLS => {
+                    return Err(Error::Http("protocol upgrade"));
+                }
+                Some(resp) if resp.status.is_informational() => continue,
+                Some(resp) => break resp,
+                None => return Err(Error::Http("origin closed the connection")),
+            }
+        };
+        let length = response_body_length(&head.method, resp.status, &resp.headers)?;
+        let keep_alive = is_keep_alive(head.version, &head.headers)
+            && is_keep_alive(resp.version, &resp.headers)
+            && length != BodyLength::CloseDelimited;
+        let mut headers = resp.headers.clone();
+        strip_hop_by_hop(&mut headers);
+
+        // An HTTP/1.0 client gets a streamed body until the connection is
+        // closed.
+        let chunked = head.version != http::Version::HTTP_10;
+        let mut streamed = headers.clone();
+        if chunked {
+            set_chunked(&mut streamed);
+        } else {
+            streamed.remove(header::TRANSFER_ENCODING);
+            streamed.remove(header::CONTENT_LENGTH);
+        }
+        set_connection(&mut streamed, head.version, keep_alive && chunked);
+        encode_response(head.version, resp.status, &streamed, &mut buf);
+        let mut spill = Spill::new(
+            &mut *client,
+            buf.split().freeze(),
+            chunked,
+            self.max_body_size,
+        );
+        copy_body(&mut *upstream, &mut spill, length, true).await?;
+        let (resp_body, response_truncated) = spill.finish().await?;
+        let time = start.elapsed();
+
+        if length != BodyLength::Empty {
+            set_content_length(&mut headers, resp_body.len());
+        }
+        set_connection(&mut headers, head.version, keep_alive);
+        if !response_truncated {
+            encode_response(head.version, resp.status, &headers, &mut buf);
+            buf.extend_from_slice(&resp_body);
+        }
+
+        let uri = format!("https://{}{}", authority, head.target);
+        let mut request = http::Request::new(Bytes::from(body));
+        *request.method_mut() = head.method;
+        *request.uri_mut() = uri.parse().map_err(|_| Error::MalformedHead)?;
+        *request.version_mut() = head.version;
+        *request.headers_mut() = head.headers;
+        let mut response = http::Response::new(Bytes::from(resp_body));
+        *response.status_mut() = resp.status;
+        *response.version_mut() = resp.version;
+        *response.headers_mut() = resp.headers;
+        let exchange = Exchange {
+            started,
+            time,
+            request,
+            response,
+            request_truncated,
+            response_truncated,
+        };
+
+        if let Some(observer) = &self.observer {
+            observer(&exchange);
+        }
+        if let Some(har) = &self.har {
+            har.record(&exchange);
+        }
+
+        client.write_all_buf(&mut buf).await?;
+        client.flush().await?;
+        Ok(keep_alive && (chunked || !response_truncated))
+    }
+}
+
+/// Frames a streamed body with chunked encoding.
+fn set_chunked(headers: &mut HeaderMap) {
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+}
+
+fn set_connection(headers: &mut HeaderMap, version: http::Version, keep_alive: bool) {
+    if !keep_alive {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+    } else if version == http::Version::HTTP_10 {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+    }
+}
+
+/// Receives a body without its framing. The body is kept in memory while
+/// it fits in `limit`, then `head` and the body are written to `inner` as
+/// they arrive, in chunks if `chunked`, and only the first `limit` bytes
+/// are kept.
+struct Spill<'a, W> {
+    inner: &'a mut W,
+    head: Bytes,
+    chunked: bool,
+    limit: usize,
+    body: Vec<u8>,
+    streaming: bool,
+    /// The bytes accepted but not yet written to `inner`.
+    pending: BytesMut,
+}
+
+impl<'a, W> Spill<'a, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn new(inner: &'a mut W, head: Bytes, chunked: bool, limit: usize) -> Self {
+        Self {
+            inner,
+            head,
+            chunked,
+            limit,
+            body: Vec::new(),
+            streaming: false,
+            pending: BytesMut::new(),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if self.chunked {
+            self.pending
+                .extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+            self.pending.extend_from_slice(data);
+            self.pending.extend_from_slice(b"\r\n");
+        } else {
+            self.pending.extend_from_slice(data);
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut *self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends a streamed body, returning the body kept and whether it was
+    /// streamed.
+    async fn finish(mut self) -> io::Result<(Vec<u8>, bool)> {
+        if self.streaming {
+            if self.chunked {
+                self.pending.extend_from_slice(b"0\r\n\r\n");
+            }
+            std::future::poll_fn(|cx| self.poll_drain(cx)).await?;
+            self.inner.flush().await?;
+        }
+        Ok((self.body, self.streaming))
+    }
+}
+
+impl<W> AsyncWrite for Spill<'_, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.streaming {
+            if this.body.len() + buf.len() <= this.limit {
+                this.body.extend_from_slice(buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+            trace!("stream body larger than {} bytes", this.limit);
+            this.streaming = true;
+            this.pending.extend_from_slice(&this.head);
+            let body = std::mem::take(&mut this.body);
+            this.encode(&body);
+            this.body = body;
+        }
+        ready!(this.poll_drain(cx))?;
+        this.encode(buf);
+        let kept = buf.len().min(this.limit - this.body.len());
+        this.body.extend_from_slice(&buf[..kept]);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut *this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Frames a buffered body with `Content-Length`.
+fn set_content_length(headers: &mut HeaderMap, length: usize) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+    use super::CertificateAuthority;
+
+    #[test]
+    fn test_max_certificates() {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let key_pem = key.serialize_pem();
+        let ca = CertifiedIssuer::self_signed(params, key).unwrap();
+        let ca = CertificateAuthority::from_pem(ca.pem().as_bytes(), key_pem.as_bytes())
+            .unwrap()
+            .set_max_certificates(2);
+
+        let a = ca.server_config("a.example").unwrap();
+        ca.server_config("b.example").unwrap();
+        assert!(Arc::ptr_eq(&a, &ca.server_config("a.example").unwrap()));
+        // The least recently used certificate is dropped.
+        ca.server_config("c.example").unwrap();
+        let configs = ca.configs.lock().unwrap();
+        assert_eq!(configs.entries.len(), 2);
+        assert!(configs.entries.contains_key("a.example"));
+        assert!(!configs.entries.contains_key("b.example"));
+    }
+}
@@ -0,0 +1,247 @@
+//! The HTTP Archive 1.2 format, read by browser developer tools.
+
+use std::{
+    collections::VecDeque,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use base64::Engine;
+use http::{header, HeaderMap};
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+
+use super::Exchange;
+
+/// The comment of a body cut at the size limit of the interceptor.
+const TRUNCATED: &str = "truncated";
+
+/// The number of exchanges kept unless configured otherwise.
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// A HAR log of the latest exchanges, kept in memory and written to a file
+/// by [`Har::flush`]. Recording an exchange does no I/O, so it does not
+/// hold up the relayed connections.
+#[derive(Clone)]
+pub struct Har {
+    path: Arc<PathBuf>,
+    max_entries: usize,
+    entries: Arc<Mutex<VecDeque<Value>>>,
+}
+
+impl Har {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            path: Arc::new(path.into()),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Sets how many exchanges are kept, 1000 by default. The oldest one is
+    /// dropped when another is recorded.
+    pub fn set_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Returns the log with the exchanges recorded so far.
+    pub fn to_json(&self) -> Value {
+        log(&self.entries.lock().unwrap())
+    }
+
+    /// Replaces the file with the log of the exchanges recorded so far.
+    pub async fn flush(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.to_json())?;
+        tokio::fs::write(self.path.as_path(), json).await
+    }
+
+    pub(crate) fn record(&self, exchange: &Exchange) {
+        let entry = entry(exchange);
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry);
+        while entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+    }
+}
+
+fn log(entries: &VecDeque<Value>) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "entries": entries,
+        }
+    })
+}
+
+/// Converts an exchange to a HAR entry.
+pub(crate) fn entry(exchange: &Exchange) -> Value {
+    let request = &exchange.request;
+    let response = &exchange.response;
+    let started = time::OffsetDateTime::from(exchange.started)
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    let time = exchange.time.as_secs_f64() * 1000.0;
+
+    let query: Vec<Value> = request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let (name, value) = v.split_once('=').unwrap_or((v, ""));
+            json!({ "name": name, "value": value })
+        })
+        .collect();
+
+    let mut har_request = json!({
+        "method": request.method().as_str(),
+        "url": request.uri().to_string(),
+        "httpVersion": format!("{:?}", request.version()),
+        "cookies": [],
+        "headers": headers(request.headers()),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": request.body().len(),
+    });
+    if !request.body().is_empty() {
+        har_request["postData"] = json!({
+            "mimeType": mime_type(request.headers()),
+            "text": String::from_utf8_lossy(request.body()),
+        });
+        if exchange.request_truncated {
+            har_request["postData"]["comment"] = json!(TRUNCATED);
+        }
+    }
+
+    let mut content = json!({
+        "size": response.body().len(),
+        "mimeType": mime_type(response.headers()),
+    });
+    if !response.body().is_empty() {
+        match std::str::from_utf8(response.body()) {
+            Ok(text) => content["text"] = json!(text),
+            Err(_) => {
+                content["text"] =
+                    json!(base64::engine::general_purpose::STANDARD.encode(response.body()));
+                content["encoding"] = json!("base64");
+            }
+        }
+        if exchange.response_truncated {
+            content["comment"] = json!(TRUNCATED);
+        }
+    }
+
+    json!({
+        "startedDateTime": started,
+        "time": time,
+        "request": har_request,
+        "response": {
+            "status": response.status().as_u16(),
+            "statusText": response.status().canonical_reason().unwrap_or(""),
+            "httpVersion": format!("{:?}", response.version()),
+            "cookies": [],
+            "headers": headers(response.headers()),
+            "content": content,
+            "redirectURL": response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(""),
+            "headersSize": -1,
+            "bodySize": response.body().len(),
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": time, "receive": 0 },
+    })
+}
+
+fn headers(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(k, v)| json!({ "name": k.as_str(), "value": String::from_utf8_lossy(v.as_bytes()) }))
+        .collect()
+}
+
+fn mime_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use bytes::Bytes;
+
+    use super::{entry, Exchange, Har};
+
+    fn exchange() -> Exchange {
+        let request = http::Request::post("https://example.com/a?b=c&d")
+            .header("content-type", "text/plain")
+            .body(Bytes::from_static(b"hello"))
+            .unwrap();
+        let response = http::Response::builder()
+            .status(302)
+            .header("location", "/b")
+            .body(Bytes::from_static(&[0xff, 0x00]))
+            .unwrap();
+        Exchange {
+            started: SystemTime::UNIX_EPOCH + Duration::from_secs(86400),
+            time: Duration::from_millis(12),
+            request,
+            response,
+            request_truncated: false,
+            response_truncated: true,
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let entry = entry(&exchange());
+        assert_eq!(entry["startedDateTime"], "1970-01-02T00:00:00Z");
+        assert_eq!(entry["time"], 12.0);
+        assert_eq!(entry["request"]["method"], "POST");
+        assert_eq!(entry["request"]["url"], "https://example.com/a?b=c&d");
+        assert_eq!(entry["request"]["httpVersion"], "HTTP/1.1");
+        assert_eq!(entry["request"]["queryString"][0]["name"], "b");
+        assert_eq!(entry["request"]["queryString"][0]["value"], "c");
+        assert_eq!(entry["request"]["queryString"][1]["name"], "d");
+        assert_eq!(entry["request"]["postData"]["mimeType"], "text/plain");
+        assert_eq!(entry["request"]["postData"]["text"], "hello");
+        assert!(entry["request"]["postData"].get("comment").is_none());
+        assert_eq!(entry["response"]["status"], 302);
+        assert_eq!(entry["response"]["statusText"], "Found");
+        assert_eq!(entry["response"]["redirectURL"], "/b");
+        assert_eq!(entry["response"]["content"]["text"], "/wA=");
+        assert_eq!(entry["response"]["content"]["encoding"], "base64");
+        assert_eq!(entry["response"]["content"]["comment"], "truncated");
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let har = Har::new("unused.har").set_max_entries(2);
+        let mut exchange = exchange();
+        for status in [200, 201, 202] {
+            *exchange.response.status_mut() = http::StatusCode::from_u16(status).unwrap();
+            har.record(&exchange);
+        }
+        let log = har.to_json();
+        let entries = log["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["response"]["status"], 201);
+        assert_eq!(entries[1]["response"]["status"], 202);
+    }
+}
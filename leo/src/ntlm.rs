@@ -0,0 +1,345 @@
+//! The client side of NTLMv2 authentication as described in MS-NLMP.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+// Message Types
+const NEGOTIATE_MESSAGE: u32 = 0x01;
+const CHALLENGE_MESSAGE: u32 = 0x02;
+const AUTHENTICATE_MESSAGE: u32 = 0x03;
+
+// Negotiate Flags
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+// AV Pair IDs
+const MSV_AV_EOL: u16 = 0x0000;
+const MSV_AV_TIMESTAMP: u16 = 0x0007;
+
+/// The length of the fixed part of the AUTHENTICATE_MESSAGE.
+const AUTHENTICATE_HEADER_LENGTH: usize = 64;
+
+/// The number of 100ns intervals between 1601-01-01 and 1970-01-01.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// The credentials used to answer an NTLM challenge.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct NtlmCredentials {
+    pub(crate) domain: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) workstation: String,
+}
+
+impl fmt::Debug for NtlmCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtlmCredentials")
+            .field("domain", &self.domain)
+            .field("username", &self.username)
+            .field("workstation", &self.workstation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encodes the NEGOTIATE_MESSAGE that opens the exchange.
+///
+/// +-----------+-------------+----------------+--------------+-------------------+
+/// | SIGNATURE | MESSAGETYPE | NEGOTIATEFLAGS | DOMAINFIELDS | WORKSTATIONFIELDS |
+/// +-----------+-------------+----------------+--------------+-------------------+
+/// |     8     |      4      |       4        |      8       |         8         |
+/// +-----------+-------------+----------------+--------------+-------------------+
+pub(crate) fn negotiate_message() -> Vec<u8> {
+    let flags = NEGOTIATE_UNICODE
+        | NEGOTIATE_OEM
+        | REQUEST_TARGET
+        | NEGOTIATE_NTLM
+        | NEGOTIATE_ALWAYS_SIGN
+        | NEGOTIATE_EXTENDED_SESSIONSECURITY
+        | NEGOTIATE_128
+        | NEGOTIATE_56;
+
+    let mut buf = BytesMut::with_capacity(32);
+    buf.put_slice(SIGNATURE);
+    buf.put_u32_le(NEGOTIATE_MESSAGE);
+    buf.put_u32_le(flags);
+    // Neither a domain nor a workstation is supplied.
+    buf.put_bytes(0, 16);
+    buf.to_vec()
+}
+
+/// The CHALLENGE_MESSAGE sent by the server.
+///
+/// +-----------+-------------+------------------+----------------+-----------------+
+/// | SIGNATURE | MESSAGETYPE | TARGETNAMEFIELDS | NEGOTIATEFLAGS | SERVERCHALLENGE |
+/// +-----------+-------------+------------------+----------------+-----------------+
+/// |     8     |      4      |        8         |       4        |        8        |
+/// +-----------+-------------+------------------+----------------+-----------------+
+///
+/// followed by 8 reserved octets and the TARGETINFOFIELDS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChallengeMessage {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl ChallengeMessage {
+    pub(crate) fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 48 || &bytes[..8] != SIGNATURE {
+            return None;
+        }
+
+        let mut buf = &bytes[8..];
+        if buf.get_u32_le() != CHALLENGE_MESSAGE {
+            return None;
+        }
+        buf.advance(8);
+        let flags = buf.get_u32_le();
+        let mut server_challenge = [0; 8];
+        buf.copy_to_slice(&mut server_challenge);
+        buf.advance(8);
+
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 {
+            let len = buf.get_u16_le() as usize;
+            buf.advance(2);
+            let offset = buf.get_u32_le() as usize;
+            bytes.get(offset..offset.checked_add(len)?)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        Some(Self {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    /// Returns the MsvAvTimestamp of the target info, if any.
+    fn timestamp(&self) -> Option<u64> {
+        let mut buf = &self.target_info[..];
+        while buf.remaining() >= 4 {
+            let id = buf.get_u16_le();
+            let len = buf.get_u16_le() as usize;
+            if id == MSV_AV_EOL || buf.remaining() < len {
+                break;
+            }
+            if id == MSV_AV_TIMESTAMP && len == 8 {
+                return Some(buf.get_u64_le());
+            }
+            buf.advance(len);
+        }
+        None
+    }
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// NTOWFv2(Passwd, User, UserDom)
+fn ntowf_v2(credentials: &NtlmCredentials) -> [u8; 16] {
+    let nt_hash = Md4::digest(utf16le(&credentials.password));
+    let identity = utf16le(&format!(
+        "{}{}",
+        credentials.username.to_uppercase(),
+        credentials.domain
+    ));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+/// Computes the LmChallengeResponse and NtChallengeResponse of NTLMv2.
+fn challenge_responses(
+    credentials: &NtlmCredentials,
+    challenge: &ChallengeMessage,
+    client_challenge: &[u8; 8],
+    timestamp: u64,
+) -> (Vec<u8>, Vec<u8>) {
+    let key = ntowf_v2(credentials);
+
+    let mut temp = Vec::with_capacity(32 + challenge.target_info.len());
+    temp.extend_from_slice(&[0x01, 0x01, 0, 0, 0, 0, 0, 0]);
+    temp.extend_from_slice(&timestamp.to_le_bytes());
+    temp.extend_from_slice(client_challenge);
+    temp.extend_from_slice(&[0; 4]);
+    temp.extend_from_slice(&challenge.target_info);
+    temp.extend_from_slice(&[0; 4]);
+
+    let nt_proof = hmac_md5(&key, &[&challenge.server_challenge, &temp]);
+    let mut nt_response = nt_proof.to_vec();
+    nt_response.extend_from_slice(&temp);
+
+    // With a server timestamp the LMv2 response must be zeroed.
+    let lm_response = if challenge.timestamp().is_some() {
+        vec![0; 24]
+    } else {
+        let mut lm_response =
+            hmac_md5(&key, &[&challenge.server_challenge, client_challenge]).to_vec();
+        lm_response.extend_from_slice(client_challenge);
+        lm_response
+    };
+
+    (lm_response, nt_response)
+}
+
+/// Encodes the AUTHENTICATE_MESSAGE answering `challenge`.
+pub(crate) fn authenticate_message(
+    credentials: &NtlmCredentials,
+    challenge: &ChallengeMessage,
+) -> Vec<u8> {
+    let timestamp = challenge.timestamp().unwrap_or_else(|| {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        FILETIME_UNIX_EPOCH + since_epoch.as_nanos() as u64 / 100
+    });
+    encode_authenticate(credentials, challenge, &rand::random(), timestamp)
+}
+
+fn encode_authenticate(
+    credentials: &NtlmCredentials,
+    challenge: &ChallengeMessage,
+    client_challenge: &[u8; 8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let (lm_response, nt_response) =
+        challenge_responses(credentials, challenge, client_challenge, timestamp);
+
+    let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+    let encode = |s: &str| {
+        if unicode {
+            utf16le(s)
+        } else {
+            s.as_bytes().to_vec()
+        }
+    };
+    let flags = challenge.flags & !NEGOTIATE_TARGET_INFO | NEGOTIATE_NTLM;
+    let fields = [
+        lm_response,
+        nt_response,
+        encode(&credentials.domain),
+        encode(&credentials.username),
+        encode(&credentials.workstation),
+        // No key exchange, the EncryptedRandomSessionKey is empty.
+        Vec::new(),
+    ];
+
+    let mut buf = BytesMut::new();
+    buf.put_slice(SIGNATURE);
+    buf.put_u32_le(AUTHENTICATE_MESSAGE);
+    let mut offset = AUTHENTICATE_HEADER_LENGTH;
+    for field in &fields {
+        buf.put_u16_le(field.len() as u16);
+        buf.put_u16_le(field.len() as u16);
+        buf.put_u32_le(offset as u32);
+        offset += field.len();
+    }
+    buf.put_u32_le(flags);
+    for field in &fields {
+        buf.put_slice(field);
+    }
+    buf.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        challenge_responses, encode_authenticate, hmac_md5, negotiate_message, ntowf_v2,
+        ChallengeMessage, NtlmCredentials,
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The NTLMv2 example of MS-NLMP section 4.2.4.
+    fn credentials() -> NtlmCredentials {
+        NtlmCredentials {
+            domain: "Domain".to_string(),
+            username: "User".to_string(),
+            password: "Password".to_string(),
+            workstation: "COMPUTER".to_string(),
+        }
+    }
+
+    /// The CHALLENGE_MESSAGE of MS-NLMP section 4.2.4.3.
+    fn challenge() -> ChallengeMessage {
+        let bytes = hex("
+            4e544c4d 53535000 02000000 0c000c00 38000000 33828ae2 01234567 89abcdef
+            00000000 00000000 24002400 44000000 06007017 0000000f 53006500 72007600
+            65007200 02000c00 44006f00 6d006100 69006e00 01000c00 53006500 72007600
+            65007200 00000000
+        ");
+        ChallengeMessage::parse(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_ntowf_v2() {
+        assert_eq!(
+            ntowf_v2(&credentials()).to_vec(),
+            hex("0c868a403bfd7a93a3001ef22ef02e3f")
+        );
+    }
+
+    #[test]
+    fn test_challenge_responses() {
+        let challenge = challenge();
+        assert_eq!(challenge.server_challenge.to_vec(), hex("0123456789abcdef"));
+
+        let (lm, nt) = challenge_responses(&credentials(), &challenge, &[0xaa; 8], 0);
+        assert_eq!(lm, hex("86c35097ac9cec102554764a57cccc19 aaaaaaaaaaaaaaaa"));
+        assert_eq!(nt[..16], hex("68cd0ab851e51c96aabc927bebef6a1c"));
+
+        let session_base_key = hmac_md5(&ntowf_v2(&credentials()), &[&nt[..16]]);
+        assert_eq!(
+            session_base_key.to_vec(),
+            hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+        );
+    }
+
+    #[test]
+    fn test_messages() {
+        let negotiate = negotiate_message();
+        assert_eq!(&negotiate[..12], b"NTLMSSP\0\x01\0\0\0");
+
+        let challenge = challenge();
+        let message = encode_authenticate(&credentials(), &challenge, &[0xaa; 8], 0);
+        assert_eq!(&message[..12], b"NTLMSSP\0\x03\0\0\0");
+        // The NtChallengeResponse is the second payload field.
+        let len = u16::from_le_bytes([message[20], message[21]]) as usize;
+        let offset = u32::from_le_bytes(message[24..28].try_into().unwrap()) as usize;
+        assert_eq!(
+            message[offset..offset + 16],
+            hex("68cd0ab851e51c96aabc927bebef6a1c")
+        );
+        assert_eq!(len, 16 + 28 + challenge.target_info.len() + 4);
+    }
+}
@@ -0,0 +1,257 @@
+//! A pool of idle connections to origin servers, shared by every client
+//! connection served by the same [`crate::server::Builder`].
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aries::{
+    proxy_protocol::{ProxyHeader, Version},
+    upstream::{Connector, Stream},
+};
+use log::trace;
+
+/// The number of idle connections kept per origin unless configured otherwise.
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+
+/// How long a connection stays idle before it is closed unless configured
+/// otherwise.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+type IdleConnections = HashMap<(String, u16), Vec<Idle>>;
+
+struct Idle {
+    stream: Stream,
+    since: Instant,
+}
+
+/// A keyed pool of idle origin connections. Clones share the same idle
+/// connections.
+#[derive(Clone)]
+pub struct Pool {
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+    proxy_protocol: Option<Version>,
+    connector: Connector,
+    idle: Arc<Mutex<IdleConnections>>,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            proxy_protocol: None,
+            connector: Connector::default(),
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many idle connections are kept for each origin, zero
+    /// disables pooling.
+    pub fn set_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Sets how long a connection may stay idle before it is closed.
+    pub fn set_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sends a PROXY protocol header with the client address on every new
+    /// connection, for origins behind the proxy that expect one. Such a
+    /// connection belongs to one client and is never pooled.
+    pub fn set_proxy_protocol(mut self, version: Version) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Sets how origins are reached, such as over a Unix socket for the
+    /// hosts mapped to one.
+    pub fn set_connector(mut self, connector: Connector) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Returns the number of idle connections to `host` and `port`.
+    pub fn idle(&self, host: &str, port: u16) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.get(&(host.to_string(), port)).map_or(0, |v| v.len())
+    }
+
+    /// Returns an idle connection to the origin or opens a new one, the flag
+    /// is set if the connection was reused. `remote_addr` is the address of
+    /// the client.
+    pub(crate) async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<(Stream, bool)> {
+        if let Some(stream) = self.checkout(host, port) {
+            trace!("reuse connection to {}:{}", host, port);
+            return Ok((stream, true));
+        }
+        Ok((self.dial(host, port, remote_addr).await?, false))
+    }
+
+    /// Opens a new connection to the origin, bypassing the idle ones.
+    pub(crate) async fn dial(
+        &self,
+        host: &str,
+        port: u16,
+        remote_addr: Option<SocketAddr>,
+    ) -> io::Result<Stream> {
+        let mut stream = self.connector.connect(host, port).await?;
+        // A Unix socket origin is on the same host and needs no header.
+        if let (Some(version), Stream::Tcp(_)) = (self.proxy_protocol, &stream) {
+            // Without a known client the proxy reports itself.
+            let source = match remote_addr {
+                Some(remote_addr) => remote_addr,
+                None => stream.local_addr()?,
+            };
+            let header = ProxyHeader::new(source, stream.peer_addr()?);
+            trace!("send proxy protocol header {:?}", header);
+            header.write(&mut stream, version).await?;
+        }
+        Ok(stream)
+    }
+
+    fn checkout(&self, host: &str, port: u16) -> Option<Stream> {
+        if self.proxy_protocol.is_some() {
+            return None;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let key = (host.to_string(), port);
+        let conns = idle.get_mut(&key)?;
+        let mut found = None;
+        while let Some(conn) = conns.pop() {
+            if conn.since.elapsed() < self.idle_timeout && is_healthy(&conn.stream) {
+                found = Some(conn.stream);
+                break;
+            }
+        }
+        if conns.is_empty() {
+            idle.remove(&key);
+        }
+        found
+    }
+
+    /// Returns a connection whose last response was read completely.
+    pub(crate) fn checkin(&self, host: &str, port: u16, stream: Stream) {
+        if self.max_idle_per_host == 0 || self.proxy_protocol.is_some() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // Expired connections are closed for every origin, not only this
+        // one, so that origins never visited again do not keep them open.
+        idle.retain(|_, conns| {
+            conns.retain(|v| v.since.elapsed() < self.idle_timeout);
+            !conns.is_empty()
+        });
+        let conns = idle.entry((host.to_string(), port)).or_default();
+        if conns.len() >= self.max_idle_per_host {
+            conns.remove(0);
+        }
+        conns.push(Idle {
+            stream,
+            since: Instant::now(),
+        });
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("max_idle_per_host", &self.max_idle_per_host)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("connector", &self.connector)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An idle connection is healthy if the origin neither closed it nor sent
+/// anything unsolicited.
+fn is_healthy(stream: &Stream) -> bool {
+    let mut buf = [0; 1];
+    matches!(stream.try_read(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Pool;
+
+    #[tokio::test]
+    async fn test_checkout() {
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listen.local_addr().unwrap().port();
+        let pool = Pool::new().set_max_idle_per_host(1);
+
+        let (stream, reused) = pool.connect("127.0.0.1", port, None).await.unwrap();
+        assert!(!reused);
+        let (peer, _) = listen.accept().await.unwrap();
+        let other = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        // The oldest connection is closed once the limit is reached.
+        pool.checkin("127.0.0.1", port, other.into());
+        pool.checkin("127.0.0.1", port, stream);
+        assert_eq!(pool.idle("127.0.0.1", port), 1);
+
+        let (stream, reused) = pool.clone().connect("127.0.0.1", port, None).await.unwrap();
+        assert!(reused);
+        pool.checkin("127.0.0.1", port, stream);
+
+        // A connection closed by the origin fails the health check.
+        drop(peer);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, reused) = pool.connect("127.0.0.1", port, None).await.unwrap();
+        assert!(!reused);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listen.local_addr().unwrap().port();
+        let pool = Pool::new().set_idle_timeout(Duration::ZERO);
+
+        let (stream, _) = pool.connect("127.0.0.1", port, None).await.unwrap();
+        pool.checkin("127.0.0.1", port, stream);
+        let (_, reused) = pool.connect("127.0.0.1", port, None).await.unwrap();
+        assert!(!reused);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listen.local_addr().unwrap().port();
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_port = other.local_addr().unwrap().port();
+        let pool = Pool::new().set_idle_timeout(Duration::from_millis(50));
+
+        let (stream, _) = pool.connect("127.0.0.1", port, None).await.unwrap();
+        pool.checkin("127.0.0.1", port, stream);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Returning a connection to another origin closes the expired one.
+        let (stream, _) = pool.connect("127.0.0.1", other_port, None).await.unwrap();
+        pool.checkin("127.0.0.1", other_port, stream);
+        assert_eq!(pool.idle("127.0.0.1", port), 0);
+        assert_eq!(pool.idle("127.0.0.1", other_port), 1);
+    }
+}
@@ -0,0 +1,199 @@
+//! Header fields added to and rewritten on forwarded messages.
+
+use std::net::{IpAddr, SocketAddr};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+
+use crate::{auth::quote, Error};
+
+/// The `X-Forwarded-For` field, the de facto predecessor of `Forwarded`.
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// A rule applied to the header fields of forwarded requests or responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderRule {
+    /// Appends a value, keeping the existing ones.
+    Add(HeaderName, HeaderValue),
+    /// Removes every value of the field.
+    Remove(HeaderName),
+    /// Replaces every value of the field, adding it if it is missing.
+    Replace(HeaderName, HeaderValue),
+}
+
+impl HeaderRule {
+    pub fn add(name: &str, value: &str) -> Result<Self, Error> {
+        Ok(Self::Add(name.parse()?, HeaderValue::try_from(value)?))
+    }
+
+    pub fn remove(name: &str) -> Result<Self, Error> {
+        Ok(Self::Remove(name.parse()?))
+    }
+
+    pub fn replace(name: &str, value: &str) -> Result<Self, Error> {
+        Ok(Self::Replace(name.parse()?, HeaderValue::try_from(value)?))
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            Self::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            Self::Remove(name) => {
+                headers.remove(name);
+            }
+            Self::Replace(name, value) => {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
+
+/// The header changes made by a forward proxy, configured on
+/// [`crate::server::Builder`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rewrite {
+    /// The pseudonym sent in `Via`, no `Via` is added without one.
+    pub(crate) via: Option<String>,
+    pub(crate) forwarded: bool,
+    pub(crate) strip_x_forwarded_for: bool,
+    pub(crate) request_rules: Vec<HeaderRule>,
+    pub(crate) response_rules: Vec<HeaderRule>,
+}
+
+impl Rewrite {
+    /// Rewrites the fields of a request received with `version` from the
+    /// client at `remote_addr`, after the hop-by-hop fields are removed.
+    pub(crate) fn request(
+        &self,
+        version: http::Version,
+        remote_addr: Option<SocketAddr>,
+        headers: &mut HeaderMap,
+    ) -> Result<(), Error> {
+        if self.strip_x_forwarded_for {
+            headers.remove(X_FORWARDED_FOR);
+        }
+        if self.forwarded {
+            let value = forwarded(remote_addr, headers.get(header::HOST));
+            headers.append(header::FORWARDED, HeaderValue::try_from(value)?);
+        }
+        self.via(version, headers)?;
+        for rule in &self.request_rules {
+            rule.apply(headers);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the fields of a response received with `version` from the
+    /// origin, after the hop-by-hop fields are removed.
+    pub(crate) fn response(
+        &self,
+        version: http::Version,
+        headers: &mut HeaderMap,
+    ) -> Result<(), Error> {
+        self.via(version, headers)?;
+        for rule in &self.response_rules {
+            rule.apply(headers);
+        }
+        Ok(())
+    }
+
+    fn via(&self, version: http::Version, headers: &mut HeaderMap) -> Result<(), Error> {
+        if let Some(pseudonym) = &self.via {
+            let protocol = match version {
+                http::Version::HTTP_10 => "1.0",
+                _ => "1.1",
+            };
+            let value = format!("{} {}", protocol, pseudonym);
+            headers.append(header::VIA, HeaderValue::try_from(value)?);
+        }
+        Ok(())
+    }
+}
+
+/// Formats a `Forwarded` element, see RFC 7239 section 4.
+fn forwarded(remote_addr: Option<SocketAddr>, host: Option<&HeaderValue>) -> String {
+    let mut value = match remote_addr.map(|v| v.ip()) {
+        Some(IpAddr::V4(ip)) => format!("for={}", ip),
+        Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+        None => String::from("for=unknown"),
+    };
+    if let Some(host) = host.and_then(|v| v.to_str().ok()) {
+        value.push_str(";host=");
+        if is_token(host) {
+            value.push_str(host);
+        } else {
+            value.push_str(&quote(host));
+        }
+    }
+    value.push_str(";proto=http");
+    value
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use super::{HeaderRule, Rewrite};
+
+    #[test]
+    fn test_request() {
+        let rewrite = Rewrite {
+            via: Some("leo".to_string()),
+            forwarded: true,
+            strip_x_forwarded_for: true,
+            request_rules: vec![
+                HeaderRule::add("x-added", "1").unwrap(),
+                HeaderRule::remove("x-removed").unwrap(),
+                HeaderRule::replace("user-agent", "leo").unwrap(),
+            ],
+            response_rules: Vec::new(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("example.com:8080"));
+        headers.insert("via", HeaderValue::from_static("1.1 upstream"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+        headers.insert("x-removed", HeaderValue::from_static("1"));
+        headers.append("user-agent", HeaderValue::from_static("a"));
+        headers.append("user-agent", HeaderValue::from_static("b"));
+
+        let remote_addr = "[2001:db8::1]:4000".parse().unwrap();
+        rewrite
+            .request(http::Version::HTTP_10, Some(remote_addr), &mut headers)
+            .unwrap();
+        assert_eq!(
+            headers["forwarded"],
+            "for=\"[2001:db8::1]\";host=\"example.com:8080\";proto=http"
+        );
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, ["1.1 upstream", "1.0 leo"]);
+        assert!(!headers.contains_key("x-forwarded-for"));
+        assert!(!headers.contains_key("x-removed"));
+        assert_eq!(headers["x-added"], "1");
+        assert_eq!(headers.get_all("user-agent").iter().count(), 1);
+        assert_eq!(headers["user-agent"], "leo");
+    }
+
+    #[test]
+    fn test_response() {
+        let rewrite = Rewrite {
+            response_rules: vec![HeaderRule::remove("server").unwrap()],
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("origin"));
+        rewrite
+            .response(http::Version::HTTP_11, &mut headers)
+            .unwrap();
+        assert!(headers.is_empty());
+
+        assert!(HeaderRule::add("bad name", "1").is_err());
+        assert!(HeaderRule::replace("x-name", "bad\nvalue").is_err());
+    }
+}
@@ -1,59 +1,386 @@
-use base64::Engine;
-use bytes::BytesMut;
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use http::{header, HeaderMap, HeaderValue};
 use log::trace;
-use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
 
 use crate::{
-    codec::{encode_response, parse_request},
+    auth::{AuthContext, BasicUsers, Credentials, Identity, ProxyAuthenticator},
+    codec::{
+        is_keep_alive, parse_request, request_body_length, respond, skip_body, BodyLength,
+        RequestHead, MAX_HEADERS, MAX_HEAD_LENGTH,
+    },
+    forward, http2,
+    pool::Pool,
+    rewrite::{HeaderRule, Rewrite},
     Error,
 };
 
-#[derive(Debug, Clone, Default)]
+/// The realm advertised in `Proxy-Authenticate` unless one is configured.
+const DEFAULT_REALM: &str = "Proxy Server";
+
+/// The number of `407` answers sent on one connection before it is closed.
+const MAX_AUTH_ATTEMPTS: usize = 3;
+
+/// The largest request body read and discarded after a `407` to keep the
+/// connection open, the connection is closed after a larger or chunked one.
+const MAX_DRAINED_BODY: u64 = 64 * 1024;
+
+#[derive(Clone, Default)]
 pub struct Builder {
-    authorization: Option<String>,
+    authenticator: Option<Arc<dyn ProxyAuthenticator>>,
+    realm: Option<String>,
+    max_auth_attempts: Option<usize>,
+    max_head_length: Option<usize>,
+    max_headers: Option<usize>,
+    pool: Pool,
+    rewrite: Rewrite,
+    peer_identity: Option<Identity>,
+    peer_identity_skips_auth: bool,
+}
+
+/// The outcome of authenticating a request.
+pub(crate) enum Authorization {
+    Granted(Option<Identity>),
+    /// The request must be answered with `407` and these challenges.
+    Challenge(Vec<String>),
+}
+
+/// Whether the body of a request answered with `407` is small enough to be
+/// discarded.
+fn is_drainable(headers: &HeaderMap) -> bool {
+    match request_body_length(headers) {
+        Ok(BodyLength::Empty) => true,
+        Ok(BodyLength::Length(length)) => length <= MAX_DRAINED_BODY,
+        _ => false,
+    }
 }
 
 impl Builder {
-    pub async fn handshake<T>(&self, mut io: T) -> Result<(T, String), Error>
+    /// Accepts a `CONNECT` request, returning the stream, the requested
+    /// target and the identity of the user if authentication is enabled.
+    ///
+    /// A request without acceptable credentials is answered with `407` and
+    /// the connection is kept open for the client to retry, until the number
+    /// of attempts set by [`Builder::set_max_auth_attempts`] is reached.
+    pub async fn handshake<T>(&self, io: T) -> Result<(T, String, Option<Identity>), Error>
     where
         T: AsyncBufRead + AsyncWrite + Unpin,
     {
-        trace!("parse request");
-        let mut buf = BytesMut::new();
-        if let Some((status, host)) = parse_request(&mut io, self.auth()).await? {
-            trace!("encode response");
-            encode_response(status, &mut buf);
-            io.write_all_buf(&mut buf).await?;
-            io.flush().await?;
-
-            if !status.is_success() {
-                return Err(Error::HttpStatus(
-                    status.canonical_reason().unwrap_or("non canonical reason"),
-                ));
+        self.accept(io, false, None)
+            .await?
+            .ok_or(Error::Http("non http request"))
+    }
+
+    /// Serves a client connection as a forward proxy. Absolute-form requests
+    /// such as `GET http://host/path HTTP/1.1` are forwarded to the origin
+    /// server, for as long as the client keeps the connection alive.
+    ///
+    /// Returns the tunnel like [`Builder::handshake`] if the client sends a
+    /// `CONNECT` request, or `None` once the connection is done.
+    pub async fn serve<T>(&self, io: T) -> Result<Option<(T, String, Option<Identity>)>, Error>
+    where
+        T: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        self.accept(io, true, None).await
+    }
+
+    /// Like [`Builder::serve`], with the address of the client reported in
+    /// the `Forwarded` header. Behind a load balancer, wrap the connection in
+    /// [`aries::proxy_protocol::ProxiedStream`] and pass the source of its
+    /// header.
+    pub async fn serve_from<T>(
+        &self,
+        io: T,
+        remote_addr: SocketAddr,
+    ) -> Result<Option<(T, String, Option<Identity>)>, Error>
+    where
+        T: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        self.accept(io, true, Some(remote_addr)).await
+    }
+
+    /// Accepts an HTTP/2 connection whose streams carry `CONNECT` tunnels.
+    /// Requests without acceptable credentials are answered with `407` on
+    /// their own stream, the client retries on a new one.
+    pub async fn handshake_h2<T>(&self, io: T) -> Result<http2::Acceptor<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        http2::Acceptor::new(io, self.clone()).await
+    }
+
+    /// Accepts an HTTP/3 connection whose requests are `CONNECT-UDP` tunnels
+    /// of RFC 9298. The QUIC connection must have negotiated the `h3` ALPN
+    /// protocol and enabled datagrams.
+    #[cfg(feature = "http3")]
+    pub async fn handshake_udp(
+        &self,
+        connection: quinn::Connection,
+    ) -> Result<crate::masque::Acceptor, Error> {
+        crate::masque::Acceptor::new(connection, self.clone()).await
+    }
+
+    async fn accept<T>(
+        &self,
+        mut io: T,
+        forward: bool,
+        remote_addr: Option<SocketAddr>,
+    ) -> Result<Option<(T, String, Option<Identity>)>, Error>
+    where
+        T: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        let max_auth_attempts = self.max_auth_attempts.unwrap_or(MAX_AUTH_ATTEMPTS);
+        let mut attempts = 0;
+
+        loop {
+            trace!("parse request");
+            let request = parse_request(
+                &mut io,
+                self.max_head_length.unwrap_or(MAX_HEAD_LENGTH),
+                self.max_headers.unwrap_or(MAX_HEADERS),
+            )
+            .await;
+            let head = match request {
+                Ok(Some(head)) => head,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    if let Some(status) = error_status(&e) {
+                        trace!("reject malformed request with {}", status);
+                        respond(&mut io, http::Version::HTTP_11, status, &HeaderMap::new()).await?;
+                    }
+                    return Err(e);
+                }
+            };
+
+            if head.method != http::Method::CONNECT && !forward {
+                trace!("method is not connect");
+                let status = http::StatusCode::METHOD_NOT_ALLOWED;
+                respond(&mut io, head.version, status, &HeaderMap::new()).await?;
+                return Err(Error::HttpStatus(status.canonical_reason().unwrap()));
             }
 
-            if let Some(host) = host {
-                Ok((io, host))
+            let challenges = match self.authorize(&head).await? {
+                Authorization::Granted(identity) => {
+                    attempts = 0;
+                    if head.method == http::Method::CONNECT {
+                        trace!("encode response");
+                        respond(
+                            &mut io,
+                            head.version,
+                            http::StatusCode::OK,
+                            &HeaderMap::new(),
+                        )
+                        .await?;
+                        // The CONNECT target is the request-target in authority-form,
+                        // HTTP/1.0 clients are not required to send a Host header.
+                        return Ok(Some((io, head.target, identity)));
+                    }
+
+                    trace!("forward {} {}", head.method, head.target);
+                    let keep_alive =
+                        forward::forward(&mut io, head, &self.pool, &self.rewrite, remote_addr)
+                            .await?;
+                    if !keep_alive {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Authorization::Challenge(challenges) => challenges,
+            };
+
+            trace!("proxy authentication required");
+            attempts += 1;
+            let keep_alive = attempts < max_auth_attempts
+                && is_keep_alive(head.version, &head.headers)
+                && is_drainable(&head.headers);
+            let mut headers = HeaderMap::new();
+            for challenge in challenges {
+                headers.append(
+                    header::PROXY_AUTHENTICATE,
+                    HeaderValue::try_from(challenge)?,
+                );
+            }
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+            if keep_alive {
+                if head.version == http::Version::HTTP_10 {
+                    headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+                }
             } else {
-                Err(Error::Http("non host"))
+                headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+            }
+
+            let status = http::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+            respond(&mut io, head.version, status, &headers).await?;
+            if !keep_alive {
+                return Err(Error::HttpStatus(status.canonical_reason().unwrap()));
             }
-        } else {
-            Err(Error::Http("non http request"))
+
+            // The client resends the request with credentials on this connection.
+            skip_body(&mut io, &head.headers).await?;
         }
     }
 
-    fn auth(&self) -> Option<&str> {
-        match &self.authorization {
-            Some(s) => Some(s.as_str()),
+    pub(crate) async fn authorize(&self, head: &RequestHead) -> Result<Authorization, Error> {
+        let authenticator = match &self.authenticator {
+            Some(authenticator)
+                if !self.peer_identity_skips_auth || self.peer_identity.is_none() =>
+            {
+                authenticator
+            }
+            _ => return Ok(Authorization::Granted(self.peer_identity.clone())),
+        };
+
+        let context = AuthContext {
+            method: &head.method,
+            target: &head.target,
+            realm: self.realm.as_deref().unwrap_or(DEFAULT_REALM),
+        };
+        let credentials = head
+            .headers
+            .get(header::PROXY_AUTHORIZATION)
+            .and_then(|v| Credentials::parse(v.as_bytes()));
+        let identity = match &credentials {
+            Some(credentials) => authenticator.authenticate(&context, credentials).await?,
             None => None,
+        };
+
+        match identity {
+            Some(identity) => Ok(Authorization::Granted(Some(identity))),
+            None => Ok(Authorization::Challenge(
+                authenticator.challenges(&context, credentials.as_ref()),
+            )),
         }
     }
 
-    pub fn set_authorization(mut self, username: &str, password: &str) -> Self {
-        let raw = format!("{}:{}", username, password);
-        let mut encoded = String::from("Basic ");
-        base64::engine::general_purpose::STANDARD.encode_string(raw.as_bytes(), &mut encoded);
-        self.authorization = Some(encoded);
+    /// Sets the maximum length in bytes of the request head, requests with
+    /// a larger head are rejected with `431 Request Header Fields Too Large`.
+    pub fn set_max_head_length(mut self, max_head_length: usize) -> Self {
+        self.max_head_length = Some(max_head_length);
         self
     }
+
+    /// Sets the maximum number of request headers, requests with more
+    /// headers are rejected with `431 Request Header Fields Too Large`.
+    pub fn set_max_headers(mut self, max_headers: usize) -> Self {
+        self.max_headers = Some(max_headers);
+        self
+    }
+
+    /// Requires `Basic` authentication with a single user, use
+    /// [`Builder::set_authenticator`] with [`BasicUsers`] for several users.
+    pub fn set_authorization(self, username: &str, password: &str) -> Self {
+        self.set_authenticator(BasicUsers::new().add_user(username, password))
+    }
+
+    /// Requires every request to be authenticated by `authenticator`.
+    pub fn set_authenticator<A>(mut self, authenticator: A) -> Self
+    where
+        A: ProxyAuthenticator + 'static,
+    {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Sets how many times a client is challenged on one connection before
+    /// the connection is closed.
+    pub fn set_max_auth_attempts(mut self, max_auth_attempts: usize) -> Self {
+        self.max_auth_attempts = Some(max_auth_attempts);
+        self
+    }
+
+    /// Sets the realm advertised in the `Proxy-Authenticate` challenge.
+    pub fn set_realm(mut self, realm: &str) -> Self {
+        self.realm = Some(realm.to_string());
+        self
+    }
+
+    /// Sets the pool of origin connections used by [`Builder::serve`]. Every
+    /// clone of a builder shares its pool, so connections are reused across
+    /// client connections.
+    pub fn set_pool(mut self, pool: Pool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Adds a `Via` header with `pseudonym` to forwarded requests and
+    /// responses.
+    pub fn set_via(mut self, pseudonym: &str) -> Self {
+        self.rewrite.via = Some(pseudonym.to_string());
+        self
+    }
+
+    /// Adds an RFC 7239 `Forwarded` header to forwarded requests, the client
+    /// address is known to [`Builder::serve_from`] only.
+    pub fn set_forwarded(mut self, forwarded: bool) -> Self {
+        self.rewrite.forwarded = forwarded;
+        self
+    }
+
+    /// Removes `X-Forwarded-For` from forwarded requests.
+    pub fn set_strip_x_forwarded_for(mut self, strip_x_forwarded_for: bool) -> Self {
+        self.rewrite.strip_x_forwarded_for = strip_x_forwarded_for;
+        self
+    }
+
+    /// Adds a rule applied to forwarded requests, rules are applied in the
+    /// order they are added and after the headers set by the proxy.
+    pub fn add_request_rule(mut self, rule: HeaderRule) -> Self {
+        self.rewrite.request_rules.push(rule);
+        self
+    }
+
+    /// Adds a rule applied to forwarded responses.
+    pub fn add_response_rule(mut self, rule: HeaderRule) -> Self {
+        self.rewrite.response_rules.push(rule);
+        self
+    }
+
+    /// Sets the identity the connection was authenticated with before the
+    /// proxy protocol, such as by a TLS client certificate. It is returned
+    /// for requests granted without credentials, and set per connection on
+    /// a clone of the builder.
+    pub fn set_peer_identity(mut self, identity: Identity) -> Self {
+        self.peer_identity = Some(identity);
+        self
+    }
+
+    /// Sets whether a connection with a peer identity is granted without
+    /// `Proxy-Authorization`, it is not by default and the credentials are
+    /// still required when an authenticator is set.
+    pub fn set_peer_identity_skips_auth(mut self, peer_identity_skips_auth: bool) -> Self {
+        self.peer_identity_skips_auth = peer_identity_skips_auth;
+        self
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("authenticator", &self.authenticator.is_some())
+            .field("realm", &self.realm)
+            .field("max_auth_attempts", &self.max_auth_attempts)
+            .field("max_head_length", &self.max_head_length)
+            .field("max_headers", &self.max_headers)
+            .field("pool", &self.pool)
+            .field("rewrite", &self.rewrite)
+            .field("peer_identity", &self.peer_identity)
+            .field("peer_identity_skips_auth", &self.peer_identity_skips_auth)
+            .finish()
+    }
+}
+
+/// Maps an error raised while reading the request head to the status code
+/// answered to the client.
+fn error_status(e: &Error) -> Option<http::StatusCode> {
+    match e {
+        Error::HeadTooLarge | Error::Httparse(httparse::Error::TooManyHeaders) => {
+            Some(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        }
+        Error::Httparse(httparse::Error::Version) => {
+            Some(http::StatusCode::HTTP_VERSION_NOT_SUPPORTED)
+        }
+        Error::Httparse(_) | Error::MalformedHead => Some(http::StatusCode::BAD_REQUEST),
+        _ => None,
+    }
 }
@@ -0,0 +1,10 @@
+//! TLS between the client and an HTTPS proxy, so that credentials do not
+//! travel in cleartext. The `rustls` and `native-tls` backends have the same
+//! API: a `ClientBuilder` builds the `TlsConnector` that wraps the stream
+//! given to [`crate::client::Builder::handshake`], a `TlsAcceptor` wraps the
+//! stream given to [`crate::server::Builder::handshake`].
+
+#[cfg(feature = "native-tls")]
+pub mod native_tls;
+#[cfg(feature = "rustls")]
+pub mod rustls;
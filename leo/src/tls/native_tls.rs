@@ -0,0 +1,170 @@
+//! The native-tls backend, OpenSSL on Linux and the platform TLS library
+//! elsewhere.
+
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::native_tls::{self, Certificate, Identity};
+
+use crate::Error;
+
+pub use tokio_native_tls::TlsStream;
+
+/// Configures the TLS connection to a proxy.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    roots: Vec<Vec<u8>>,
+    built_in_roots: bool,
+    sni: bool,
+    danger_accept_invalid_certs: bool,
+    alpn_protocols: Vec<String>,
+    client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            built_in_roots: true,
+            sni: true,
+            danger_accept_invalid_certs: false,
+            alpn_protocols: Vec::new(),
+            client_certificate: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the PEM encoded certificate, such as a private CA.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Result<Self, Error> {
+        Certificate::from_pem(pem)?;
+        self.roots.push(pem.to_vec());
+        Ok(self)
+    }
+
+    /// Sets whether the system root certificates are trusted, they are by
+    /// default.
+    pub fn set_built_in_roots(mut self, built_in_roots: bool) -> Self {
+        self.built_in_roots = built_in_roots;
+        self
+    }
+
+    /// Sets whether the proxy name is sent with Server Name Indication, it
+    /// is by default.
+    pub fn set_sni(mut self, sni: bool) -> Self {
+        self.sni = sni;
+        self
+    }
+
+    /// Accepts any certificate, for testing only.
+    pub fn set_danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.danger_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Sets the protocols offered with ALPN.
+    pub fn set_alpn_protocols(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn_protocols = protocols
+            .iter()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .collect();
+        self
+    }
+
+    /// Presents the PEM encoded certificate chain and PKCS #8 private key to
+    /// a proxy that authenticates clients by certificate.
+    pub fn set_client_certificate(mut self, cert_chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        Identity::from_pkcs8(cert_chain, key)?;
+        self.client_certificate = Some((cert_chain.to_vec(), key.to_vec()));
+        Ok(self)
+    }
+
+    pub fn build(&self) -> Result<TlsConnector, Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.roots {
+            builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some((cert_chain, key)) = &self.client_certificate {
+            builder.identity(Identity::from_pkcs8(cert_chain, key)?);
+        }
+        let alpn_protocols: Vec<_> = self.alpn_protocols.iter().map(|v| v.as_str()).collect();
+        let connector = builder
+            .disable_built_in_roots(!self.built_in_roots)
+            .use_sni(self.sni)
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs)
+            .request_alpns(&alpn_protocols)
+            .build()?;
+        Ok(TlsConnector {
+            connector: tokio_native_tls::TlsConnector::from(connector),
+        })
+    }
+}
+
+/// Connects to a proxy over TLS.
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: tokio_native_tls::TlsConnector,
+}
+
+impl TlsConnector {
+    /// Runs the TLS handshake on `io`, verifying the certificate of the
+    /// proxy against `domain`.
+    pub async fn connect<T>(&self, domain: &str, io: T) -> Result<TlsStream<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.connector.connect(domain, io).await?)
+    }
+}
+
+/// Accepts TLS connections to the proxy.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_native_tls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Uses the PEM encoded certificate chain and PKCS #8 private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let identity = Identity::from_pkcs8(cert_chain, key)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)?;
+        Ok(Self {
+            acceptor: tokio_native_tls::TlsAcceptor::from(acceptor),
+        })
+    }
+
+    /// Returns an error: native-tls cannot request a client certificate, so
+    /// a proxy identifying clients by certificate must use the rustls
+    /// acceptor.
+    pub fn from_pem_with_client_auth(
+        _cert_chain: &[u8],
+        _key: &[u8],
+        _client_roots: &[u8],
+        _required: bool,
+    ) -> Result<Self, Error> {
+        Err(Error::Tls(
+            "client certificates are not supported by native-tls",
+        ))
+    }
+
+    /// Loads the PEM encoded certificate chain and PKCS #8 private key from
+    /// files.
+    pub fn from_pem_files<P>(cert_chain: P, key: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(key)?)
+    }
+
+    /// Runs the TLS handshake on an accepted connection.
+    pub async fn accept<T>(&self, io: T) -> Result<TlsStream<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.acceptor.accept(io).await?)
+    }
+}
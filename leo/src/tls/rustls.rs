@@ -0,0 +1,220 @@
+//! The rustls backend, with the `ring` crypto provider.
+
+use std::{fmt, path::Path, sync::Arc};
+
+use aries::tls::{parse_pem, NoVerifier};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+use rustls_pki_types::pem::PemObject;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::Error;
+
+pub use aries::tls::{certificate_identity, peer_identity};
+pub use tokio_rustls::{client, server};
+
+/// Configures the TLS connection to a proxy.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    roots: Vec<CertificateDer<'static>>,
+    built_in_roots: bool,
+    sni: bool,
+    danger_accept_invalid_certs: bool,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            built_in_roots: true,
+            sni: true,
+            danger_accept_invalid_certs: false,
+            alpn_protocols: Vec::new(),
+            client_certificate: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the PEM encoded certificates, such as a private CA.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Result<Self, Error> {
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            self.roots
+                .push(cert.map_err(|_| Error::Tls("invalid certificate"))?);
+        }
+        Ok(self)
+    }
+
+    /// Sets whether the Mozilla root certificates are trusted, they are by
+    /// default.
+    pub fn set_built_in_roots(mut self, built_in_roots: bool) -> Self {
+        self.built_in_roots = built_in_roots;
+        self
+    }
+
+    /// Sets whether the proxy name is sent with Server Name Indication, it
+    /// is by default.
+    pub fn set_sni(mut self, sni: bool) -> Self {
+        self.sni = sni;
+        self
+    }
+
+    /// Accepts any certificate, for testing only.
+    pub fn set_danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.danger_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Sets the protocols offered with ALPN.
+    pub fn set_alpn_protocols(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn_protocols = protocols.iter().map(|v| v.to_vec()).collect();
+        self
+    }
+
+    /// Presents the PEM encoded certificate chain and private key to a proxy
+    /// that authenticates clients by certificate.
+    pub fn set_client_certificate(mut self, cert_chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        parse_pem(cert_chain, key)?;
+        self.client_certificate = Some((cert_chain.to_vec(), key.to_vec()));
+        Ok(self)
+    }
+
+    pub fn build(&self) -> Result<TlsConnector, Error> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.danger_accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier::new(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            if self.built_in_roots {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+            for cert in &self.roots {
+                roots.add(cert.clone())?;
+            }
+            builder.with_root_certificates(roots)
+        };
+        let mut config = match &self.client_certificate {
+            Some((cert_chain, key)) => {
+                let (cert_chain, key) = parse_pem(cert_chain, key)?;
+                builder.with_client_auth_cert(cert_chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.enable_sni = self.sni;
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(TlsConnector {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        })
+    }
+}
+
+/// Connects to a proxy over TLS.
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnector").finish_non_exhaustive()
+    }
+}
+
+impl TlsConnector {
+    /// Runs the TLS handshake on `io`, verifying the certificate of the
+    /// proxy against `domain`.
+    pub async fn connect<T>(&self, domain: &str, io: T) -> Result<client::TlsStream<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let name = ServerName::try_from(domain.to_string())
+            .map_err(|_| Error::Tls("invalid server name"))?;
+        Ok(self.connector.connect(name, io).await?)
+    }
+}
+
+/// Accepts TLS connections to the proxy.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Uses the PEM encoded certificate chain and private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let (cert_chain, key) = parse_pem(cert_chain, key)?;
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, key)?;
+        Ok(Self::new(config))
+    }
+
+    /// Like [`TlsAcceptor::from_pem`], and asks clients for a certificate
+    /// issued by one of the PEM encoded `client_roots`. A client without a
+    /// certificate is refused unless `required` is false, [`peer_identity`]
+    /// returns the identity of a client that presented one.
+    pub fn from_pem_with_client_auth(
+        cert_chain: &[u8],
+        key: &[u8],
+        client_roots: &[u8],
+        required: bool,
+    ) -> Result<Self, Error> {
+        let (cert_chain, key) = parse_pem(cert_chain, key)?;
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(client_roots) {
+            roots.add(cert.map_err(|_| Error::Tls("invalid certificate"))?)?;
+        }
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        let verifier = if required {
+            verifier.build()
+        } else {
+            verifier.allow_unauthenticated().build()
+        }
+        .map_err(|_| Error::Tls("invalid client roots"))?;
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain, key)?;
+        Ok(Self::new(config))
+    }
+
+    /// Loads the PEM encoded certificate chain and private key from files.
+    pub fn from_pem_files<P>(cert_chain: P, key: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(key)?)
+    }
+
+    fn new(config: rustls::ServerConfig) -> Self {
+        Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+        }
+    }
+
+    /// Runs the TLS handshake on an accepted connection.
+    pub async fn accept<T>(&self, io: T) -> Result<server::TlsStream<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.acceptor.accept(io).await?)
+    }
+}
@@ -0,0 +1,203 @@
+#![cfg(feature = "http3")]
+
+use std::{sync::Arc, time::Duration};
+
+use leo::{client, digest::DigestUsers, masque, server};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    },
+};
+use tokio::net::UdpSocket;
+
+/// Returns a server and a client endpoint on loopback, the server uses a
+/// self-signed certificate for `localhost` that the client trusts.
+fn endpoints() -> (quinn::Endpoint, quinn::Endpoint) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut server_crypto = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+    server_crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(server_crypto).unwrap(),
+    ));
+    let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let mut client_crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(client_crypto).unwrap(),
+    )));
+    (server, client)
+}
+
+#[tokio::test]
+async fn test_connect_udp() {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        loop {
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+
+    let (server, client) = endpoints();
+    let proxy_addr = server.local_addr().unwrap();
+    let builder =
+        server::Builder::default().set_authenticator(DigestUsers::new().add_user("hello", "world"));
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        let mut acceptor = builder.handshake_udp(connection).await.unwrap();
+        while let Some((mut tunnel, target, identity)) = acceptor.accept().await.unwrap() {
+            assert_eq!(identity.unwrap().name(), "hello");
+            tokio::spawn(async move {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                socket.connect(target).await.unwrap();
+                let mut buf = [0; 1500];
+                loop {
+                    tokio::select! {
+                        payload = tunnel.recv() => match payload {
+                            Some(payload) => {
+                                socket.send(&payload).await.unwrap();
+                            }
+                            None => return,
+                        },
+                        n = socket.recv(&mut buf) => tunnel.send(&buf[..n.unwrap()]).unwrap(),
+                    }
+                }
+            });
+        }
+    });
+
+    let connection = client
+        .connect(proxy_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let proxy = masque::Client::new(connection, &format!("localhost:{}", proxy_addr.port()))
+        .await
+        .unwrap();
+    let builder = client::Builder::default()
+        .set_authorization("hello", "world")
+        .set_host_port("127.0.0.1".to_string(), echo_addr.port());
+
+    // Two tunnels on one connection keep their datagrams apart.
+    let mut first = builder.handshake_udp(&proxy).await.unwrap();
+    let mut second = builder.handshake_udp(&proxy).await.unwrap();
+    second.send(b"second").unwrap();
+    first.send(b"first").unwrap();
+    assert_eq!(&first.recv().await.unwrap()[..], b"first");
+    assert_eq!(&second.recv().await.unwrap()[..], b"second");
+
+    let rejected = client::Builder::default()
+        .set_authorization("hello", "wrong")
+        .set_host_port("127.0.0.1".to_string(), echo_addr.port())
+        .handshake_udp(&proxy)
+        .await;
+    assert!(rejected.is_err());
+}
+
+#[tokio::test]
+async fn test_datagram_not_supported() {
+    let (server, client) = endpoints();
+    let proxy_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        let mut connection: h3::server::Connection<_, bytes::Bytes> = h3::server::builder()
+            .enable_extended_connect(true)
+            .build(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        while let Ok(Some(resolver)) = connection.accept().await {
+            let (_, mut stream) = resolver.resolve_request().await.unwrap();
+            stream.send_response(http::Response::new(())).await.unwrap();
+        }
+    });
+
+    let connection = client
+        .connect(proxy_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let proxy = masque::Client::new(connection, &format!("localhost:{}", proxy_addr.port()))
+        .await
+        .unwrap();
+    // The proxy accepts the request without announcing HTTP Datagrams.
+    let result = client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 53)
+        .handshake_udp(&proxy)
+        .await;
+    assert!(matches!(
+        result,
+        Err(leo::Error::Http("proxy does not support http datagrams"))
+    ));
+}
+
+#[tokio::test]
+async fn test_full_tunnel() {
+    let (server, client) = endpoints();
+    let proxy_addr = server.local_addr().unwrap();
+    let (accepted, tunnel) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        let mut acceptor = server::Builder::default()
+            .handshake_udp(connection)
+            .await
+            .unwrap();
+        let (tunnel, _, _) = acceptor.accept().await.unwrap().unwrap();
+        accepted.send(tunnel).ok().unwrap();
+        // Keeps the connection open.
+        while let Ok(Some(_)) = acceptor.accept().await {}
+    });
+
+    let connection = client
+        .connect(proxy_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let proxy = masque::Client::new(connection, &format!("localhost:{}", proxy_addr.port()))
+        .await
+        .unwrap();
+    let sender = client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 53)
+        .handshake_udp(&proxy)
+        .await
+        .unwrap();
+    let mut tunnel = tunnel.await.unwrap();
+
+    // The payloads beyond what the tunnel queues are dropped while it is not
+    // read.
+    for i in 0..2000u32 {
+        sender.send(&i.to_be_bytes()).unwrap();
+        if i % 100 == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut received = 0;
+    while let Ok(Some(_)) = tokio::time::timeout(Duration::from_millis(100), tunnel.recv()).await {
+        received += 1;
+    }
+    assert!(received > 0);
+    assert!(received <= 1024, "{} payloads queued", received);
+}
@@ -0,0 +1,250 @@
+#![cfg(feature = "mitm")]
+
+use std::sync::{Arc, Mutex};
+
+use leo::{
+    client,
+    mitm::{CertificateAuthority, Har, Interceptor},
+    server,
+    tls::rustls::{ClientBuilder, TlsAcceptor},
+};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{TcpListener, TcpStream},
+};
+
+/// Runs an HTTPS origin for `localhost` that answers every request with its
+/// request line and body length, and returns its port and certificate.
+async fn origin() -> (u16, String) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = certified.cert.pem();
+    let acceptor = TlsAcceptor::from_pem(
+        cert.as_bytes(),
+        certified.signing_key.serialize_pem().as_bytes(),
+    )
+    .unwrap();
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = BufStream::new(acceptor.accept(stream).await.unwrap());
+                loop {
+                    let mut request_line = String::new();
+                    if stream.read_line(&mut request_line).await.unwrap() == 0 {
+                        return;
+                    }
+                    let mut length = 0;
+                    let mut chunked = false;
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        let line = line.to_ascii_lowercase();
+                        if let Some(v) = line.strip_prefix("content-length:") {
+                            length = v.trim().parse().unwrap();
+                        }
+                        chunked |= line == "transfer-encoding: chunked\r\n";
+                    }
+                    if chunked {
+                        loop {
+                            let mut line = String::new();
+                            stream.read_line(&mut line).await.unwrap();
+                            let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+                            let mut chunk = vec![0; size + 2];
+                            stream.read_exact(&mut chunk).await.unwrap();
+                            if size == 0 {
+                                break;
+                            }
+                            length += size;
+                        }
+                    } else {
+                        let mut body = vec![0; length];
+                        stream.read_exact(&mut body).await.unwrap();
+                    }
+                    let body = format!("{} {}", request_line.trim_end(), length);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: \
+                         chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            });
+        }
+    });
+    (port, cert)
+}
+
+/// Returns a local CA certificate and its key, PEM encoded.
+fn ca() -> (String, String) {
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca_key_pem = ca_key.serialize_pem();
+    let ca = CertifiedIssuer::self_signed(params, ca_key).unwrap();
+    (ca.pem(), ca_key_pem)
+}
+
+/// Runs a proxy intercepting one tunnel with `interceptor`, and returns a
+/// TLS connection to the origin through it. The client trusts the local CA,
+/// not the origin.
+async fn connect(
+    interceptor: Interceptor,
+    origin_port: u16,
+    ca_pem: &str,
+) -> BufStream<leo::tls::rustls::client::TlsStream<BufStream<TcpStream>>> {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listen.accept().await.unwrap();
+        let (stream, target, _) = server::Builder::default()
+            .handshake(BufStream::new(stream))
+            .await
+            .unwrap();
+        interceptor.intercept(stream, &target).await.unwrap();
+    });
+
+    let stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    let stream = client::Builder::default()
+        .set_host_port("localhost".to_string(), origin_port)
+        .handshake(BufStream::new(stream))
+        .await
+        .unwrap();
+    let connector = ClientBuilder::new()
+        .set_built_in_roots(false)
+        .add_root_certificate(ca_pem.as_bytes())
+        .unwrap()
+        .build()
+        .unwrap();
+    BufStream::new(connector.connect("localhost", stream).await.unwrap())
+}
+
+#[tokio::test]
+async fn test_intercept() {
+    let (origin_port, origin_cert) = origin().await;
+    let (ca_pem, ca_key_pem) = ca();
+
+    let har_path = std::env::temp_dir().join(format!("leo-mitm-{}.har", std::process::id()));
+    let har = Har::new(&har_path);
+    let exchanges = Arc::new(Mutex::new(Vec::new()));
+    let observed = exchanges.clone();
+    let interceptor = Interceptor::new(
+        CertificateAuthority::from_pem(ca_pem.as_bytes(), ca_key_pem.as_bytes()).unwrap(),
+        ClientBuilder::new()
+            .set_built_in_roots(false)
+            .add_root_certificate(origin_cert.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap(),
+    )
+    .set_observer(move |exchange| observed.lock().unwrap().push(exchange.clone()))
+    .set_har(har.clone());
+    let mut stream = connect(interceptor, origin_port, &ca_pem).await;
+
+    let requests = [
+        "GET /a?b=c HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "POST /d HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+    ];
+    let bodies = ["GET /a?b=c HTTP/1.1 0", "POST /d HTTP/1.1 5"];
+    for (request, body) in requests.iter().zip(bodies) {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert!(!head.contains("transfer-encoding"));
+        let expected = format!("content-length: {}\r\n", body.len());
+        assert!(head.contains(&expected));
+        let mut received = vec![0; body.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, body.as_bytes());
+    }
+
+    let exchanges = exchanges.lock().unwrap().clone();
+    assert_eq!(exchanges.len(), 2);
+    let uri = format!("https://localhost:{}/a?b=c", origin_port);
+    assert_eq!(exchanges[0].request.uri().to_string(), uri);
+    assert_eq!(&exchanges[0].response.body()[..], bodies[0].as_bytes());
+    assert_eq!(exchanges[1].request.method(), http::Method::POST);
+    assert_eq!(&exchanges[1].request.body()[..], b"hello");
+
+    har.flush().await.unwrap();
+    let log: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&har_path).unwrap()).unwrap();
+    let entries = log["log"]["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    let recorded = har.to_json();
+    assert_eq!(recorded["log"]["entries"].as_array().unwrap().len(), 2);
+    assert_eq!(entries[0]["request"]["url"], uri);
+    assert_eq!(entries[1]["request"]["postData"]["text"], "hello");
+    assert_eq!(entries[1]["response"]["content"]["text"], bodies[1]);
+    std::fs::remove_file(&har_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_intercept_large_body() {
+    let (origin_port, origin_cert) = origin().await;
+    let (ca_pem, ca_key_pem) = ca();
+    let exchanges = Arc::new(Mutex::new(Vec::new()));
+    let observed = exchanges.clone();
+    let interceptor = Interceptor::new(
+        CertificateAuthority::from_pem(ca_pem.as_bytes(), ca_key_pem.as_bytes()).unwrap(),
+        ClientBuilder::new()
+            .set_built_in_roots(false)
+            .add_root_certificate(origin_cert.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap(),
+    )
+    .set_max_body_size(4)
+    .set_observer(move |exchange| observed.lock().unwrap().push(exchange.clone()));
+    let mut stream = connect(interceptor, origin_port, &ca_pem).await;
+
+    // Both bodies are larger than the limit and relayed in chunks.
+    let request = "POST /d HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        stream.read_line(&mut head).await.unwrap();
+    }
+    let head = head.to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 200 ok\r\n"));
+    assert!(head.contains("transfer-encoding: chunked\r\n"));
+    assert!(!head.contains("content-length"));
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+        let mut chunk = vec![0; size + 2];
+        stream.read_exact(&mut chunk).await.unwrap();
+        if size == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+    assert_eq!(body, b"POST /d HTTP/1.1 5");
+
+    // A streamed exchange is observed once relayed.
+    while exchanges.lock().unwrap().is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let exchanges = exchanges.lock().unwrap().clone();
+    assert_eq!(exchanges.len(), 1);
+    assert_eq!(&exchanges[0].request.body()[..], b"hell");
+    assert!(exchanges[0].request_truncated);
+    assert_eq!(&exchanges[0].response.body()[..], b"POST");
+    assert!(exchanges[0].response_truncated);
+}
@@ -1,6 +1,24 @@
-use leo::{client, server};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use aries::{
+    connector::{ConnectError, Protocol, ProxyConnector},
+    proxy_protocol::{read_header, ProxiedStream, ProxyHeader, Version},
+    proxy_url::ProxyUrl,
+};
+use base64::Engine;
+use leo::{
+    auth::{AuthContext, BasicUsers, Credentials, ProxyAuthenticator},
+    client,
+    digest::{Algorithm, DigestUsers},
+    pool::Pool,
+    rewrite::HeaderRule,
+    server,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufStream},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufStream},
     net::{TcpListener, TcpStream},
 };
 
@@ -20,7 +38,7 @@ async fn test_echo() {
         loop {
             let (stream, _) = listen.accept().await.unwrap();
             let buf = BufStream::new(stream);
-            let (mut src, dst) = server::Builder::default().handshake(buf).await.unwrap();
+            let (mut src, dst, _) = server::Builder::default().handshake(buf).await.unwrap();
             let mut dst = TcpStream::connect(&dst).await.unwrap();
             tokio::io::copy_bidirectional(&mut src, &mut dst)
                 .await
@@ -42,3 +60,808 @@ async fn test_echo() {
     println!("{}", data);
     assert_eq!(data, "hello world\r\n")
 }
+
+#[tokio::test]
+async fn test_head_too_large() {
+    let (client, server) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        server::Builder::default()
+            .set_max_head_length(64)
+            .handshake(BufStream::new(server))
+            .await
+    });
+
+    let mut client = BufStream::new(client);
+    let head = format!(
+        "CONNECT 127.0.0.1:9764 HTTP/1.1\r\nX-Pad: {}\r\n\r\n",
+        "a".repeat(64)
+    );
+    client.write_all(head.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let mut line = String::new();
+    client.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "HTTP/1.1 431 Request Header Fields Too Large\r\n");
+    assert!(matches!(
+        handle.await.unwrap(),
+        Err(leo::Error::HeadTooLarge)
+    ));
+}
+
+#[tokio::test]
+async fn test_authentication() {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        let builder = server::Builder::default()
+            .set_authenticator(
+                BasicUsers::new()
+                    .add_user("alice", "secret")
+                    .add_user("bob", "hunter2"),
+            )
+            .set_realm("zodiac");
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = listen.accept().await.unwrap();
+            let result = builder.handshake(BufStream::new(stream)).await;
+            results.push(result.map(|(_, host, identity)| (host, identity)));
+        }
+        results
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_authorization("bob", "hunter2")
+        .handshake(BufStream::new(stream))
+        .await
+        .unwrap();
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let result = client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_authorization("bob", "secret")
+        .handshake(BufStream::new(stream))
+        .await;
+    assert!(matches!(result, Err(leo::Error::HttpStatus(_))));
+
+    let results = handle.await.unwrap();
+    let (host, identity) = results[0].as_ref().unwrap();
+    assert_eq!(host, "127.0.0.1:9764");
+    assert_eq!(identity.as_ref().unwrap().name(), "bob");
+    assert!(results[1].is_err());
+}
+
+#[tokio::test]
+async fn test_digest_client() {
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        let users = DigestUsers::new().add_user("Mufasa", "Circle of Life");
+        let context = AuthContext {
+            method: &http::Method::CONNECT,
+            target: "127.0.0.1:9764",
+            realm: "zodiac",
+        };
+        let mut proxy = BufStream::new(proxy);
+
+        // No credentials go out before the proxy asks for Digest.
+        let head = read_head(&mut proxy).await;
+        assert!(!head.contains("proxy-authorization"));
+        let challenge = users.challenges(&context, None).remove(0);
+        let response = format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: {}\r\nContent-Length: 4\r\n\r\nnope",
+            challenge
+        );
+        proxy.write_all(response.as_bytes()).await.unwrap();
+        proxy.flush().await.unwrap();
+
+        let head = read_head(&mut proxy).await;
+        let value = head
+            .lines()
+            .find_map(|v| v.strip_prefix("proxy-authorization: "))
+            .unwrap();
+        let credentials = Credentials::parse(value.as_bytes()).unwrap();
+        let identity = users.authenticate(&context, &credentials).await.unwrap();
+        proxy
+            .write_all(b"HTTP/1.0 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        proxy.flush().await.unwrap();
+        identity
+    });
+
+    client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_authorization("Mufasa", "Circle of Life")
+        .handshake(BufStream::new(client))
+        .await
+        .unwrap();
+    assert_eq!(handle.await.unwrap().unwrap().name(), "Mufasa");
+}
+
+#[tokio::test]
+async fn test_ntlm_client() {
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        let mut proxy = BufStream::new(proxy);
+        let authorization = |head: String| {
+            let value = head
+                .lines()
+                .find_map(|v| v.strip_prefix("proxy-authorization: NTLM "))
+                .unwrap()
+                .to_string();
+            base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .unwrap()
+        };
+
+        let negotiate = authorization(read_head(&mut proxy).await);
+        assert_eq!(&negotiate[..12], b"NTLMSSP\0\x01\0\0\0");
+
+        // The CHALLENGE_MESSAGE of MS-NLMP section 4.2.4.3.
+        let challenge = "TlRMTVNTUAACAAAADAAMADgAAAAzgoriASNFZ4mrze8AAAAAAAAAACQAJABEAAAABgBwFwAAAA9TAGUAcgB2AGUAcgACAAwARABvAG0AYQBpAG4AAQAMAFMAZQByAHYAZQByAAAAAAA=";
+        let response = format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: NTLM {}\r\nContent-Length: 0\r\n\r\n",
+            challenge
+        );
+        proxy.write_all(response.as_bytes()).await.unwrap();
+        proxy.flush().await.unwrap();
+
+        let authenticate = authorization(read_head(&mut proxy).await);
+        proxy
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        proxy.flush().await.unwrap();
+        authenticate
+    });
+
+    client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_ntlm_credentials("Domain", "User", "Password", "COMPUTER")
+        .handshake(BufStream::new(client))
+        .await
+        .unwrap();
+    let authenticate = handle.await.unwrap();
+    assert_eq!(&authenticate[..12], b"NTLMSSP\0\x03\0\0\0");
+}
+
+#[tokio::test]
+async fn test_digest_authentication() {
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        server::Builder::default()
+            .set_authenticator(DigestUsers::new().add_user("Mufasa", "Circle of Life"))
+            .handshake(BufStream::new(proxy))
+            .await
+            .map(|(_, host, identity)| (host, identity))
+    });
+
+    client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_authorization("Mufasa", "Circle of Life")
+        .handshake(BufStream::new(client))
+        .await
+        .unwrap();
+    let (host, identity) = handle.await.unwrap().unwrap();
+    assert_eq!(host, "127.0.0.1:9764");
+    assert_eq!(identity.unwrap().name(), "Mufasa");
+}
+
+#[tokio::test]
+async fn test_auth_large_body() {
+    for body_header in ["Content-Length: 65537", "Transfer-Encoding: chunked"] {
+        let (client, proxy) = tokio::io::duplex(4096);
+        let handle = tokio::spawn(async move {
+            server::Builder::default()
+                .set_authorization("bob", "hunter2")
+                .handshake(BufStream::new(proxy))
+                .await
+                .map(|_| ())
+        });
+
+        // The body is not read, the connection is closed instead.
+        let mut client = BufStream::new(client);
+        let request = format!("CONNECT 127.0.0.1:9764 HTTP/1.1\r\n{}\r\n\r\n", body_header);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(head.contains("connection: close\r\n"), "{}", body_header);
+        assert!(handle.await.unwrap().is_err());
+    }
+}
+
+/// Counts the challenges sent by the authenticator it wraps.
+struct CountChallenges<A>(A, Arc<AtomicUsize>);
+
+#[async_trait::async_trait]
+impl<A: ProxyAuthenticator> ProxyAuthenticator for CountChallenges<A> {
+    async fn authenticate(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: &Credentials,
+    ) -> Result<Option<leo::auth::Identity>, leo::Error> {
+        self.0.authenticate(context, credentials).await
+    }
+
+    fn challenges(
+        &self,
+        context: &AuthContext<'_>,
+        credentials: Option<&Credentials>,
+    ) -> Vec<String> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        self.0.challenges(context, credentials)
+    }
+}
+
+#[tokio::test]
+async fn test_digest_nonce_reuse() {
+    let challenges = Arc::new(AtomicUsize::new(0));
+    let server = server::Builder::default().set_authenticator(CountChallenges(
+        DigestUsers::new().add_user("Mufasa", "Circle of Life"),
+        challenges.clone(),
+    ));
+    let client = client::Builder::default()
+        .set_host_port("127.0.0.1".to_string(), 9764)
+        .set_authorization("Mufasa", "Circle of Life");
+
+    // The second and third tunnels reuse the nonce with a growing nc,
+    // without another 407.
+    for _ in 0..3 {
+        let (client_io, proxy_io) = tokio::io::duplex(4096);
+        let server = server.clone();
+        let handle = tokio::spawn(async move { server.handshake(BufStream::new(proxy_io)).await });
+        client.handshake(BufStream::new(client_io)).await.unwrap();
+        let (_, _, identity) = handle.await.unwrap().unwrap();
+        assert_eq!(identity.unwrap().name(), "Mufasa");
+    }
+    assert_eq!(challenges.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_auth_attempts() {
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        server::Builder::default()
+            .set_authorization("bob", "hunter2")
+            .set_max_auth_attempts(2)
+            .handshake(BufStream::new(proxy))
+            .await
+            .map(|_| ())
+    });
+
+    let mut client = BufStream::new(client);
+    let request = "CONNECT 127.0.0.1:9764 HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc";
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+    assert!(head.contains("content-length: 0\r\n"));
+    assert!(!head.contains("connection: close\r\n"));
+
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.contains("connection: close\r\n"));
+    assert!(handle.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_forward() {
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = origin.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut stream = BufStream::new(stream);
+                loop {
+                    let head = read_head(&mut stream).await;
+                    if head.is_empty() {
+                        return;
+                    }
+                    let request_line = head.lines().next().unwrap().to_string();
+                    assert!(!head.contains("proxy-"));
+                    let length = head
+                        .lines()
+                        .find_map(|v| v.strip_prefix("content-length: "))
+                        .map(|v| v.parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).await.unwrap();
+
+                    let response = if request_line.starts_with("GET /chunked ") {
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+                            .to_string()
+                    } else if request_line.starts_with("GET /both ") {
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 100\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+                            .to_string()
+                    } else {
+                        let body = format!("{}|{}", request_line, String::from_utf8(body).unwrap());
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    };
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            });
+        }
+    });
+
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        server::Builder::default()
+            .serve(BufStream::new(proxy))
+            .await
+            .map(|v| v.is_none())
+    });
+
+    let mut client = BufStream::new(client);
+    let request = format!(
+        "POST http://{}/echo?a=b HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\nContent-Length: 5\r\n\r\nworld",
+        origin_addr, origin_addr
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    let expected = "POST /echo?a=b HTTP/1.1|world";
+    assert!(head.contains(&format!("content-length: {}\r\n", expected.len())));
+    let mut body = vec![0; expected.len()];
+    client.read_exact(&mut body).await.unwrap();
+    assert_eq!(body, expected.as_bytes());
+
+    // The `Content-Length` of a chunked response is not forwarded.
+    let request = format!("GET http://{}/both HTTP/1.1\r\n\r\n", origin_addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.contains("transfer-encoding: chunked\r\n"));
+    assert!(!head.contains("content-length"));
+    let expected = "5\r\nhello\r\n0\r\n\r\n";
+    let mut body = vec![0; expected.len()];
+    client.read_exact(&mut body).await.unwrap();
+    assert_eq!(body, expected.as_bytes());
+
+    // An HTTP/1.0 client gets the chunked body decoded and the connection closed.
+    let request = format!("GET http://{}/chunked HTTP/1.0\r\n\r\n", origin_addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(head.contains("connection: close\r\n"));
+    assert!(!head.contains("transfer-encoding"));
+    let mut body = String::new();
+    client.read_to_string(&mut body).await.unwrap();
+    assert_eq!(body, "hello");
+    assert!(handle.await.unwrap().unwrap());
+
+    // A request framed by both `Transfer-Encoding` and `Content-Length` is
+    // rejected rather than forwarded.
+    let (client, proxy) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        server::Builder::default()
+            .serve(BufStream::new(proxy))
+            .await
+            .map(|v| v.is_none())
+    });
+    let mut client = BufStream::new(client);
+    let request = format!(
+        "POST http://{}/echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        origin_addr
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(!response.contains("smuggled"));
+    assert!(handle.await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn test_forward_pool() {
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = origin.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut stream = BufStream::new(stream);
+                while !read_head(&mut stream).await.is_empty() {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                }
+            });
+        }
+    });
+
+    let pool = Pool::new().set_max_idle_per_host(1);
+    let builder = server::Builder::default().set_pool(pool.clone());
+    for _ in 0..3 {
+        let (client, proxy) = tokio::io::duplex(4096);
+        let builder = builder.clone();
+        let handle = tokio::spawn(async move { builder.serve(BufStream::new(proxy)).await });
+
+        let mut client = BufStream::new(client);
+        let request = format!("GET http://{}/ HTTP/1.0\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok"));
+        assert!(handle.await.unwrap().unwrap().is_none());
+    }
+
+    // Every client connection reused the first origin connection.
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.idle("127.0.0.1", origin_addr.port()), 1);
+}
+
+#[tokio::test]
+async fn test_forward_headers() {
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = origin.accept().await.unwrap();
+        let mut stream = BufStream::new(stream);
+        let head = read_head(&mut stream).await;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nServer: origin\r\nContent-Length: {}\r\n\r\n{}",
+            head.len(),
+            head
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    });
+
+    let builder = server::Builder::default()
+        .set_via("leo")
+        .set_forwarded(true)
+        .set_strip_x_forwarded_for(true)
+        .add_request_rule(HeaderRule::replace("user-agent", "leo").unwrap())
+        .add_response_rule(HeaderRule::remove("server").unwrap());
+    let (client, proxy) = tokio::io::duplex(4096);
+    let remote_addr = "192.0.2.1:4000".parse().unwrap();
+    tokio::spawn(async move { builder.serve_from(BufStream::new(proxy), remote_addr).await });
+
+    let mut client = BufStream::new(client);
+    let request = format!(
+        "GET http://{}/ HTTP/1.0\r\nUser-Agent: curl\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n",
+        origin_addr
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("via: 1.1 leo\r\n"));
+    assert!(!head.contains("server:"));
+    assert!(body.contains("via: 1.0 leo\r\n"));
+    assert!(body.contains(&format!(
+        "forwarded: for=192.0.2.1;host=\"{}\";proto=http\r\n",
+        origin_addr
+    )));
+    assert!(body.contains("user-agent: leo\r\n"));
+    assert!(!body.contains("x-forwarded-for"));
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = origin.accept().await.unwrap();
+        let mut stream = BufStream::new(stream);
+        let header = read_header(&mut stream).await.unwrap().unwrap();
+        let head = read_head(&mut stream).await;
+        let body = format!("{}\r\n{}", header.source, head);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    });
+
+    let builder = server::Builder::default()
+        .set_forwarded(true)
+        .set_pool(Pool::new().set_proxy_protocol(Version::V2));
+    let (client, proxy) = tokio::io::duplex(4096);
+    let server = builder.clone();
+    tokio::spawn(async move {
+        let stream = ProxiedStream::accept(proxy).await?;
+        let source = stream.header().unwrap().source;
+        server.serve_from(BufStream::new(stream), source).await
+    });
+
+    let mut client = BufStream::new(client);
+    let header = ProxyHeader::new(
+        "203.0.113.7:5000".parse().unwrap(),
+        "192.0.2.2:8080".parse().unwrap(),
+    );
+    header.write(&mut client, Version::V1).await.unwrap();
+    let request = format!("GET http://{}/ HTTP/1.0\r\n\r\n", origin_addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.flush().await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(body.starts_with("203.0.113.7:5000\r\n"));
+    assert!(body.contains("forwarded: for=203.0.113.7;"));
+
+    // A CONNECT tunnel sees the client address of the header too.
+    let (client, proxy) = tokio::io::duplex(4096);
+    let tunnel = tokio::spawn(async move {
+        let stream = ProxiedStream::accept(proxy).await.unwrap();
+        let source = stream.header().unwrap().source;
+        let (_, target, _) = builder.handshake(BufStream::new(stream)).await.unwrap();
+        (source, target)
+    });
+    let mut client = BufStream::new(client);
+    header.write(&mut client, Version::V2).await.unwrap();
+    client
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+    let (source, target) = tunnel.await.unwrap();
+    assert_eq!(source, header.source);
+    assert_eq!(target, "example.com:443");
+
+    let (client, proxy) = tokio::io::duplex(4096);
+    let mut client = BufStream::new(client);
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    client.flush().await.unwrap();
+    assert!(ProxiedStream::accept(proxy).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() {
+    use aries::{unix::UnixListener, upstream::Connector};
+
+    let dir = std::env::temp_dir().join(format!("leo-unix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let origin = UnixListener::bind(dir.join("origin.sock")).unwrap();
+    tokio::spawn(async move {
+        let mut stream = BufStream::new(origin.accept().await.unwrap());
+        let head = read_head(&mut stream).await;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            head.len(),
+            head
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    });
+
+    let listen = UnixListener::bind(dir.join("proxy.sock")).unwrap();
+    listen.set_mode(0o660).unwrap();
+    let connector = Connector::new().add_unix_socket("app.internal", dir.join("origin.sock"));
+    let builder = server::Builder::default().set_pool(Pool::new().set_connector(connector));
+    tokio::spawn(async move {
+        let stream = listen.accept().await.unwrap();
+        builder.serve(BufStream::new(stream)).await.unwrap();
+    });
+
+    let stream = tokio::net::UnixStream::connect(dir.join("proxy.sock"))
+        .await
+        .unwrap();
+    let mut client = BufStream::new(stream);
+    client
+        .write_all(b"GET http://app.internal/health HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(body.starts_with("GET /health HTTP/1.1\r\n"));
+    assert!(body.contains("host: app.internal\r\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_address() {
+    let (client, proxy) = tokio::io::duplex(4096);
+    let accepted = tokio::spawn(async move {
+        let (_, target, _) = server::Builder::default()
+            .handshake(BufStream::new(proxy))
+            .await
+            .unwrap();
+        target
+    });
+    client::Builder::default()
+        .set_address("[2001:db8::1]:443".parse().unwrap())
+        .handshake(BufStream::new(client))
+        .await
+        .unwrap();
+    assert_eq!(accepted.await.unwrap(), "[2001:db8::1]:443");
+
+    let (client, _proxy) = tokio::io::duplex(4096);
+    let result = client::Builder::default()
+        .set_host_port("exa mple.com".to_string(), 443)
+        .handshake(BufStream::new(client))
+        .await;
+    assert!(matches!(result, Err(leo::Error::Http("invalid host"))));
+}
+
+#[tokio::test]
+async fn test_http2() {
+    let (client, proxy) = tokio::io::duplex(64 * 1024);
+    let builder = server::Builder::default().set_authenticator(
+        DigestUsers::new()
+            .add_user("hello", "world")
+            .set_algorithm(Algorithm::Md5),
+    );
+    tokio::spawn(async move {
+        let mut acceptor = builder.handshake_h2(proxy).await.unwrap();
+        while let Some((tunnel, target, identity)) = acceptor.accept().await.unwrap() {
+            assert_eq!(identity.unwrap().name(), "hello");
+            tokio::spawn(async move {
+                let greeting = format!("{} {:?} {:?}\n", target, tunnel.protocol(), tunnel.path());
+                let (mut reader, mut writer) = tokio::io::split(tunnel);
+                writer.write_all(greeting.as_bytes()).await.unwrap();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    });
+
+    let send_request = leo::http2::connect(client).await.unwrap();
+    let builder = client::Builder::default()
+        .set_authorization("hello", "world")
+        .set_host_port("example.com".to_string(), 443);
+    let mut first = BufReader::new(builder.handshake_h2(&send_request).await.unwrap());
+    let mut second = BufReader::new(
+        builder
+            .clone()
+            .set_extended_connect("websocket", "https", "/chat")
+            .handshake_h2(&send_request)
+            .await
+            .unwrap(),
+    );
+
+    let mut line = String::new();
+    first.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "example.com:443 None None\n");
+    line.clear();
+    second.read_line(&mut line).await.unwrap();
+    assert_eq!(
+        line,
+        "example.com:443 Some(\"websocket\") Some(\"/chat\")\n"
+    );
+
+    // Both tunnels share the connection and carry their own bytes.
+    second.write_all(b"second").await.unwrap();
+    first.write_all(b"first").await.unwrap();
+    first.shutdown().await.unwrap();
+    second.shutdown().await.unwrap();
+    let mut body = String::new();
+    first.read_to_string(&mut body).await.unwrap();
+    assert_eq!(body, "first");
+    body.clear();
+    second.read_to_string(&mut body).await.unwrap();
+    assert_eq!(body, "second");
+
+    let rejected = client::Builder::default()
+        .set_authorization("hello", "wrong")
+        .set_host_port("example.com".to_string(), 443)
+        .handshake_h2(&send_request)
+        .await;
+    assert!(rejected.is_err());
+}
+
+async fn read_head<T: AsyncBufReadExt + Unpin>(io: &mut T) -> String {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if io.read_line(&mut line).await.unwrap() == 0 {
+            return head;
+        }
+        head.push_str(&line);
+        if line == "\r\n" {
+            return head;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_connector() {
+    let echo_listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = echo_listen.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listen.accept().await.unwrap();
+        let (mut src, dst, _) = server::Builder::default()
+            .handshake(BufStream::new(stream))
+            .await
+            .unwrap();
+        let mut dst = TcpStream::connect(&dst).await.unwrap();
+        tokio::io::copy_bidirectional(&mut src, &mut dst)
+            .await
+            .unwrap();
+        // Refuse the second tunnel.
+        let (mut stream, _) = listen.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let connector: Box<dyn ProxyConnector> =
+        Box::new(client::Builder::default().set_proxy(addr.into()));
+    assert_eq!(connector.protocol(), Protocol::HttpConnect);
+    let mut stream = connector.connect(&echo_addr.into()).await.unwrap();
+    stream.write_all(b"hello world\r\n").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut data = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut data)
+        .await
+        .unwrap();
+    assert_eq!(data, "hello world\r\n");
+    drop(stream);
+
+    let e = match connector.connect(&echo_addr.into()).await {
+        Err(e) => e,
+        Ok(_) => panic!("tunnel opened"),
+    };
+    assert!(matches!(
+        e.downcast_ref::<leo::Error>(),
+        Some(leo::Error::HttpStatus("Forbidden"))
+    ));
+
+    let result = client::Builder::default().connect(&echo_addr.into()).await;
+    assert!(matches!(result, Err(ConnectError::MissingProxy)));
+}
+
+#[tokio::test]
+async fn test_proxy_url() {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listen.accept().await.unwrap();
+        let (_, host, identity) = server::Builder::default()
+            .set_authenticator(BasicUsers::new().add_user("alice", "p@ss"))
+            .handshake(BufStream::new(stream))
+            .await
+            .unwrap();
+        (host, identity.unwrap().name().to_string())
+    });
+
+    let url: ProxyUrl = format!("http://alice:p%40ss@{}", addr).parse().unwrap();
+    let connector = client::Builder::try_from(&url).unwrap();
+    connector
+        .connect(&"example.com:443".parse().unwrap())
+        .await
+        .unwrap();
+    let (host, name) = handle.await.unwrap();
+    assert_eq!(host, "example.com:443");
+    assert_eq!(name, "alice");
+
+    let url: ProxyUrl = "socks5://127.0.0.1".parse().unwrap();
+    assert!(client::Builder::try_from(&url).is_err());
+}
@@ -1,5 +1,11 @@
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, sync::Arc};
 
+use aries::{
+    address::Address,
+    connector::{self, BoxStream, ConnectError, Protocol, ProxyConnector},
+    proxy_url::{ProxyUrl, Scheme},
+};
+use async_trait::async_trait;
 use log::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::Decoder;
@@ -9,13 +15,28 @@ use crate::{
         rep_str, send_wait, Codec, DecoderState, Item, AUTH_SUCCEED, CONNECT,
         NO_AUTHENTICATION_REQUIRED, SUCCEEDED, USERNAME_AND_PASSWORD,
     },
-    errors, Destination,
+    errors,
+    resolver::{self, Resolution, Resolver},
+    Destination,
 };
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default)]
 pub struct Builder {
     authorization: Option<(String, String)>,
     destination: Option<Destination>,
+    proxy: Option<Address>,
+    resolution: Resolution,
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("destination", &self.destination)
+            .field("proxy", &self.proxy)
+            .field("resolution", &self.resolution)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Builder {
@@ -64,11 +85,25 @@ impl Builder {
         }
 
         // Write destination
-        let (atyp, addr, port) = self
+        let mut destination = self
             .destination
             .clone()
-            .ok_or_else(|| errors::Error::AddressTypeNotSupported)?
-            .into_tuple();
+            .ok_or(errors::Error::AddressTypeNotSupported)?;
+        if !destination.is_socket_addr() {
+            let domain = destination.host().unwrap_or_default();
+            let resolved = resolver::resolve(
+                self.resolver.as_deref(),
+                self.resolution,
+                domain,
+                destination.port(),
+                |_| true,
+            )
+            .await?;
+            if let Some(addr) = resolved {
+                destination = addr.into();
+            }
+        }
+        let (atyp, addr, port) = destination.into_tuple();
         if let Item::Reply(rep, atyp, host, port) = send_wait(
             &mut frame,
             Item::Command(CONNECT, atyp, addr, port),
@@ -94,13 +129,104 @@ impl Builder {
         self
     }
 
-    pub fn set_domain(mut self, domain: String, port: u16) -> Self {
-        self.destination = Some((domain, port).into());
-        self
+    /// Sets a domain destination, failing for a domain that is empty or
+    /// longer than 255 bytes.
+    pub fn set_domain(mut self, domain: String, port: u16) -> Result<Self, errors::Error> {
+        self.destination = Some(Destination::domain(&domain, port)?);
+        Ok(self)
     }
 
     pub fn set_addr(mut self, addr: SocketAddr) -> Self {
         self.destination = Some(addr.into());
         self
     }
+
+    pub fn set_address(mut self, address: Address) -> Result<Self, errors::Error> {
+        self.destination = Some(address.try_into()?);
+        Ok(self)
+    }
+
+    pub fn set_destination(mut self, destination: Destination) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Sets the SOCKS server dialed by [`ProxyConnector::connect`].
+    pub fn set_proxy(mut self, proxy: Address) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the destination from a host that is either an IP address or a
+    /// domain, which is resolved as set by [`Builder::set_resolution`].
+    pub fn set_host_port(self, host: &str, port: u16) -> Result<Self, errors::Error> {
+        let address = Address::new(host, port)
+            .map_err(|_| errors::Error::InvalidDestination("invalid host"))?;
+        self.set_address(address)
+    }
+
+    /// Sets where a domain destination is resolved, by the proxy unless
+    /// set otherwise.
+    pub fn set_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Resolves domain destinations with `resolver` instead of the system
+    /// resolver.
+    pub fn set_resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Resolver + 'static,
+    {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+}
+
+/// Configures the proxy, credentials and resolution of a `socks5://` or
+/// `socks5h://` URL.
+impl TryFrom<&ProxyUrl> for Builder {
+    type Error = aries::Error;
+
+    fn try_from(url: &ProxyUrl) -> Result<Self, Self::Error> {
+        if !matches!(url.scheme(), Scheme::Socks5 | Scheme::Socks5h) {
+            return Err(aries::Error::InvalidProxyUrl("not a socks5 url"));
+        }
+        let mut builder = Builder::default()
+            .set_proxy(url.proxy().clone())
+            .set_resolution(resolution(url.scheme()));
+        if let Some((username, password)) = url.credentials() {
+            builder = builder.set_authorization(username.to_string(), password.to_string());
+        }
+        Ok(builder)
+    }
+}
+
+/// The resolution of a proxy URL scheme, like curl.
+pub(crate) fn resolution(scheme: Scheme) -> Resolution {
+    if scheme.resolves_remotely() {
+        Resolution::Remote
+    } else {
+        Resolution::Local
+    }
+}
+
+#[async_trait]
+impl ProxyConnector for Builder {
+    fn protocol(&self) -> Protocol {
+        Protocol::Socks5
+    }
+
+    async fn connect(&self, target: &Address) -> Result<BoxStream, ConnectError> {
+        let builder = self
+            .clone()
+            .set_address(target.clone())
+            .map_err(|e| ConnectError::handshake(Protocol::Socks5, e))?;
+        let stream = connector::dial(self.proxy.as_ref()).await?;
+        let stream = builder
+            .handshake(stream)
+            .await
+            .map_err(|e| ConnectError::handshake(Protocol::Socks5, e))?;
+        Ok(Box::new(stream))
+    }
 }
@@ -290,7 +290,7 @@ impl codec::Decoder for Codec {
                             }
                         }
                         DST_DOMAIN => {
-                            if src.len() < 7 || src[5] as usize > src.len() - 7 {
+                            if src.len() < 7 || src[4] as usize > src.len() - 7 {
                                 Ok(None)
                             } else {
                                 assert!(src.get_u8() == SOCKS_VERSION, "Invalid SOCKS version");
@@ -336,7 +336,7 @@ impl codec::Decoder for Codec {
                             }
                         }
                         DST_DOMAIN => {
-                            if src.len() < 7 || src[5] as usize > src.len() - 7 {
+                            if src.len() < 7 || src[4] as usize > src.len() - 7 {
                                 Ok(None)
                             } else {
                                 assert!(src.get_u8() == SOCKS_VERSION, "Invalid SOCKS version");
@@ -12,6 +12,9 @@ pub enum Error {
     #[error("address type not supported")]
     AddressTypeNotSupported,
 
+    #[error("invalid destination:{0}")]
+    InvalidDestination(&'static str),
+
     #[error("{1}({0})")]
     Rep(u8, &'static str),
 
@@ -24,6 +27,36 @@ pub enum Error {
     #[error("io error:{0}")]
     Io(#[from] std::io::Error),
 
+    #[cfg(feature = "rustls")]
+    #[error("rustls error:{0}")]
+    Rustls(#[from] rustls::Error),
+
+    #[cfg(feature = "tokio-native-tls")]
+    #[error("native tls error:{0}")]
+    NativeTls(#[from] tokio_native_tls::native_tls::Error),
+
+    #[error("tls error:{0}")]
+    Tls(&'static str),
+
+    #[error("proxy protocol error:{0}")]
+    ProxyProtocol(aries::Error),
+
+    #[error("connection not redirected")]
+    NotRedirected,
+
+    #[error("upstream proxy error:{0}")]
+    Upstream(#[from] aries::connector::ConnectError),
+
     #[error("unknown")]
     Unknown,
 }
+
+/// The TLS errors of the shared rustls helpers keep their own variant.
+impl From<aries::Error> for Error {
+    fn from(e: aries::Error) -> Self {
+        match e {
+            aries::Error::Tls(reason) => Error::Tls(reason),
+            e => Error::ProxyProtocol(e),
+        }
+    }
+}
@@ -1,20 +1,37 @@
-#![feature(slice_as_chunks)]
-#![feature(impl_trait_in_assoc_type)]
 #![allow(dead_code)]
 pub mod client;
 
 mod codec;
 
 mod errors;
+pub mod resolver;
 pub mod server;
+pub mod socks4;
+#[cfg(any(feature = "rustls", feature = "tokio-native-tls"))]
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod transparent;
 pub use errors::Error;
 use tokio::net::TcpStream;
 
 use std::{
     fmt, io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
+/// The credentials of the process at the other end of a Unix socket.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not every platform reports the process id.
+    pub pid: Option<i32>,
+}
+
+/// The addresses of a connection. Streams without IP addresses, such as
+/// Unix sockets and in-memory pipes, answer with an error of kind
+/// [`io::ErrorKind::Unsupported`].
 pub trait Peer {
     fn local_addr(&self) -> io::Result<SocketAddr>;
 
@@ -23,6 +40,20 @@ pub trait Peer {
     fn peer_addr(&self) -> io::Result<(SocketAddr, SocketAddr)> {
         Ok((self.local_addr()?, self.remote_addr()?))
     }
+
+    /// Returns the credentials of the peer process, known for Unix sockets
+    /// only.
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        Err(unsupported("peer credentials"))
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} not supported by the stream", what),
+    )
 }
 
 impl Peer for TcpStream {
@@ -45,6 +76,85 @@ impl Peer for std::net::TcpStream {
     }
 }
 
+#[cfg(unix)]
+impl Peer for tokio::net::UnixStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("ip address"))
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("ip address"))
+    }
+
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        let cred = self.peer_cred()?;
+        Ok(Credentials {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+
+impl Peer for aries::upstream::Stream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        aries::upstream::Stream::local_addr(self)
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        aries::upstream::Stream::peer_addr(self)
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        match self {
+            aries::upstream::Stream::Unix(stream) => stream.peer_credentials(),
+            _ => Err(unsupported("peer credentials")),
+        }
+    }
+}
+
+impl Peer for tokio::io::DuplexStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("ip address"))
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        Err(unsupported("ip address"))
+    }
+}
+
+/// Implements `Peer` for a wrapper stream by delegating to `get_ref()`.
+macro_rules! delegate_peer {
+    ($($ty:ty: $($bound:path)+;)*) => {
+        $(
+            impl<T> Peer for $ty
+            where
+                T: Peer $(+ $bound)+,
+            {
+                fn local_addr(&self) -> io::Result<SocketAddr> {
+                    self.get_ref().local_addr()
+                }
+
+                fn remote_addr(&self) -> io::Result<SocketAddr> {
+                    self.get_ref().remote_addr()
+                }
+
+                #[cfg(unix)]
+                fn peer_credentials(&self) -> io::Result<Credentials> {
+                    self.get_ref().peer_credentials()
+                }
+            }
+        )*
+    };
+}
+
+delegate_peer!(
+    tokio::io::BufStream<T>: tokio::io::AsyncRead tokio::io::AsyncWrite;
+    tokio::io::BufReader<T>: tokio::io::AsyncRead;
+    tokio::io::BufWriter<T>: tokio::io::AsyncWrite;
+);
+
 #[cfg(feature = "tokio-native-tls")]
 impl<T> Peer for tokio_native_tls::TlsStream<T>
 where
@@ -57,11 +167,104 @@ where
     fn remote_addr(&self) -> io::Result<SocketAddr> {
         self.get_ref().get_ref().get_ref().remote_addr()
     }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        self.get_ref().get_ref().get_ref().peer_credentials()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl<T> Peer for tokio_rustls::server::TlsStream<T>
+where
+    T: Peer,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        self.get_ref().0.peer_credentials()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl<T> Peer for tokio_rustls::client::TlsStream<T>
+where
+    T: Peer,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        self.get_ref().0.peer_credentials()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl<T> Peer for tokio_rustls::TlsStream<T>
+where
+    T: Peer,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        self.get_ref().0.peer_credentials()
+    }
+}
+
+/// The addresses carried by the PROXY protocol header, or those of the
+/// connection if the header has none.
+impl<T> Peer for aries::proxy_protocol::ProxiedStream<T>
+where
+    T: Peer,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.header() {
+            Some(header) => Ok(header.destination),
+            None => self.get_ref().local_addr(),
+        }
+    }
+
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        match self.header() {
+            Some(header) => Ok(header.source),
+            None => self.get_ref().remote_addr(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> io::Result<Credentials> {
+        self.get_ref().peer_credentials()
+    }
 }
 
 use codec::{DST_DOMAIN, DST_IPV4, DST_IPV6};
 
-#[derive(Debug, Clone)]
+/// The longest domain name of a SOCKS5 request, its length is one byte.
+const MAX_DOMAIN_LENGTH: usize = 255;
+
+/// The target of a SOCKS5 request. A destination is valid once built: an
+/// IPv4 host has 4 bytes, an IPv6 host 16 bytes and a domain 1 to 255.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Destination {
     aty: u8,
     host: Vec<u8>,
@@ -79,8 +282,57 @@ impl Default for Destination {
 }
 
 impl Destination {
-    pub fn new(aty: u8, host: Vec<u8>, port: u16) -> Self {
-        Self { aty, host, port }
+    pub fn new(aty: u8, host: Vec<u8>, port: u16) -> Result<Self, errors::Error> {
+        let valid = match aty {
+            DST_IPV4 => host.len() == 4,
+            DST_IPV6 => host.len() == 16,
+            DST_DOMAIN => !host.is_empty() && host.len() <= MAX_DOMAIN_LENGTH,
+            _ => return Err(errors::Error::AddressTypeNotSupported),
+        };
+        if !valid {
+            return Err(errors::Error::InvalidDestination("invalid host length"));
+        }
+        Ok(Self { aty, host, port })
+    }
+
+    pub fn domain(domain: &str, port: u16) -> Result<Self, errors::Error> {
+        Self::new(DST_DOMAIN, domain.as_bytes().to_vec(), port)
+    }
+
+    /// Parses the `ATYP`, `DST.ADDR` and `DST.PORT` fields of RFC 1928
+    /// section 4, with nothing following them.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, errors::Error> {
+        let (&aty, rest) = bytes
+            .split_first()
+            .ok_or(errors::Error::InvalidDestination("missing address type"))?;
+        let (host, rest) = match aty {
+            DST_IPV4 if rest.len() >= 4 => rest.split_at(4),
+            DST_IPV6 if rest.len() >= 16 => rest.split_at(16),
+            DST_DOMAIN => match rest.split_first() {
+                Some((&len, rest)) if rest.len() >= len as usize => rest.split_at(len as usize),
+                _ => return Err(errors::Error::InvalidDestination("truncated address")),
+            },
+            DST_IPV4 | DST_IPV6 => {
+                return Err(errors::Error::InvalidDestination("truncated address"))
+            }
+            _ => return Err(errors::Error::AddressTypeNotSupported),
+        };
+        let [hi, lo] = rest else {
+            return Err(errors::Error::InvalidDestination("invalid port"));
+        };
+        Self::new(aty, host.to_vec(), u16::from_be_bytes([*hi, *lo]))
+    }
+
+    /// Encodes the destination like [`Destination::from_bytes`] parses it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.host.len() + 4);
+        bytes.push(self.aty);
+        if self.aty == DST_DOMAIN {
+            bytes.push(self.host.len() as u8);
+        }
+        bytes.extend_from_slice(&self.host);
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes
     }
 
     pub fn into_tuple(self) -> (u8, Vec<u8>, u16) {
@@ -94,16 +346,13 @@ impl Destination {
     pub fn as_socket_addr(&self) -> Option<SocketAddr> {
         match self.aty {
             DST_IPV4 => {
-                debug_assert!(self.host.len() >= 4, "invalid ipv4");
-                Some(SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::new(self.host[0], self.host[1], self.host[2], self.host[3]),
-                    self.port,
-                )))
+                let ip: [u8; 4] = self.host[..].try_into().ok()?;
+                Some(SocketAddr::V4(SocketAddrV4::new(ip.into(), self.port)))
             }
             DST_IPV6 => {
-                debug_assert!(self.host.len() >= 16, "invalid ipv6");
+                let ip: [u8; 16] = self.host[..].try_into().ok()?;
                 Some(SocketAddr::V6(SocketAddrV6::new(
-                    Ipv6Addr::from(self.host.as_chunks().0[0]),
+                    ip.into(),
                     self.port,
                     0,
                     0,
@@ -114,11 +363,7 @@ impl Destination {
     }
 
     pub fn host(&self) -> Option<&str> {
-        if let Ok(v) = std::str::from_utf8(&self.host) {
-            Some(v)
-        } else {
-            None
-        }
+        std::str::from_utf8(&self.host).ok()
     }
 
     pub fn port(&self) -> u16 {
@@ -126,21 +371,31 @@ impl Destination {
     }
 }
 
-impl From<(u8, Vec<u8>, u16)> for Destination {
-    fn from(value: (u8, Vec<u8>, u16)) -> Self {
+impl TryFrom<(u8, Vec<u8>, u16)> for Destination {
+    type Error = errors::Error;
+
+    fn try_from(value: (u8, Vec<u8>, u16)) -> Result<Self, Self::Error> {
         Self::new(value.0, value.1, value.2)
     }
 }
 
 impl From<SocketAddrV4> for Destination {
     fn from(value: SocketAddrV4) -> Self {
-        Self::new(DST_IPV4, value.ip().octets().to_vec(), value.port())
+        Self {
+            aty: DST_IPV4,
+            host: value.ip().octets().to_vec(),
+            port: value.port(),
+        }
     }
 }
 
 impl From<SocketAddrV6> for Destination {
     fn from(value: SocketAddrV6) -> Self {
-        Self::new(DST_IPV6, value.ip().octets().to_vec(), value.port())
+        Self {
+            aty: DST_IPV6,
+            host: value.ip().octets().to_vec(),
+            port: value.port(),
+        }
     }
 }
 
@@ -153,32 +408,89 @@ impl From<SocketAddr> for Destination {
     }
 }
 
-impl From<(String, u16)> for Destination {
-    fn from(value: (String, u16)) -> Self {
+impl TryFrom<aries::address::Address> for Destination {
+    type Error = errors::Error;
+
+    fn try_from(value: aries::address::Address) -> Result<Self, Self::Error> {
+        match value.host() {
+            aries::address::Host::Ip(ip) => Ok(SocketAddr::new(*ip, value.port()).into()),
+            aries::address::Host::Domain(domain) => Self::domain(domain, value.port()),
+        }
+    }
+}
+
+impl TryFrom<(String, u16)> for Destination {
+    type Error = errors::Error;
+
+    fn try_from(value: (String, u16)) -> Result<Self, Self::Error> {
         Self::new(DST_DOMAIN, value.0.into_bytes(), value.1)
     }
 }
 
 impl fmt::Display for Destination {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.aty {
-            DST_IPV4 => SocketAddrV4::new(
-                Ipv4Addr::new(self.host[0], self.host[1], self.host[2], self.host[3]),
-                self.port,
-            )
-            .fmt(f),
-            DST_IPV6 => SocketAddrV6::new(
-                Ipv6Addr::from(self.host.as_chunks::<16>().0[0]),
-                self.port,
-                0,
-                0,
-            )
-            .fmt(f),
-            DST_DOMAIN => {
+        match self.as_socket_addr() {
+            Some(addr) => addr.fmt(f),
+            None => {
                 let domain = String::from_utf8_lossy(&self.host);
                 write!(f, "{}:{}", domain, self.port)
             }
-            _ => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codec::{DST_DOMAIN, DST_IPV4, DST_IPV6},
+        Destination, Error,
+    };
+
+    #[test]
+    fn test_destination() {
+        for (bytes, expected) in [
+            (&[DST_IPV4, 127, 0, 0, 1, 0x04, 0x38][..], "127.0.0.1:1080"),
+            (
+                &[
+                    DST_IPV6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80,
+                ],
+                "[::1]:80",
+            ),
+            (&[DST_DOMAIN, 1, b'a', 0, 80], "a:80"),
+        ] {
+            let destination = Destination::from_bytes(bytes).unwrap();
+            assert_eq!(destination.to_string(), expected);
+            assert_eq!(destination.to_bytes(), bytes);
+        }
+
+        for bytes in [
+            &[][..],
+            &[DST_IPV4, 127, 0, 0, 1, 0],
+            &[DST_IPV4, 127, 0, 0, 1, 0, 80, 0],
+            &[DST_IPV6, 0, 0, 0, 1, 0, 80],
+            &[DST_DOMAIN, 0, 0, 80],
+            &[DST_DOMAIN, 5, b'a', 0, 80],
+        ] {
+            assert!(matches!(
+                Destination::from_bytes(bytes),
+                Err(Error::InvalidDestination(_))
+            ));
+        }
+        assert!(matches!(
+            Destination::from_bytes(&[0x02, 0, 80]),
+            Err(Error::AddressTypeNotSupported)
+        ));
+
+        assert!(Destination::new(DST_IPV4, vec![127, 0, 0], 80).is_err());
+        assert!(Destination::new(DST_IPV6, vec![0; 4], 80).is_err());
+        assert!(Destination::domain(&"a".repeat(256), 80).is_err());
+        assert!(Destination::domain(&"a".repeat(255), 80).is_ok());
+
+        let address = aries::address::Address::new("Example.COM", 80).unwrap();
+        let destination = Destination::try_from(address).unwrap();
+        assert_eq!(destination.to_string(), "example.com:80");
+        assert!(crate::client::Builder::default()
+            .set_host_port("exa mple.com", 80)
+            .is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Resolution of domain destinations by the client, for proxies that are
+//! not trusted or not able to resolve them.
+
+use std::{io, net::SocketAddr};
+
+use async_trait::async_trait;
+use log::debug;
+
+/// Where a domain destination is resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    /// The domain is sent to the proxy and never resolved by the client, as
+    /// for `socks5h://`.
+    #[default]
+    Remote,
+    /// The domain is resolved by the client and the address is sent, a
+    /// failed resolution fails the handshake, as for `socks5://`.
+    Local,
+    /// The domain is resolved by the client, and sent to the proxy if that
+    /// fails.
+    LocalThenRemote,
+}
+
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves with the resolver of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((domain, port)).await?.collect())
+    }
+}
+
+/// Applies `resolution` to a domain destination, returning the first
+/// address accepted by `filter`, or `None` to send the domain.
+pub(crate) async fn resolve<F>(
+    resolver: Option<&dyn Resolver>,
+    resolution: Resolution,
+    domain: &str,
+    port: u16,
+    filter: F,
+) -> io::Result<Option<SocketAddr>>
+where
+    F: Fn(&SocketAddr) -> bool,
+{
+    if resolution == Resolution::Remote {
+        return Ok(None);
+    }
+    let result = resolver
+        .unwrap_or(&SystemResolver)
+        .resolve(domain, port)
+        .await
+        .and_then(|addrs| {
+            addrs.into_iter().find(filter).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address for {}", domain),
+                )
+            })
+        });
+    match result {
+        Ok(addr) => {
+            debug!("resolve {} to {}", domain, addr);
+            Ok(Some(addr))
+        }
+        Err(e) if resolution == Resolution::LocalThenRemote => {
+            debug!("send {} unresolved: {}", domain, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
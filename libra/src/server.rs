@@ -1,4 +1,7 @@
-use std::net::SocketAddr;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+};
 
 use futures_util::SinkExt;
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -7,8 +10,9 @@ use tokio_util::codec::Decoder;
 use crate::{
     codec::{
         recv, rep_str, Codec, DecoderState, Item, ADDRESS_TYPE_NOT_SUPPORTED, AUTH_FAILED,
-        AUTH_SUCCEED, COMMAND_NOT_SUPPORTED, CONNECT, DST_DOMAIN, DST_IPV4, DST_IPV6,
-        NO_ACCEPTABLE_METHODS, NO_AUTHENTICATION_REQUIRED, SUCCEEDED, USERNAME_AND_PASSWORD,
+        AUTH_SUCCEED, COMMAND_NOT_SUPPORTED, CONNECT, DST_IPV4, DST_IPV6,
+        GENERAL_SOCKS_SERVER_FAILURE, NO_ACCEPTABLE_METHODS, NO_AUTHENTICATION_REQUIRED, SUCCEEDED,
+        USERNAME_AND_PASSWORD,
     },
     errors, Destination, Peer,
 };
@@ -17,17 +21,33 @@ use crate::{
 pub struct Builder {
     authorization: Option<(String, String)>,
     bind_addr: Option<SocketAddr>,
+    peer_identity: Option<String>,
+    peer_identity_skips_auth: bool,
 }
 
 impl Builder {
-    pub async fn handshake<T>(&self, io: T) -> Result<(T, String), errors::Error>
+    /// Runs the SOCKS5 handshake, returning the stream, the requested
+    /// destination and the identity of the client: the username it
+    /// authenticated with, or the peer identity if it was not asked for one.
+    pub async fn handshake<T>(&self, io: T) -> Result<(T, String, Option<String>), errors::Error>
     where
         T: AsyncRead + AsyncWrite + Peer + Unpin,
     {
-        let local_addr = io.local_addr()?;
+        // A stream without an IP address replies with the unspecified one.
+        let local_addr = match io.local_addr() {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+            }
+            local_addr => local_addr?,
+        };
         let mut frame = Codec::new(DecoderState::Methods).framed(io);
+        let mut identity = self.peer_identity.clone();
         if let Item::Methods(methods) = recv(&mut frame, DecoderState::Methods).await? {
-            if let Some((user, pass)) = &self.authorization {
+            let authorization = match &self.authorization {
+                Some(_) if self.peer_identity_skips_auth && self.peer_identity.is_some() => None,
+                authorization => authorization.as_ref(),
+            };
+            if let Some((user, pass)) = authorization {
                 if methods.contains(&USERNAME_AND_PASSWORD) {
                     frame.send(Item::Selection(USERNAME_AND_PASSWORD)).await?;
                     if let Item::UsernamePassword(u, p) =
@@ -35,6 +55,7 @@ impl Builder {
                     {
                         if user == &u && pass == &p {
                             frame.send(Item::Status(AUTH_SUCCEED)).await?;
+                            identity = Some(u);
                         } else {
                             frame.send(Item::Status(AUTH_FAILED)).await?;
                             return Err(errors::Error::Unauthorized);
@@ -71,25 +92,22 @@ impl Builder {
                 ));
             }
 
-            match atyp {
-                DST_IPV4 | DST_IPV6 | DST_DOMAIN => {
-                    destination = Some(Destination::new(atyp, host, port));
-                }
-                _ => {
+            match Destination::new(atyp, host, port) {
+                Ok(v) => destination = Some(v),
+                Err(e) => {
+                    let rep = match e {
+                        errors::Error::AddressTypeNotSupported => ADDRESS_TYPE_NOT_SUPPORTED,
+                        _ => GENERAL_SOCKS_SERVER_FAILURE,
+                    };
                     frame
-                        .send(Item::Reply(
-                            ADDRESS_TYPE_NOT_SUPPORTED,
-                            DST_IPV4,
-                            vec![0, 0, 0, 0],
-                            0,
-                        ))
+                        .send(Item::Reply(rep, DST_IPV4, vec![0, 0, 0, 0], 0))
                         .await?;
-                    return Err(errors::Error::AddressTypeNotSupported);
+                    return Err(e);
                 }
             }
         }
 
-        let bnd_addr = if let Some(addr) = self.bind_addr.clone() {
+        let bnd_addr = if let Some(addr) = self.bind_addr {
             addr
         } else {
             local_addr
@@ -101,7 +119,11 @@ impl Builder {
         };
 
         frame.send(Item::Reply(SUCCEEDED, atyp, addr, port)).await?;
-        Ok((frame.into_inner(), destination.unwrap().to_string()))
+        Ok((
+            frame.into_inner(),
+            destination.unwrap().to_string(),
+            identity,
+        ))
     }
 
     pub fn set_authorization(mut self, username: String, password: String) -> Self {
@@ -113,4 +135,19 @@ impl Builder {
         self.bind_addr = Some(bind);
         self
     }
+
+    /// Sets the name the connection was authenticated with before SOCKS5,
+    /// such as by a TLS client certificate. It is set per connection on a
+    /// clone of the builder.
+    pub fn set_peer_identity(mut self, identity: String) -> Self {
+        self.peer_identity = Some(identity);
+        self
+    }
+
+    /// Sets whether a connection with a peer identity skips the username and
+    /// password authentication, it does not by default.
+    pub fn set_peer_identity_skips_auth(mut self, peer_identity_skips_auth: bool) -> Self {
+        self.peer_identity_skips_auth = peer_identity_skips_auth;
+        self
+    }
 }
@@ -0,0 +1,244 @@
+//! A SOCKS4 client, with the SOCKS4a extension for domain destinations.
+
+use std::{fmt, net::IpAddr, sync::Arc};
+
+use aries::{
+    address::{Address, Host},
+    connector::{self, BoxStream, ConnectError, Protocol, ProxyConnector},
+    proxy_url::{ProxyUrl, Scheme},
+};
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    client, errors,
+    resolver::{self, Resolution, Resolver},
+};
+
+const VERSION: u8 = 0x04;
+const CONNECT: u8 = 0x01;
+
+const GRANTED: u8 = 90;
+const REJECTED: u8 = 91;
+const IDENTD_UNREACHABLE: u8 = 92;
+const IDENTD_MISMATCH: u8 = 93;
+
+fn rep_str(rep: u8) -> &'static str {
+    match rep {
+        REJECTED => "request rejected or failed",
+        IDENTD_UNREACHABLE => "identd unreachable",
+        IDENTD_MISMATCH => "identd user mismatch",
+        _ => "unknown reply",
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Builder {
+    user_id: String,
+    destination: Option<Address>,
+    proxy: Option<Address>,
+    resolution: Resolution,
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("user_id", &self.user_id)
+            .field("destination", &self.destination)
+            .field("proxy", &self.proxy)
+            .field("resolution", &self.resolution)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Builder {
+    /// Sends a `CONNECT` request, a domain destination is sent as in
+    /// SOCKS4a unless resolved to an IPv4 address as set by
+    /// [`Builder::set_resolution`], and an IPv6 one is not supported.
+    pub async fn handshake<T>(&self, mut io: T) -> Result<T, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut destination = self
+            .destination
+            .clone()
+            .ok_or(errors::Error::AddressTypeNotSupported)?;
+        if let Host::Domain(domain) = destination.host() {
+            let resolved = resolver::resolve(
+                self.resolver.as_deref(),
+                self.resolution,
+                domain,
+                destination.port(),
+                |v| v.is_ipv4(),
+            )
+            .await?;
+            if let Some(addr) = resolved {
+                destination = addr.into();
+            }
+        }
+
+        let mut buf = BytesMut::new();
+        buf.put_u8(VERSION);
+        buf.put_u8(CONNECT);
+        buf.put_u16(destination.port());
+        match destination.host() {
+            Host::Ip(IpAddr::V4(ip)) => {
+                buf.put_slice(&ip.octets());
+                buf.put_slice(self.user_id.as_bytes());
+                buf.put_u8(0);
+            }
+            // SOCKS4a, an address of 0.0.0.x with x nonzero is followed by
+            // the domain.
+            Host::Domain(domain) => {
+                buf.put_slice(&[0, 0, 0, 1]);
+                buf.put_slice(self.user_id.as_bytes());
+                buf.put_u8(0);
+                buf.put_slice(domain.as_bytes());
+                buf.put_u8(0);
+            }
+            Host::Ip(IpAddr::V6(_)) => return Err(errors::Error::AddressTypeNotSupported),
+        }
+        io.write_all(&buf).await?;
+        io.flush().await?;
+
+        let mut reply = [0; 8];
+        io.read_exact(&mut reply).await?;
+        debug!("reply with ({:?}, {:?})", reply[0], reply[1]);
+        if reply[0] != 0 {
+            return Err(errors::Error::InvalidVersion);
+        }
+        if reply[1] != GRANTED {
+            return Err(errors::Error::Rep(reply[1], rep_str(reply[1])));
+        }
+        Ok(io)
+    }
+
+    /// Sets the user id sent with the request, empty by default.
+    pub fn set_user_id(mut self, user_id: &str) -> Self {
+        self.user_id = user_id.to_string();
+        self
+    }
+
+    /// Sets the target host and port, failing for an invalid host.
+    pub fn set_host_port(self, host: &str, port: u16) -> Result<Self, errors::Error> {
+        let address = Address::new(host, port)
+            .map_err(|_| errors::Error::InvalidDestination("invalid host"))?;
+        Ok(self.set_address(address))
+    }
+
+    pub fn set_address(mut self, address: Address) -> Self {
+        self.destination = Some(address);
+        self
+    }
+
+    /// Sets the SOCKS server dialed by [`ProxyConnector::connect`].
+    pub fn set_proxy(mut self, proxy: Address) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets where a domain destination is resolved, by the proxy unless
+    /// set otherwise. A server without SOCKS4a needs [`Resolution::Local`].
+    pub fn set_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Resolves domain destinations with `resolver` instead of the system
+    /// resolver.
+    pub fn set_resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Resolver + 'static,
+    {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+}
+
+/// Configures the proxy, user id and resolution of a `socks4://` or
+/// `socks4a://` URL, the username is sent as the user id.
+impl TryFrom<&ProxyUrl> for Builder {
+    type Error = aries::Error;
+
+    fn try_from(url: &ProxyUrl) -> Result<Self, Self::Error> {
+        if !matches!(url.scheme(), Scheme::Socks4 | Scheme::Socks4a) {
+            return Err(aries::Error::InvalidProxyUrl("not a socks4 url"));
+        }
+        let mut builder = Builder::default()
+            .set_proxy(url.proxy().clone())
+            .set_resolution(client::resolution(url.scheme()));
+        if let Some((username, _)) = url.credentials() {
+            builder = builder.set_user_id(username);
+        }
+        Ok(builder)
+    }
+}
+
+#[async_trait]
+impl ProxyConnector for Builder {
+    fn protocol(&self) -> Protocol {
+        Protocol::Socks4
+    }
+
+    async fn connect(&self, target: &Address) -> Result<BoxStream, ConnectError> {
+        let stream = connector::dial(self.proxy.as_ref()).await?;
+        let stream = self
+            .clone()
+            .set_address(target.clone())
+            .handshake(stream)
+            .await
+            .map_err(|e| ConnectError::handshake(Protocol::Socks4, e))?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::Builder;
+    use crate::Error;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let task = tokio::spawn(async move {
+            Builder::default()
+                .set_user_id("alice")
+                .set_host_port("example.com", 443)
+                .unwrap()
+                .handshake(client)
+                .await
+        });
+        let mut request = [0; 26];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"\x04\x01\x01\xbb\0\0\0\x01alice\0example.com\0");
+        server.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        task.await.unwrap().unwrap();
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let task = tokio::spawn(async move {
+            Builder::default()
+                .set_host_port("192.0.2.1", 80)
+                .unwrap()
+                .handshake(client)
+                .await
+        });
+        let mut request = [0; 9];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"\x04\x01\0\x50\xc0\0\x02\x01\0");
+        server.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        assert!(matches!(task.await.unwrap(), Err(Error::Rep(91, _))));
+
+        let (client, _server) = tokio::io::duplex(64);
+        let result = Builder::default()
+            .set_host_port("::1", 80)
+            .unwrap()
+            .handshake(client)
+            .await;
+        assert!(matches!(result, Err(Error::AddressTypeNotSupported)));
+    }
+}
@@ -0,0 +1,17 @@
+//! SOCKS5 over TLS, with either native-tls or rustls. Both backends have the
+//! same API: a `TlsListener` accepts connections, `Handshake::finish` runs
+//! their TLS handshake and the streams are given to
+//! [`crate::server::Builder::handshake`], and the `TlsConnector` built by a
+//! `ClientBuilder` runs the TLS handshake before
+//! [`crate::client::Builder::handshake`].
+
+use std::time::Duration;
+
+#[cfg(feature = "tokio-native-tls")]
+pub mod native_tls;
+#[cfg(feature = "rustls")]
+pub mod rustls;
+
+/// How long a `TlsListener` waits for a TLS handshake unless configured
+/// otherwise.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
@@ -0,0 +1,243 @@
+//! The native-tls backend, OpenSSL on Linux and the platform TLS library
+//! elsewhere.
+
+use std::{io, net::SocketAddr, path::Path, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tokio_native_tls::native_tls::{self, Certificate, Identity};
+
+use super::DEFAULT_HANDSHAKE_TIMEOUT;
+use crate::{client, errors};
+
+pub use tokio_native_tls::TlsStream;
+
+/// Configures the TLS connection to a proxy.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    roots: Vec<Vec<u8>>,
+    built_in_roots: bool,
+    sni: bool,
+    danger_accept_invalid_certs: bool,
+    client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            built_in_roots: true,
+            sni: true,
+            danger_accept_invalid_certs: false,
+            client_certificate: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the PEM encoded certificate, such as a private CA.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Result<Self, errors::Error> {
+        Certificate::from_pem(pem)?;
+        self.roots.push(pem.to_vec());
+        Ok(self)
+    }
+
+    /// Sets whether the system root certificates are trusted, they are by
+    /// default.
+    pub fn set_built_in_roots(mut self, built_in_roots: bool) -> Self {
+        self.built_in_roots = built_in_roots;
+        self
+    }
+
+    /// Sets whether the proxy name is sent with Server Name Indication, it
+    /// is by default.
+    pub fn set_sni(mut self, sni: bool) -> Self {
+        self.sni = sni;
+        self
+    }
+
+    /// Accepts any certificate, for testing only.
+    pub fn set_danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.danger_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Presents the PEM encoded certificate chain and PKCS #8 private key to
+    /// a proxy that authenticates clients by certificate.
+    pub fn set_client_certificate(
+        mut self,
+        cert_chain: &[u8],
+        key: &[u8],
+    ) -> Result<Self, errors::Error> {
+        Identity::from_pkcs8(cert_chain, key)?;
+        self.client_certificate = Some((cert_chain.to_vec(), key.to_vec()));
+        Ok(self)
+    }
+
+    pub fn build(&self) -> Result<TlsConnector, errors::Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.roots {
+            builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        if let Some((cert_chain, key)) = &self.client_certificate {
+            builder.identity(Identity::from_pkcs8(cert_chain, key)?);
+        }
+        let connector = builder
+            .disable_built_in_roots(!self.built_in_roots)
+            .use_sni(self.sni)
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs)
+            .build()?;
+        Ok(TlsConnector {
+            connector: tokio_native_tls::TlsConnector::from(connector),
+        })
+    }
+}
+
+/// Connects to a SOCKS5 proxy over TLS.
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: tokio_native_tls::TlsConnector,
+}
+
+impl TlsConnector {
+    /// Runs the TLS handshake on `io`, verifying the certificate of the
+    /// proxy against `domain`.
+    pub async fn connect<T>(&self, domain: &str, io: T) -> Result<TlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.connector.connect(domain, io).await?)
+    }
+
+    /// Runs the TLS handshake and then the SOCKS5 handshake of `client`.
+    pub async fn handshake<T>(
+        &self,
+        client: &client::Builder,
+        domain: &str,
+        io: T,
+    ) -> Result<TlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        client.handshake(self.connect(domain, io).await?).await
+    }
+}
+
+/// Accepts TLS connections to the proxy.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_native_tls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Uses the PEM encoded certificate chain and PKCS #8 private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, errors::Error> {
+        let identity = Identity::from_pkcs8(cert_chain, key)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)?;
+        Ok(Self {
+            acceptor: tokio_native_tls::TlsAcceptor::from(acceptor),
+        })
+    }
+
+    /// Always fails, native-tls has no way to ask a client for a
+    /// certificate. Clients are authenticated by certificate with the
+    /// rustls listener only.
+    pub fn from_pem_with_client_auth(
+        _cert_chain: &[u8],
+        _key: &[u8],
+        _client_roots: &[u8],
+        _required: bool,
+    ) -> Result<Self, errors::Error> {
+        Err(errors::Error::Tls(
+            "client certificates are not supported by native-tls",
+        ))
+    }
+
+    /// Loads the PEM encoded certificate chain and PKCS #8 private key from
+    /// files.
+    pub fn from_pem_files<P>(cert_chain: P, key: P) -> Result<Self, errors::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(key)?)
+    }
+
+    /// Runs the TLS handshake on an accepted connection.
+    pub async fn accept<T>(&self, io: T) -> Result<TlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.acceptor.accept(io).await?)
+    }
+}
+
+/// A TCP listener whose connections are wrapped in TLS.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Self {
+        Self {
+            listener,
+            acceptor,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    pub async fn bind<A>(addr: A, acceptor: TlsAcceptor) -> Result<Self, errors::Error>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self::new(TcpListener::bind(addr).await?, acceptor))
+    }
+
+    /// Sets how long [`Handshake::finish`] waits for a TLS handshake.
+    pub fn set_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts a connection without running the TLS handshake, which the
+    /// caller runs with [`Handshake::finish`] in the connection's own task so
+    /// that a slow client does not hold up the next one.
+    pub async fn accept(&self) -> Result<(Handshake, SocketAddr), errors::Error> {
+        let (stream, addr) = self.listener.accept().await?;
+        let handshake = Handshake {
+            stream,
+            acceptor: self.acceptor.clone(),
+            timeout: self.handshake_timeout,
+        };
+        Ok((handshake, addr))
+    }
+}
+
+/// A connection accepted by a [`TlsListener`] whose TLS handshake has not
+/// run yet.
+pub struct Handshake {
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    timeout: Duration,
+}
+
+impl Handshake {
+    /// Runs the TLS handshake, failing when it takes longer than the
+    /// listener's handshake timeout.
+    pub async fn finish(self) -> Result<TlsStream<TcpStream>, errors::Error> {
+        tokio::time::timeout(self.timeout, self.acceptor.accept(self.stream))
+            .await
+            .map_err(|_| errors::Error::Tls("handshake timed out"))?
+    }
+}
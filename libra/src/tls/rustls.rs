@@ -0,0 +1,291 @@
+//! The rustls backend, with the `ring` crypto provider.
+
+use std::{io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use aries::tls::{parse_pem, NoVerifier};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+use rustls_pki_types::pem::PemObject;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use super::DEFAULT_HANDSHAKE_TIMEOUT;
+use crate::{client, errors};
+
+pub use aries::tls::{certificate_identity, peer_identity};
+pub use tokio_rustls::{client::TlsStream as ClientTlsStream, server::TlsStream};
+
+/// Configures the TLS connection to a proxy.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    roots: Vec<CertificateDer<'static>>,
+    built_in_roots: bool,
+    sni: bool,
+    danger_accept_invalid_certs: bool,
+    client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            built_in_roots: true,
+            sni: true,
+            danger_accept_invalid_certs: false,
+            client_certificate: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the PEM encoded certificates, such as a private CA.
+    pub fn add_root_certificate(mut self, pem: &[u8]) -> Result<Self, errors::Error> {
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            self.roots
+                .push(cert.map_err(|_| errors::Error::Tls("invalid certificate"))?);
+        }
+        Ok(self)
+    }
+
+    /// Sets whether the Mozilla root certificates are trusted, they are by
+    /// default.
+    pub fn set_built_in_roots(mut self, built_in_roots: bool) -> Self {
+        self.built_in_roots = built_in_roots;
+        self
+    }
+
+    /// Sets whether the proxy name is sent with Server Name Indication, it
+    /// is by default.
+    pub fn set_sni(mut self, sni: bool) -> Self {
+        self.sni = sni;
+        self
+    }
+
+    /// Accepts any certificate, for testing only.
+    pub fn set_danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.danger_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Presents the PEM encoded certificate chain and private key to a proxy
+    /// that authenticates clients by certificate.
+    pub fn set_client_certificate(
+        mut self,
+        cert_chain: &[u8],
+        key: &[u8],
+    ) -> Result<Self, errors::Error> {
+        parse_pem(cert_chain, key)?;
+        self.client_certificate = Some((cert_chain.to_vec(), key.to_vec()));
+        Ok(self)
+    }
+
+    pub fn build(&self) -> Result<TlsConnector, errors::Error> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.danger_accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier::new(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            if self.built_in_roots {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+            for cert in &self.roots {
+                roots.add(cert.clone())?;
+            }
+            builder.with_root_certificates(roots)
+        };
+        let mut config = match &self.client_certificate {
+            Some((cert_chain, key)) => {
+                let (cert_chain, key) = parse_pem(cert_chain, key)?;
+                builder.with_client_auth_cert(cert_chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.enable_sni = self.sni;
+        Ok(TlsConnector {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        })
+    }
+}
+
+/// Connects to a SOCKS5 proxy over TLS.
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl TlsConnector {
+    /// Runs the TLS handshake on `io`, verifying the certificate of the
+    /// proxy against `domain`.
+    pub async fn connect<T>(&self, domain: &str, io: T) -> Result<ClientTlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let name = ServerName::try_from(domain.to_string())
+            .map_err(|_| errors::Error::Tls("invalid server name"))?;
+        Ok(self.connector.connect(name, io).await?)
+    }
+
+    /// Runs the TLS handshake and then the SOCKS5 handshake of `client`.
+    pub async fn handshake<T>(
+        &self,
+        client: &client::Builder,
+        domain: &str,
+        io: T,
+    ) -> Result<ClientTlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        client.handshake(self.connect(domain, io).await?).await
+    }
+}
+
+/// Accepts TLS connections to the proxy.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Uses the PEM encoded certificate chain and private key.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, errors::Error> {
+        let (cert_chain, key) = parse_pem(cert_chain, key)?;
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, key)?;
+        Ok(Self::new(config))
+    }
+
+    /// Like [`TlsAcceptor::from_pem`], and asks clients for a certificate
+    /// issued by one of the PEM encoded `client_roots`. A client without a
+    /// certificate is refused unless `required` is false, [`peer_identity`]
+    /// returns the name of a client that presented one.
+    pub fn from_pem_with_client_auth(
+        cert_chain: &[u8],
+        key: &[u8],
+        client_roots: &[u8],
+        required: bool,
+    ) -> Result<Self, errors::Error> {
+        let (cert_chain, key) = parse_pem(cert_chain, key)?;
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(client_roots) {
+            roots.add(cert.map_err(|_| errors::Error::Tls("invalid certificate"))?)?;
+        }
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        let verifier = if required {
+            verifier.build()
+        } else {
+            verifier.allow_unauthenticated().build()
+        }
+        .map_err(|_| errors::Error::Tls("invalid client roots"))?;
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain, key)?;
+        Ok(Self::new(config))
+    }
+
+    fn new(config: rustls::ServerConfig) -> Self {
+        Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+        }
+    }
+
+    /// Loads the PEM encoded certificate chain and private key from files.
+    pub fn from_pem_files<P>(cert_chain: P, key: P) -> Result<Self, errors::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(key)?)
+    }
+
+    /// Runs the TLS handshake on an accepted connection.
+    pub async fn accept<T>(&self, io: T) -> Result<TlsStream<T>, errors::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Ok(self.acceptor.accept(io).await?)
+    }
+}
+
+/// A TCP listener whose connections are wrapped in TLS.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Self {
+        Self {
+            listener,
+            acceptor,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    pub async fn bind<A>(addr: A, acceptor: TlsAcceptor) -> Result<Self, errors::Error>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self::new(TcpListener::bind(addr).await?, acceptor))
+    }
+
+    /// Sets how long [`Handshake::finish`] waits for a TLS handshake.
+    pub fn set_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts a connection without running the TLS handshake, which the
+    /// caller runs with [`Handshake::finish`] in the connection's own task so
+    /// that a slow client does not hold up the next one.
+    pub async fn accept(&self) -> Result<(Handshake, SocketAddr), errors::Error> {
+        let (stream, addr) = self.listener.accept().await?;
+        let handshake = Handshake {
+            stream,
+            acceptor: self.acceptor.clone(),
+            timeout: self.handshake_timeout,
+        };
+        Ok((handshake, addr))
+    }
+}
+
+/// A connection accepted by a [`TlsListener`] whose TLS handshake has not
+/// run yet.
+pub struct Handshake {
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    timeout: Duration,
+}
+
+impl Handshake {
+    /// Runs the TLS handshake, failing when it takes longer than the
+    /// listener's handshake timeout.
+    pub async fn finish(self) -> Result<TlsStream<TcpStream>, errors::Error> {
+        tokio::time::timeout(self.timeout, self.acceptor.accept(self.stream))
+            .await
+            .map_err(|_| errors::Error::Tls("handshake timed out"))?
+    }
+}
@@ -0,0 +1,113 @@
+//! Transparent proxying on Linux. Connections redirected to the listener by
+//! an iptables `REDIRECT` rule keep their original destination in
+//! conntrack, where `SO_ORIGINAL_DST` and `IP6T_SO_ORIGINAL_DST` read it
+//! back. [`forward`] then relays the connection there, directly or through
+//! an upstream proxy.
+//!
+//! A rule on the `OUTPUT` chain also catches the connections the proxy
+//! dials itself, which would be redirected back to the listener with the
+//! same original destination, again and again. Exclude the user the proxy
+//! runs as, such as with
+//! `iptables -t nat -A OUTPUT -p tcp -m owner ! --uid-owner proxy -j REDIRECT --to-ports 1081`,
+//! or mark its sockets with `SO_MARK` and exclude the mark. Forwarding
+//! directly requires this, and so does an upstream proxy on the same host
+//! unless its address is excluded too.
+
+use std::{io, net::SocketAddr};
+
+use aries::{address::Address, connector::ProxyConnector};
+use log::trace;
+use socket2::SockRef;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::{errors, Destination};
+
+/// Returns the destination `stream` was addressed to before it was
+/// redirected.
+pub fn original_destination(stream: &TcpStream) -> Result<Destination, errors::Error> {
+    let local_addr = stream.local_addr()?;
+    let socket = SockRef::from(stream);
+    let original = match local_addr {
+        SocketAddr::V4(_) => socket.original_dst_v4(),
+        // A dual-stack socket accepts redirected IPv4 connections too.
+        SocketAddr::V6(_) => socket
+            .original_dst_v6()
+            .or_else(|_| socket.original_dst_v4()),
+    };
+    let original = match original {
+        Ok(original) => original.as_socket(),
+        // conntrack has no entry for the connection, or is not loaded and
+        // nothing can have been redirected.
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    // Without a NAT entry the original destination is the listener itself,
+    // forwarding there would loop.
+    match original {
+        Some(original) if original != local_addr => Ok(original.into()),
+        _ => Err(errors::Error::NotRedirected),
+    }
+}
+
+/// Relays `stream` to its original `destination` until either side closes
+/// it, returning the bytes copied each way. The destination is dialed
+/// through `upstream` if given, such as a [`crate::client::Builder`] or a
+/// leo client configured with its proxy, and directly otherwise.
+pub async fn forward(
+    mut stream: TcpStream,
+    destination: &Destination,
+    upstream: Option<&dyn ProxyConnector>,
+) -> Result<(u64, u64), errors::Error> {
+    let addr = destination
+        .as_socket_addr()
+        .ok_or(errors::Error::InvalidDestination("not a socket address"))?;
+    let copied = match upstream {
+        Some(upstream) => {
+            trace!(
+                "forward to {} through a {} proxy",
+                addr,
+                upstream.protocol()
+            );
+            let mut target = upstream.connect(&Address::from(addr)).await?;
+            tokio::io::copy_bidirectional(&mut stream, &mut target).await?
+        }
+        None => {
+            trace!("forward to {}", addr);
+            let mut target = TcpStream::connect(addr).await?;
+            tokio::io::copy_bidirectional(&mut stream, &mut target).await?
+        }
+    };
+    Ok(copied)
+}
+
+/// A TCP listener for redirected connections.
+pub struct TransparentListener {
+    listener: TcpListener,
+}
+
+impl TransparentListener {
+    pub fn new(listener: TcpListener) -> Self {
+        Self { listener }
+    }
+
+    pub async fn bind<A>(addr: A) -> Result<Self, errors::Error>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self::new(TcpListener::bind(addr).await?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts a connection and recovers its original destination. A
+    /// connection that was not redirected is returned as an error and the
+    /// listener can still be used.
+    pub async fn accept(&self) -> Result<(TcpStream, Destination), errors::Error> {
+        let (stream, addr) = self.listener.accept().await?;
+        let destination = original_destination(&stream)?;
+        trace!("{} redirected from {}", addr, destination);
+        Ok((stream, destination))
+    }
+}
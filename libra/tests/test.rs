@@ -1,6 +1,24 @@
-use libra::{client, server};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use aries::{
+    connector::{ConnectError, ProxyConnector},
+    proxy_protocol::{ProxiedStream, ProxyHeader, Version},
+    proxy_url::ProxyUrl,
+};
+use libra::{
+    client,
+    resolver::{Resolution, Resolver},
+    server, socks4, Peer,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
 };
 
@@ -19,7 +37,7 @@ async fn echo() {
     tokio::spawn(async move {
         loop {
             let (stream, _) = listen.accept().await.unwrap();
-            let (mut src, dst) = server::Builder::default().handshake(stream).await.unwrap();
+            let (mut src, dst, _) = server::Builder::default().handshake(stream).await.unwrap();
             let mut dst = TcpStream::connect(dst.to_string()).await.unwrap();
             tokio::io::copy_bidirectional(&mut dst, &mut src)
                 .await
@@ -39,3 +57,357 @@ async fn echo() {
     println!("{}", data);
     assert_eq!(data, "hello world\r\n")
 }
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    let echo_listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = echo_listen.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let header = ProxyHeader::new(
+        "203.0.113.7:5000".parse().unwrap(),
+        "192.0.2.2:1080".parse().unwrap(),
+    );
+    tokio::spawn(async move {
+        let (stream, _) = listen.accept().await.unwrap();
+        let stream = ProxiedStream::accept(stream).await.unwrap();
+        assert_eq!(stream.remote_addr().unwrap(), header.source);
+        assert_eq!(stream.local_addr().unwrap(), header.destination);
+        let (mut src, dst, _) = server::Builder::default().handshake(stream).await.unwrap();
+        let mut dst = TcpStream::connect(dst.to_string()).await.unwrap();
+        tokio::io::copy_bidirectional(&mut dst, &mut src)
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    header.write(&mut stream, Version::V2).await.unwrap();
+    let mut stream = client::Builder::default()
+        .set_addr(echo_addr)
+        .handshake(stream)
+        .await
+        .unwrap();
+    stream.write_all(b"hello world\r\n").await.unwrap();
+    let mut data = String::new();
+    BufReader::new(stream).read_line(&mut data).await.unwrap();
+    assert_eq!(data, "hello world\r\n")
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_peer() {
+    let (client, server) = tokio::net::UnixStream::pair().unwrap();
+    let server = tokio::io::BufStream::new(server);
+    let credentials = server.peer_credentials().unwrap();
+    assert_eq!(credentials, client.peer_credentials().unwrap());
+    #[cfg(target_os = "linux")]
+    assert_eq!(credentials.pid, Some(std::process::id() as i32));
+    assert_eq!(
+        server.remote_addr().unwrap_err().kind(),
+        std::io::ErrorKind::Unsupported
+    );
+
+    // A local process is authorized by its uid instead of a password.
+    let builder = server::Builder::default()
+        .set_authorization("hello".to_string(), "world".to_string())
+        .set_peer_identity(credentials.uid.to_string())
+        .set_peer_identity_skips_auth(true);
+    let accepted = tokio::spawn(async move { builder.handshake(server).await.map(|v| (v.1, v.2)) });
+    client::Builder::default()
+        .set_addr("127.0.0.1:80".parse().unwrap())
+        .handshake(client)
+        .await
+        .unwrap();
+    let (target, identity) = accepted.await.unwrap().unwrap();
+    assert_eq!(target, "127.0.0.1:80");
+    assert_eq!(identity, Some(credentials.uid.to_string()));
+}
+
+#[tokio::test]
+async fn test_address() {
+    for (address, expected) in [
+        ("bücher.example:443", "xn--bcher-kva.example:443"),
+        ("[::1]:80", "[::1]:80"),
+    ] {
+        let (client, server) = tokio::io::duplex(1024);
+        let accepted = tokio::spawn(async move {
+            server::Builder::default()
+                .handshake(server)
+                .await
+                .map(|v| v.1)
+        });
+        client::Builder::default()
+            .set_address(address.parse().unwrap())
+            .unwrap()
+            .handshake(client)
+            .await
+            .unwrap();
+        assert_eq!(accepted.await.unwrap().unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_duplex_peer() {
+    let (client, server) = tokio::io::duplex(1024);
+    let accepted = tokio::spawn(async move {
+        server::Builder::default()
+            .handshake(server)
+            .await
+            .map(|v| v.1)
+    });
+    client::Builder::default()
+        .set_addr("127.0.0.1:80".parse().unwrap())
+        .handshake(client)
+        .await
+        .unwrap();
+    assert_eq!(accepted.await.unwrap().unwrap(), "127.0.0.1:80");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() {
+    use aries::{unix::UnixListener, upstream::Connector};
+
+    let dir = std::env::temp_dir().join(format!("libra-unix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let backend = UnixListener::bind(dir.join("backend.sock")).unwrap();
+    tokio::spawn(async move {
+        let stream = backend.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    // The sidecar reaches the proxy over a socket, the proxy reaches the
+    // backend over another one.
+    let listen = UnixListener::bind(dir.join("proxy.sock")).unwrap();
+    listen.set_mode(0o600).unwrap();
+    let connector = Connector::new().add_unix_socket("backend.internal", dir.join("backend.sock"));
+    tokio::spawn(async move {
+        let stream = listen.accept().await.unwrap();
+        let (mut src, dst, _) = server::Builder::default().handshake(stream).await.unwrap();
+        let (host, port) = dst.rsplit_once(':').unwrap();
+        let mut dst = connector
+            .connect(host, port.parse().unwrap())
+            .await
+            .unwrap();
+        tokio::io::copy_bidirectional(&mut dst, &mut src)
+            .await
+            .unwrap();
+    });
+
+    let stream = tokio::net::UnixStream::connect(dir.join("proxy.sock"))
+        .await
+        .unwrap();
+    let mut stream = client::Builder::default()
+        .set_domain("backend.internal".to_string(), 80)
+        .unwrap()
+        .handshake(stream)
+        .await
+        .unwrap();
+    stream.write_all(b"hello world\r\n").await.unwrap();
+    let mut data = String::new();
+    BufReader::new(stream).read_line(&mut data).await.unwrap();
+    assert_eq!(data, "hello world\r\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_proxy_connector() {
+    let echo_listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = echo_listen.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        }
+    });
+
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listen.accept().await.unwrap();
+        let (mut src, dst, _) = server::Builder::default().handshake(stream).await.unwrap();
+        let mut dst = TcpStream::connect(dst.to_string()).await.unwrap();
+        tokio::io::copy_bidirectional(&mut dst, &mut src)
+            .await
+            .unwrap();
+    });
+
+    // A SOCKS4 server granting one tunnel and rejecting the next.
+    let socks4_listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks4_addr = socks4_listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        for rep in [90, 91] {
+            let (mut stream, _) = socks4_listen.accept().await.unwrap();
+            let mut request = [0; 9];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[0, rep, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            if rep == 90 {
+                let port = u16::from_be_bytes([request[2], request[3]]);
+                let mut dst = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                tokio::io::copy_bidirectional(&mut dst, &mut stream)
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let connectors: Vec<Box<dyn ProxyConnector>> = vec![
+        Box::new(client::Builder::default().set_proxy(addr.into())),
+        Box::new(socks4::Builder::default().set_proxy(socks4_addr.into())),
+    ];
+    for connector in &connectors {
+        let mut stream = connector.connect(&echo_addr.into()).await.unwrap();
+        stream.write_all(b"hello world\r\n").await.unwrap();
+        let mut data = String::new();
+        BufReader::new(&mut stream)
+            .read_line(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, "hello world\r\n", "{}", connector.protocol());
+    }
+
+    let e = match connectors[1].connect(&echo_addr.into()).await {
+        Err(e) => e,
+        Ok(_) => panic!("tunnel opened"),
+    };
+    assert!(e.to_string().starts_with("socks4 handshake"));
+    assert!(matches!(
+        e.downcast_ref::<libra::Error>(),
+        Some(libra::Error::Rep(91, _))
+    ));
+    let result = client::Builder::default().connect(&echo_addr.into()).await;
+    assert!(matches!(result, Err(ConnectError::MissingProxy)));
+}
+
+#[tokio::test]
+async fn test_proxy_url() {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        let builder =
+            server::Builder::default().set_authorization("alice".to_string(), "p@ss".to_string());
+        let mut targets = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = listen.accept().await.unwrap();
+            let (_, target, identity) = builder.handshake(stream).await.unwrap();
+            assert_eq!(identity.as_deref(), Some("alice"));
+            targets.push(target);
+        }
+        targets
+    });
+
+    let target = "localhost:80".parse().unwrap();
+    for scheme in ["socks5h", "socks5"] {
+        let url: ProxyUrl = format!("{}://alice:p%40ss@{}", scheme, addr)
+            .parse()
+            .unwrap();
+        let connector = client::Builder::try_from(&url).unwrap();
+        connector.connect(&target).await.unwrap();
+    }
+    let targets = handle.await.unwrap();
+    assert_eq!(targets[0], "localhost:80");
+    assert!(["127.0.0.1:80", "[::1]:80"].contains(&targets[1].as_str()));
+
+    let url: ProxyUrl = "socks4a://alice@127.0.0.1".parse().unwrap();
+    assert!(socks4::Builder::try_from(&url).is_ok());
+    assert!(client::Builder::try_from(&url).is_err());
+    let url: ProxyUrl = "http://127.0.0.1".parse().unwrap();
+    assert!(socks4::Builder::try_from(&url).is_err());
+}
+
+/// Resolves `known.test` only, counting the lookups.
+#[derive(Clone, Default)]
+struct StaticResolver(Arc<AtomicUsize>);
+
+#[async_trait::async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        match domain {
+            "known.test" => Ok(vec![SocketAddr::from(([192, 0, 2, 1], port))]),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, domain.to_string())),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_resolution() {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            tokio::spawn(async move {
+                // Tell the client the target it asked for, once it writes.
+                let (mut stream, target, _) =
+                    match server::Builder::default().handshake(stream).await {
+                        Ok(v) => v,
+                        Err(_) => return,
+                    };
+                stream.read_u8().await.unwrap();
+                stream
+                    .write_all(format!("{}\n", target).as_bytes())
+                    .await
+                    .unwrap();
+            });
+        }
+    });
+
+    let resolver = StaticResolver::default();
+    let sent = |resolution, host: &'static str| {
+        let resolver = resolver.clone();
+        async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let stream = client::Builder::default()
+                .set_resolution(resolution)
+                .set_resolver(resolver)
+                .set_host_port(host, 80)?
+                .handshake(stream)
+                .await?;
+            let mut stream = BufReader::new(stream);
+            stream.write_u8(b'\n').await.unwrap();
+            let mut target = String::new();
+            stream.read_line(&mut target).await.unwrap();
+            Ok::<_, libra::Error>(target.trim_end().to_string())
+        }
+    };
+
+    assert_eq!(
+        sent(Resolution::Remote, "known.test").await.unwrap(),
+        "known.test:80"
+    );
+    assert_eq!(resolver.0.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        sent(Resolution::Remote, "192.0.2.9").await.unwrap(),
+        "192.0.2.9:80"
+    );
+    assert_eq!(
+        sent(Resolution::Local, "known.test").await.unwrap(),
+        "192.0.2.1:80"
+    );
+    assert!(matches!(
+        sent(Resolution::Local, "unknown.test").await,
+        Err(libra::Error::Io(_))
+    ));
+    assert_eq!(
+        sent(Resolution::LocalThenRemote, "unknown.test")
+            .await
+            .unwrap(),
+        "unknown.test:80"
+    );
+    assert_eq!(
+        sent(Resolution::LocalThenRemote, "known.test")
+            .await
+            .unwrap(),
+        "192.0.2.1:80"
+    );
+    assert_eq!(resolver.0.load(Ordering::SeqCst), 4);
+}
@@ -0,0 +1,247 @@
+#![cfg(any(feature = "rustls", feature = "tokio-native-tls"))]
+
+use std::time::Duration;
+
+use libra::{client, server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A self-signed certificate for `localhost` and its key, PEM encoded.
+fn certificate() -> (String, String) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (certified.cert.pem(), certified.signing_key.serialize_pem())
+}
+
+/// Runs an echo server and returns its port.
+async fn echo() -> u16 {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            tokio::spawn(async move { tokio::io::copy(&mut reader, &mut writer).await });
+        }
+    });
+    port
+}
+
+fn socks(echo_port: u16) -> client::Builder {
+    client::Builder::default()
+        .set_authorization("hello".to_string(), "world".to_string())
+        .set_addr(([127, 0, 0, 1], echo_port).into())
+}
+
+async fn check_echo<S>(mut stream: S)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    stream.write_all(b"hello world").await.unwrap();
+    let mut data = [0; 11];
+    stream.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"hello world");
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn test_rustls() {
+    use libra::tls::rustls::{ClientBuilder, TlsAcceptor, TlsListener};
+
+    let (cert, key) = certificate();
+    let acceptor = TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+    let listen = TlsListener::bind("127.0.0.1:0", acceptor)
+        .await
+        .unwrap()
+        .set_handshake_timeout(Duration::from_secs(3600));
+    let port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((handshake, _)) = listen.accept().await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                let stream = handshake.finish().await?;
+                let (mut src, dst, _) = server::Builder::default()
+                    .set_authorization("hello".to_string(), "world".to_string())
+                    .handshake(stream)
+                    .await?;
+                let mut dst = TcpStream::connect(dst).await?;
+                tokio::io::copy_bidirectional(&mut src, &mut dst).await?;
+                Ok::<_, libra::Error>(())
+            });
+        }
+    });
+    let echo_port = echo().await;
+
+    let connector = ClientBuilder::new()
+        .set_built_in_roots(false)
+        .add_root_certificate(cert.as_bytes())
+        .unwrap()
+        .build()
+        .unwrap();
+    // A client that never sends its ClientHello does not hold up the others.
+    let _silent = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = tokio::time::timeout(
+        Duration::from_secs(5),
+        connector.handshake(&socks(echo_port), "localhost", stream),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    check_echo(stream).await;
+
+    // The certificate is not valid for another name.
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    assert!(connector
+        .handshake(&socks(echo_port), "example.com", stream)
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn test_rustls_client_auth() {
+    use libra::tls::rustls::{peer_identity, ClientBuilder, TlsAcceptor, TlsListener};
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair,
+    };
+
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "alice");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_key = KeyPair::generate().unwrap();
+    let client_cert = params.signed_by(&client_key, &ca).unwrap();
+
+    let (cert, key) = certificate();
+    let acceptor = TlsAcceptor::from_pem_with_client_auth(
+        cert.as_bytes(),
+        key.as_bytes(),
+        ca.pem().as_bytes(),
+        false,
+    )
+    .unwrap();
+    let listen = TlsListener::bind("127.0.0.1:0", acceptor).await.unwrap();
+    let port = listen.local_addr().unwrap().port();
+    let builder = server::Builder::default()
+        .set_authorization("hello".to_string(), "world".to_string())
+        .set_peer_identity_skips_auth(true);
+    tokio::spawn(async move {
+        loop {
+            let Ok((handshake, _)) = listen.accept().await else {
+                continue;
+            };
+            let mut builder = builder.clone();
+            tokio::spawn(async move {
+                let stream = handshake.finish().await?;
+                if let Some(identity) = peer_identity(&stream) {
+                    assert_eq!(identity, "alice");
+                    builder = builder.set_peer_identity(identity);
+                }
+                let (mut src, dst, _) = builder.handshake(stream).await?;
+                let mut dst = TcpStream::connect(dst).await?;
+                tokio::io::copy_bidirectional(&mut src, &mut dst).await?;
+                Ok::<_, libra::Error>(())
+            });
+        }
+    });
+    let echo_port = echo().await;
+
+    // A trusted certificate is enough, without it the password is required.
+    let connector = ClientBuilder::new()
+        .set_built_in_roots(false)
+        .add_root_certificate(cert.as_bytes())
+        .unwrap();
+    let with_cert = connector
+        .clone()
+        .set_client_certificate(
+            client_cert.pem().as_bytes(),
+            client_key.serialize_pem().as_bytes(),
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+    let no_auth = client::Builder::default().set_addr(([127, 0, 0, 1], echo_port).into());
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = with_cert
+        .handshake(&no_auth, "localhost", stream)
+        .await
+        .unwrap();
+    check_echo(stream).await;
+
+    let without_cert = connector.build().unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    assert!(without_cert
+        .handshake(&no_auth, "localhost", stream)
+        .await
+        .is_err());
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = without_cert
+        .handshake(&socks(echo_port), "localhost", stream)
+        .await
+        .unwrap();
+    check_echo(stream).await;
+}
+
+#[cfg(feature = "tokio-native-tls")]
+#[tokio::test]
+async fn test_native_tls() {
+    use libra::tls::native_tls::{ClientBuilder, TlsAcceptor, TlsListener};
+
+    let (cert, key) = certificate();
+    let acceptor = TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+    let listen = TlsListener::bind("127.0.0.1:0", acceptor).await.unwrap();
+    let port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((handshake, _)) = listen.accept().await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                let stream = handshake.finish().await?;
+                let (mut src, dst, _) = server::Builder::default()
+                    .set_authorization("hello".to_string(), "world".to_string())
+                    .handshake(stream)
+                    .await?;
+                let mut dst = TcpStream::connect(dst).await?;
+                tokio::io::copy_bidirectional(&mut src, &mut dst).await?;
+                Ok::<_, libra::Error>(())
+            });
+        }
+    });
+    let echo_port = echo().await;
+
+    let connector = ClientBuilder::new()
+        .set_built_in_roots(false)
+        .add_root_certificate(cert.as_bytes())
+        .unwrap()
+        .build()
+        .unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let stream = connector
+        .handshake(&socks(echo_port), "localhost", stream)
+        .await
+        .unwrap();
+    check_echo(stream).await;
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    assert!(connector
+        .handshake(&socks(echo_port), "example.com", stream)
+        .await
+        .is_err());
+
+    // Client certificates are refused rather than silently not asked for.
+    assert!(TlsAcceptor::from_pem_with_client_auth(
+        cert.as_bytes(),
+        key.as_bytes(),
+        cert.as_bytes(),
+        false
+    )
+    .is_err());
+}
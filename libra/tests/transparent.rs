@@ -0,0 +1,118 @@
+#![cfg(target_os = "linux")]
+
+use aries::connector::ProxyConnector;
+use libra::{
+    client, server,
+    transparent::{forward, original_destination, TransparentListener},
+    Destination, Error,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Runs an echo server and returns its address.
+async fn echo() -> std::net::SocketAddr {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            tokio::spawn(async move { tokio::io::copy(&mut reader, &mut writer).await });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_not_redirected() {
+    let listen = TransparentListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let _stream = TcpStream::connect(addr).await.unwrap();
+    // A direct connection has no other destination than the listener.
+    assert!(matches!(listen.accept().await, Err(Error::NotRedirected)));
+
+    // The listener is still usable.
+    let _stream = TcpStream::connect(addr).await.unwrap();
+    assert!(matches!(listen.accept().await, Err(Error::NotRedirected)));
+}
+
+#[tokio::test]
+async fn test_forward() {
+    let echo_addr = echo().await;
+    let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_addr = socks.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = socks.accept().await.unwrap();
+        let (mut src, dst, _) = server::Builder::default().handshake(stream).await.unwrap();
+        let mut dst = TcpStream::connect(dst).await.unwrap();
+        tokio::io::copy_bidirectional(&mut src, &mut dst)
+            .await
+            .unwrap();
+    });
+    let upstream = client::Builder::default().set_proxy(socks_addr.into());
+
+    // The destination is dialed directly, then through the SOCKS5 proxy.
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    let destination = Destination::from(echo_addr);
+    let upstreams: [Option<&dyn ProxyConnector>; 2] = [None, Some(&upstream)];
+    for upstream in upstreams {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listen.accept().await.unwrap();
+        let echoed = async move {
+            client.write_all(b"hello").await.unwrap();
+            let mut echo = [0; 5];
+            client.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, b"hello");
+        };
+        let (forwarded, ()) = tokio::join!(forward(stream, &destination, upstream), echoed);
+        assert_eq!(forwarded.unwrap(), (5, 5));
+    }
+}
+
+/// Redirects a connection with iptables, which needs root in a network
+/// namespace of its own:
+/// `unshare -rn cargo test -p libra --test transparent -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_original_destination() {
+    let run = |program: &str, args: &[&str]| {
+        let status = std::process::Command::new(program)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} {:?}", program, args);
+    };
+    run("ip", &["link", "set", "lo", "up"]);
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listen.local_addr().unwrap().port().to_string();
+    run(
+        "iptables",
+        &[
+            "-t",
+            "nat",
+            "-A",
+            "OUTPUT",
+            "-p",
+            "tcp",
+            "-d",
+            "127.0.0.2",
+            "--dport",
+            "80",
+            "-j",
+            "REDIRECT",
+            "--to-ports",
+            &port,
+        ],
+    );
+
+    let _client = TcpStream::connect("127.0.0.2:80").await.unwrap();
+    let (stream, _) = listen.accept().await.unwrap();
+    let destination = original_destination(&stream).unwrap();
+    assert_eq!(
+        destination.as_socket_addr(),
+        Some("127.0.0.2:80".parse().unwrap())
+    );
+}
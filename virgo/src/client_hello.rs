@@ -0,0 +1,179 @@
+//! Parsing of the TLS ClientHello, see RFC 8446 section 4.1.2. Only the
+//! fields needed for routing are kept.
+
+use crate::Error;
+
+pub(crate) const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+
+/// The length of a record header: content type, legacy version and length.
+pub(crate) const RECORD_HEADER_LENGTH: usize = 5;
+
+/// The length of a handshake header: message type and 24-bit length.
+pub(crate) const HANDSHAKE_HEADER_LENGTH: usize = 4;
+
+/// The largest record payload, RFC 8446 section 5.1.
+pub(crate) const MAX_RECORD_LENGTH: usize = 1 << 14;
+
+pub(crate) const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const SERVER_NAME_HOST_NAME: u8 = 0;
+
+/// The routing fields of a ClientHello.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    server_name: Option<String>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Parses the body of a ClientHello handshake message, without its
+    /// handshake header.
+    pub fn parse(body: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader(body);
+        // legacy_version and random
+        reader.take(2 + 32)?;
+        // legacy_session_id
+        reader.vec8()?;
+        // cipher_suites
+        reader.vec16()?;
+        // legacy_compression_methods
+        reader.vec8()?;
+
+        let mut hello = ClientHello::default();
+        // A ClientHello without extensions is valid before TLS 1.3.
+        if reader.0.is_empty() {
+            return Ok(hello);
+        }
+        let mut extensions = Reader(reader.vec16()?);
+        while !extensions.0.is_empty() {
+            let extension_type = extensions.u16()?;
+            let mut data = Reader(extensions.vec16()?);
+            match extension_type {
+                EXTENSION_SERVER_NAME => {
+                    let mut names = Reader(data.vec16()?);
+                    while !names.0.is_empty() {
+                        let name_type = names.u8()?;
+                        let name = names.vec16()?;
+                        if name_type == SERVER_NAME_HOST_NAME && hello.server_name.is_none() {
+                            hello.server_name = Some(host_name(name)?);
+                        }
+                    }
+                }
+                EXTENSION_ALPN => {
+                    let mut protocols = Reader(data.vec16()?);
+                    while !protocols.0.is_empty() {
+                        hello.alpn_protocols.push(protocols.vec8()?.to_vec());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(hello)
+    }
+
+    /// Returns the host name of the Server Name Indication extension,
+    /// lowercased and without a trailing dot.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Returns the protocols offered with ALPN, in the client's order of
+    /// preference.
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.alpn_protocols
+    }
+}
+
+fn host_name(name: &[u8]) -> Result<String, Error> {
+    if name.is_empty() || !name.iter().all(|b| b.is_ascii_graphic()) {
+        return Err(Error::MalformedClientHello);
+    }
+    let name = std::str::from_utf8(name).map_err(|_| Error::MalformedClientHello)?;
+    Ok(name.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Reads the big-endian integers and length-prefixed vectors of RFC 8446
+/// section 3.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::MalformedClientHello);
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let v = self.take(2)?;
+        Ok(u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    fn vec8(&mut self) -> Result<&'a [u8], Error> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], Error> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ClientHello, HANDSHAKE_HEADER_LENGTH, RECORD_HEADER_LENGTH};
+    use crate::Error;
+
+    /// Returns the ClientHello body sent by rustls.
+    fn client_hello(server_name: &str, alpn_protocols: &[&[u8]]) -> Vec<u8> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        config.alpn_protocols = alpn_protocols.iter().map(|v| v.to_vec()).collect();
+        let mut conn = rustls::ClientConnection::new(
+            Arc::new(config),
+            server_name.to_string().try_into().unwrap(),
+        )
+        .unwrap();
+        let mut record = Vec::new();
+        conn.write_tls(&mut record).unwrap();
+        record.split_off(RECORD_HEADER_LENGTH + HANDSHAKE_HEADER_LENGTH)
+    }
+
+    #[test]
+    fn test_parse() {
+        let body = client_hello("Example.COM", &[b"h2", b"http/1.1"]);
+        let hello = ClientHello::parse(&body).unwrap();
+        assert_eq!(hello.server_name(), Some("example.com"));
+        assert_eq!(
+            hello.alpn_protocols(),
+            [b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+
+        // rustls does not send SNI for an IP address.
+        let hello = ClientHello::parse(&client_hello("127.0.0.1", &[])).unwrap();
+        assert_eq!(hello.server_name(), None);
+        assert!(hello.alpn_protocols().is_empty());
+
+        for n in [0, 10, 40, body.len() - 1] {
+            assert!(matches!(
+                ClientHello::parse(&body[..n]),
+                Err(Error::MalformedClientHello)
+            ));
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not a tls handshake")]
+    NotTls,
+
+    #[error("client hello too large")]
+    ClientHelloTooLarge,
+
+    #[error("malformed client hello")]
+    MalformedClientHello,
+
+    #[error("no server name")]
+    MissingServerName,
+
+    #[error("invalid server name: {0}")]
+    InvalidServerName(#[from] aries::Error),
+}
@@ -0,0 +1,11 @@
+//! A TLS passthrough acceptor. The server name of the ClientHello is the
+//! target, an `aries::address::Address` dialed like the targets of the
+//! SOCKS and HTTP proxies, and the connection is relayed without being
+//! decrypted.
+pub mod client_hello;
+pub mod server;
+
+mod errors;
+pub use client_hello::ClientHello;
+pub use errors::Error;
+pub use server::Replay;
@@ -0,0 +1,187 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use aries::address::Address;
+use bytes::{Buf, Bytes};
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    client_hello::{
+        ClientHello, CONTENT_TYPE_HANDSHAKE, HANDSHAKE_CLIENT_HELLO, HANDSHAKE_HEADER_LENGTH,
+        MAX_RECORD_LENGTH, RECORD_HEADER_LENGTH,
+    },
+    Error,
+};
+
+/// The default limit on the length of a ClientHello, large enough for the
+/// post-quantum key shares that make it span several records.
+const MAX_CLIENT_HELLO_LENGTH: usize = 64 * 1024;
+
+/// The port of the target unless configured otherwise.
+const DEFAULT_PORT: u16 = 443;
+
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    max_client_hello_length: Option<usize>,
+    port: Option<u16>,
+}
+
+impl Builder {
+    /// Reads the ClientHello of a TLS connection, returning the stream that
+    /// replays it, the target named by its server name and the fields to
+    /// route the connection by. The stream is then relayed to the target,
+    /// such as with an `aries::upstream::Connector` or an
+    /// `aries::connector::ProxyConnector`, and the backend completes the
+    /// handshake with the client.
+    ///
+    /// A ClientHello without a server name is rejected.
+    pub async fn handshake<T>(&self, mut io: T) -> Result<(Replay<T>, Address, ClientHello), Error>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let max_client_hello_length = self
+            .max_client_hello_length
+            .unwrap_or(MAX_CLIENT_HELLO_LENGTH);
+        // The records as received, and the handshake message they carry.
+        let mut records = Vec::new();
+        let mut message = Vec::new();
+
+        loop {
+            trace!("read record header");
+            let start = records.len();
+            records.resize(start + RECORD_HEADER_LENGTH, 0);
+            io.read_exact(&mut records[start..]).await?;
+            let header = &records[start..];
+            if header[0] != CONTENT_TYPE_HANDSHAKE {
+                return Err(Error::NotTls);
+            }
+            let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if length == 0 || length > MAX_RECORD_LENGTH {
+                return Err(Error::MalformedClientHello);
+            }
+
+            let start = records.len();
+            records.resize(start + length, 0);
+            io.read_exact(&mut records[start..]).await?;
+            message.extend_from_slice(&records[start..]);
+
+            if message.len() < HANDSHAKE_HEADER_LENGTH {
+                continue;
+            }
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(Error::NotTls);
+            }
+            let length = HANDSHAKE_HEADER_LENGTH
+                + u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if length > max_client_hello_length {
+                return Err(Error::ClientHelloTooLarge);
+            }
+            // A record never carries the start of the next handshake
+            // message before the server has answered.
+            if message.len() > length {
+                return Err(Error::MalformedClientHello);
+            }
+            if message.len() == length {
+                let hello = ClientHello::parse(&message[HANDSHAKE_HEADER_LENGTH..])?;
+                trace!("client hello for {:?}", hello.server_name());
+                let server_name = hello.server_name().ok_or(Error::MissingServerName)?;
+                let target = Address::new(server_name, self.port.unwrap_or(DEFAULT_PORT))?;
+                return Ok((Replay::new(Bytes::from(records), io), target, hello));
+            }
+        }
+    }
+
+    /// Sets the maximum length in bytes of the ClientHello message.
+    pub fn set_max_client_hello_length(mut self, max_client_hello_length: usize) -> Self {
+        self.max_client_hello_length = Some(max_client_hello_length);
+        self
+    }
+
+    /// Sets the port of the target, 443 by default.
+    pub fn set_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+}
+
+/// A stream that yields bytes already read from it before reading further.
+#[derive(Debug)]
+pub struct Replay<T> {
+    buf: Bytes,
+    io: T,
+}
+
+impl<T> Replay<T> {
+    pub fn new(buf: Bytes, io: T) -> Self {
+        Self { buf, io }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Returns the stream and the bytes not replayed yet.
+    pub fn into_parts(self) -> (T, Bytes) {
+        (self.io, self.buf)
+    }
+}
+
+impl<T> AsyncRead for Replay<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buf.is_empty() {
+            let n = self.buf.len().min(buf.remaining());
+            buf.put_slice(&self.buf[..n]);
+            self.buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for Replay<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use virgo::{server, Error};
+
+/// Runs a TLS backend for `name` on the Unix socket at `path`, that answers
+/// with its name, then echoes. Returns its certificate.
+#[cfg(unix)]
+async fn backend(name: &'static str, path: &std::path::Path) -> CertificateDer<'static> {
+    use aries::unix::UnixListener;
+
+    let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listen = UnixListener::bind(path).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let stream = listen.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = acceptor.accept(stream).await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                writer.write_all(name.as_bytes()).await.unwrap();
+                writer.flush().await.unwrap();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+        }
+    });
+    cert
+}
+
+/// The backends are reached by the target like any upstream host, here
+/// through the Unix sockets its domain is mapped to.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_passthrough() {
+    use aries::upstream::Connector;
+
+    let dir = std::env::temp_dir().join(format!("virgo-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let a_cert = backend("a.test", &dir.join("a.sock")).await;
+    let b_cert = backend("b.test", &dir.join("b.sock")).await;
+    let connector = Connector::new()
+        .add_unix_socket("a.test", dir.join("a.sock"))
+        .add_unix_socket("b.test", dir.join("b.sock"));
+
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listen.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            let connector = connector.clone();
+            tokio::spawn(async move {
+                let (mut src, target, hello) = server::Builder::default().handshake(stream).await?;
+                assert_eq!(hello.alpn_protocols(), [b"h2".to_vec()]);
+                assert_eq!(target.port(), 443);
+                let mut dst = connector
+                    .connect(&target.host().to_string(), target.port())
+                    .await?;
+                tokio::io::copy_bidirectional(&mut src, &mut dst).await?;
+                Ok::<_, Error>(())
+            });
+        }
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(a_cert).unwrap();
+    roots.add(b_cert).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+    // The backend completes the handshake, so its certificate is verified.
+    for name in ["a.test", "b.test"] {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let server_name = ServerName::try_from(name).unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let mut received = [0; 6];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, name.as_bytes());
+        stream.write_all(b"hello").await.unwrap();
+        let mut echo = [0; 5];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"hello");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The first record sent by a rustls client connecting to `server_name`.
+fn client_hello(server_name: ServerName<'static>) -> Vec<u8> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let mut conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+    let mut record = Vec::new();
+    conn.write_tls(&mut record).unwrap();
+    record
+}
+
+#[tokio::test]
+async fn test_not_tls() {
+    let (mut client, server) = tokio::io::duplex(1024);
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n")
+        .await
+        .unwrap();
+    let result = server::Builder::default().handshake(server).await;
+    assert!(matches!(result, Err(Error::NotTls)));
+}
+
+#[tokio::test]
+async fn test_fragmented() {
+    let record = client_hello("a.test".try_into().unwrap());
+
+    // The same handshake message in two records.
+    let (header, payload) = record.split_at(5);
+    let (first, second) = payload.split_at(40);
+    let mut records = Vec::new();
+    for fragment in [first, second] {
+        records.extend_from_slice(&header[..3]);
+        records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        records.extend_from_slice(fragment);
+    }
+
+    let (mut client, server) = tokio::io::duplex(4096);
+    client.write_all(&records).await.unwrap();
+    client.write_all(b"rest").await.unwrap();
+    drop(client);
+    let (mut stream, target, hello) = server::Builder::default()
+        .set_port(8443)
+        .handshake(server)
+        .await
+        .unwrap();
+    assert_eq!(hello.server_name(), Some("a.test"));
+    assert_eq!(target.to_string(), "a.test:8443");
+    let mut replayed = Vec::new();
+    stream.read_to_end(&mut replayed).await.unwrap();
+    assert_eq!(&replayed[..records.len()], &records[..]);
+    assert_eq!(&replayed[records.len()..], b"rest");
+
+    let (reader, mut writer) = tokio::io::duplex(4096);
+    writer.write_all(&records).await.unwrap();
+    let result = server::Builder::default()
+        .set_max_client_hello_length(64)
+        .handshake(reader)
+        .await;
+    assert!(matches!(result, Err(Error::ClientHelloTooLarge)));
+}
+
+#[tokio::test]
+async fn test_no_server_name() {
+    // Clients do not send an IP address as the server name.
+    let record = client_hello("127.0.0.1".try_into().unwrap());
+    let (mut client, server) = tokio::io::duplex(4096);
+    client.write_all(&record).await.unwrap();
+    let result = server::Builder::default().handshake(server).await;
+    assert!(matches!(result, Err(Error::MissingServerName)));
+}